@@ -0,0 +1,191 @@
+//! Parser for the leases database of macOS bootpd(8), /var/db/dhcpd_leases,
+//! which consists of blocks like this:
+//!
+//! ```text
+//! {
+//!     name=debian
+//!     ip_address=192.168.64.2
+//!     hw_address=1,2:0:0:0:0:1
+//!     identifier=1,2:0:0:0:0:1
+//!     lease=0x6812fc1a
+//! }
+//! ```
+//!
+//! The lease field is the expiration time in seconds since the UNIX epoch.
+
+use anyhow::{Context, Result, anyhow};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// ARP hardware type of Ethernet, which prefixes the hardware addresses
+const HTYPE_ETHERNET: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootpdLease {
+    pub name: Option<String>,
+    pub ip_address: Ipv4Addr,
+    pub hw_address: Option<[u8; 6]>,
+    pub expires_at: SystemTime,
+}
+
+impl BootpdLease {
+    /// Time left until the lease expires, zero if it already has
+    pub fn remaining(&self) -> Duration {
+        self.expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+pub fn parse(contents: &str) -> Result<Vec<BootpdLease>> {
+    let mut leases = Vec::new();
+    let mut fields: Option<Vec<(&str, &str)>> = None;
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+
+        match (line, &mut fields) {
+            ("", _) => {}
+            ("{", None) => fields = Some(Vec::new()),
+            ("}", Some(block)) => {
+                leases.push(
+                    parse_lease(block)
+                        .with_context(|| format!("invalid lease ending at line {}", n + 1))?,
+                );
+                fields = None;
+            }
+            (line, Some(block)) => {
+                let (key, value) = line.split_once('=').ok_or_else(|| {
+                    anyhow!("expected key=value at line {}, got {:?}", n + 1, line)
+                })?;
+
+                block.push((key.trim(), value.trim()));
+            }
+            (line, None) => return Err(anyhow!("unexpected {:?} at line {}", line, n + 1)),
+        }
+    }
+
+    if fields.is_some() {
+        return Err(anyhow!("unterminated lease at the end of the file"));
+    }
+
+    Ok(leases)
+}
+
+fn parse_lease(fields: &[(&str, &str)]) -> Result<BootpdLease> {
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    };
+
+    let ip_address = field("ip_address")
+        .ok_or_else(|| anyhow!("missing ip_address"))?
+        .parse()
+        .context("invalid ip_address")?;
+
+    let hw_address = field("hw_address")
+        .map(parse_hw_address)
+        .transpose()?
+        .flatten();
+
+    let lease = field("lease").ok_or_else(|| anyhow!("missing lease"))?;
+    let lease = u64::from_str_radix(lease.trim_start_matches("0x"), 16)
+        .with_context(|| format!("invalid lease {:?}", lease))?;
+
+    Ok(BootpdLease {
+        name: field("name").map(str::to_string),
+        ip_address,
+        hw_address,
+        expires_at: UNIX_EPOCH + Duration::from_secs(lease),
+    })
+}
+
+/// Parses TYPE,MAC with the octets not padded with zeros (e.g. 1,2:0:0:0:0:1),
+/// hardware types other than Ethernet are skipped
+fn parse_hw_address(value: &str) -> Result<Option<[u8; 6]>> {
+    let (htype, address) = value
+        .split_once(',')
+        .ok_or_else(|| anyhow!("invalid hw_address {:?}", value))?;
+
+    if htype.parse::<u8>().ok() != Some(HTYPE_ETHERNET) {
+        return Ok(None);
+    }
+
+    let octets = address
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid hw_address {:?}", value))?;
+
+    let octets = octets
+        .try_into()
+        .map_err(|_| anyhow!("invalid hw_address {:?}", value))?;
+
+    Ok(Some(octets))
+}
+
+/// Finds the most recent lease for the hardware address
+pub fn lookup(path: &Path, hw_address: [u8; 6]) -> Result<Option<BootpdLease>> {
+    let contents = std::fs::read_to_string(path)?;
+
+    let lease = parse(&contents)?
+        .into_iter()
+        .filter(|lease| lease.hw_address == Some(hw_address))
+        .max_by_key(|lease| lease.expires_at);
+
+    Ok(lease)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BootpdLease, lookup, parse};
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_parse() {
+        let leases = parse(include_str!("fixtures/dhcpd_leases")).unwrap();
+
+        assert_eq!(leases.len(), 4);
+        assert_eq!(
+            leases[0],
+            BootpdLease {
+                name: Some("debian".to_string()),
+                ip_address: Ipv4Addr::new(192, 168, 64, 2),
+                hw_address: Some([0x02, 0, 0, 0, 0, 0x01]),
+                expires_at: UNIX_EPOCH + Duration::from_secs(0x6812fc1a),
+            }
+        );
+
+        // Entry with a non-Ethernet hardware address
+        assert_eq!(leases[3].hw_address, None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("{\n\tip_address=192.168.64.2\n").is_err());
+        assert!(parse("{\n\tip_address=192.168.64.2\n}\n").is_err());
+        assert!(parse("{\n\tip_address=192.168.64.2\n\tlease=0xzz\n}\n").is_err());
+        assert!(parse("ip_address=192.168.64.2\n").is_err());
+        assert!(parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let path =
+            std::env::temp_dir().join(format!("softnet-dhcpd-leases-{}", std::process::id()));
+        std::fs::write(&path, include_str!("fixtures/dhcpd_leases")).unwrap();
+
+        // The most recent of the two leases
+        let lease = lookup(&path, [0x02, 0, 0, 0, 0, 0x01]).unwrap().unwrap();
+        assert_eq!(lease.ip_address, Ipv4Addr::new(192, 168, 64, 5));
+        assert_eq!(lease.remaining(), Duration::ZERO);
+
+        assert_eq!(lookup(&path, [0x02, 0, 0, 0, 0, 0xff]).unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,24 +1,294 @@
+use crate::bootpd_leases;
+use anyhow::{Context, Result, anyhow};
 use dhcproto::Decodable;
-use dhcproto::v4::{DhcpOption, MessageType, OptionCode};
-use smoltcp::wire::Ipv4Address;
-use std::collections::HashSet;
-use std::time::Duration;
+use dhcproto::v4::{DhcpOption, Message, MessageType, Opcode, OptionCode};
+use log::{info, warn};
+use serde_json::json;
+use smoltcp::wire::{EthernetAddress, Ipv4Address};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long to wait for the server to reply to the VM's DISCOVER or REQUEST
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Upper bound on the number of the VM's DHCP transactions in flight
+const MAX_TRANSACTIONS: usize = 16;
+
+// How long to remember the expired lease and its server, so that
+// a late renewal unicast to the server can still get through
+const LEASE_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+// RFC 1700 hardware type of Ethernet
+const HTYPE_ETHERNET: u8 = 1;
 
 #[derive(Default)]
 pub struct DhcpSnooper {
+    vm_mac_address: EthernetAddress,
     vm_lease: Option<Lease>,
+    transactions: HashMap<u32, Transaction>,
+    server: Option<Ipv4Address>,
     uncertainty_duration: Duration,
+    // Where the lease is persisted to survive softnet restarts
+    state_path: Option<PathBuf>,
+    // Client identifier used by the VM, to release the lease on its behalf
+    client_id: Option<Vec<u8>>,
+}
+
+/// DHCP transaction initiated by the VM
+struct Transaction {
+    started_at: coarsetime::Instant,
+    // Server selected by the VM in its REQUEST
+    server: Option<Ipv4Address>,
+    // Started while renewing, when the REQUEST is unicast to the server
+    // that granted the lease, unlike the broadcast one when rebinding
+    renewing: bool,
 }
 
 impl DhcpSnooper {
-    pub fn new(uncertainty_duration: Duration) -> Self {
-        DhcpSnooper {
+    pub fn new(
+        vm_mac_address: EthernetAddress,
+        uncertainty_duration: Duration,
+        state_path: Option<PathBuf>,
+    ) -> Self {
+        let mut snooper = DhcpSnooper {
+            vm_mac_address,
             uncertainty_duration,
+            state_path,
             ..Default::default()
+        };
+
+        // Pick up where the previous instance left off, otherwise
+        // the VM would be cut off until its next renewal
+        if let Some(state_path) = &snooper.state_path {
+            match restore(state_path) {
+                Ok(Some((lease, server))) => {
+                    info!(
+                        "restored the VM's lease on {} from {}",
+                        lease.address,
+                        state_path.display()
+                    );
+
+                    snooper.vm_lease = Some(lease);
+                    snooper.server = server;
+                }
+                Ok(None) => {}
+                Err(err) => warn!("failed to restore the VM's lease: {err:#}"),
+            }
         }
+
+        snooper
+    }
+
+    /// Seeds the lease from the host's bootpd(8) leases database,
+    /// unless there's one already or the database has an expired one
+    pub fn restore_from_bootpd_leases(&mut self, path: &Path) {
+        if self.vm_lease.is_some() {
+            return;
+        }
+
+        let bootpd_lease = match bootpd_leases::lookup(path, self.vm_mac_address.0) {
+            Ok(Some(bootpd_lease)) => bootpd_lease,
+            Ok(None) => return,
+            Err(err) => {
+                warn!(
+                    "failed to restore the VM's lease from {}: {err:#}",
+                    path.display()
+                );
+
+                return;
+            }
+        };
+
+        // Adjust for uncertainty caused by using a coarse clock
+        let lease_time = bootpd_lease
+            .remaining()
+            .saturating_sub(self.uncertainty_duration);
+
+        if lease_time.is_zero() {
+            return;
+        }
+
+        info!(
+            "restored the VM's lease on {} from {}",
+            bootpd_lease.ip_address,
+            path.display()
+        );
+
+        // The database lacks the DNS servers and the server identifier,
+        // these are learned when the VM renews the lease
+        self.vm_lease = Some(Lease::new(
+            bootpd_lease.ip_address,
+            lease_time,
+            HashSet::new(),
+        ));
+        self.save();
+    }
+
+    /// Installs a permanent lease for the VM that uses static addressing,
+    /// in which case the VM's and the server's DHCP messages are ignored
+    pub fn set_static_lease(&mut self, lease: Lease) {
+        info!("using static lease on {} for the VM", lease.address);
+
+        self.vm_lease = Some(lease);
+        self.server = None;
+        self.transactions.clear();
+    }
+
+    fn static_lease(&self) -> bool {
+        self.vm_lease.as_ref().is_some_and(|lease| lease.permanent)
+    }
+
+    /// Keeps track of the DHCP transactions initiated by the VM, so that only
+    /// the replies to them are taken into account, returns true if the VM
+    /// has given up its lease by releasing or declining the address
+    pub fn register_dhcp_request(&mut self, dhcp_packet: &[u8]) -> bool {
+        if self.static_lease() {
+            return false;
+        }
+
+        let mut decoder = dhcproto::v4::Decoder::new(dhcp_packet);
+
+        let message = match dhcproto::v4::Message::decode(&mut decoder) {
+            Ok(message) => message,
+            Err(_) => return false,
+        };
+
+        if message.opcode() != Opcode::BootRequest || message.chaddr() != self.vm_mac_address.0 {
+            return false;
+        }
+
+        let server = match message.opts().get(OptionCode::ServerIdentifier) {
+            Some(DhcpOption::ServerIdentifier(server)) => Some(*server),
+            _ => None,
+        };
+
+        if let Some(DhcpOption::ClientIdentifier(client_id)) =
+            message.opts().get(OptionCode::ClientIdentifier)
+        {
+            self.client_id = Some(client_id.clone());
+        }
+
+        match message.opts().msg_type() {
+            Some(MessageType::Discover | MessageType::Request) => {
+                self.register_transaction(message.xid(), server);
+
+                false
+            }
+            // RELEASE carries the address in ciaddr
+            Some(MessageType::Release) => self.give_up_lease(message.ciaddr(), server),
+            // DECLINE carries the address in the requested IP address option
+            Some(MessageType::Decline) => {
+                match message.opts().get(OptionCode::RequestedIpAddress) {
+                    Some(DhcpOption::RequestedIpAddress(address)) => {
+                        self.give_up_lease(*address, server)
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    fn register_transaction(&mut self, xid: u32, server: Option<Ipv4Address>) {
+        let now = coarsetime::Instant::recent();
+
+        self.transactions.retain(|_, transaction| {
+            now.duration_since(transaction.started_at) < TRANSACTION_TIMEOUT.into()
+        });
+
+        if !self.transactions.contains_key(&xid) && self.transactions.len() >= MAX_TRANSACTIONS {
+            return;
+        }
+
+        let renewing = self
+            .vm_lease
+            .as_ref()
+            .is_some_and(|lease| lease.state_at(now) == LeaseState::Renewing);
+
+        let transaction = self.transactions.entry(xid).or_insert(Transaction {
+            started_at: now,
+            server: None,
+            renewing,
+        });
+
+        // REQUEST that selects the server reuses the XID of the DISCOVER
+        if server.is_some() {
+            transaction.server = server;
+        }
+    }
+
+    /// Invalidates the lease when the VM releases or declines its address,
+    /// so that it can't keep using it after the server hands it to someone
+    /// else, returns true if the lease was invalidated
+    fn give_up_lease(&mut self, address: Ipv4Address, server: Option<Ipv4Address>) -> bool {
+        let Some(lease) = &self.vm_lease else {
+            return false;
+        };
+
+        // Restored leases may not know their server,
+        // in which case any server identifier will do
+        let other_server = matches!(
+            (server, self.server),
+            (Some(server), Some(expected)) if server != expected
+        );
+
+        if lease.address() != address || other_server {
+            return false;
+        }
+
+        self.vm_lease = None;
+        self.server = None;
+        self.save();
+
+        true
+    }
+
+    /// Crafts a DHCPRELEASE on behalf of the VM and forgets the lease,
+    /// returns the message along with the server to send it to
+    pub fn release(&mut self, default_server: Ipv4Address) -> Option<(Message, Ipv4Address)> {
+        if self.static_lease() {
+            return None;
+        }
+
+        let address = self
+            .vm_lease
+            .as_ref()
+            .filter(|lease| lease.valid())?
+            .address;
+        let server = self.server.unwrap_or(default_server);
+
+        // Without the client identifier option, the server
+        // identifies the client by its hardware address
+        let client_id = self
+            .client_id
+            .clone()
+            .unwrap_or_else(|| [&[HTYPE_ETHERNET][..], &self.vm_mac_address.0].concat());
+
+        let mut message = Message::new(
+            address,
+            Ipv4Address::UNSPECIFIED,
+            Ipv4Address::UNSPECIFIED,
+            Ipv4Address::UNSPECIFIED,
+            &self.vm_mac_address.0,
+        );
+        let opts = message.opts_mut();
+        opts.insert(DhcpOption::MessageType(MessageType::Release));
+        opts.insert(DhcpOption::ServerIdentifier(server));
+        opts.insert(DhcpOption::ClientIdentifier(client_id));
+
+        info!("releasing the VM's lease on {} to {}", address, server);
+
+        self.give_up_lease(address, None);
+
+        Some((message, server))
     }
 
     pub fn register_dhcp_reply(&mut self, dhcp_packet: &[u8]) {
+        if self.static_lease() {
+            return;
+        }
+
         let mut decoder = dhcproto::v4::Decoder::new(dhcp_packet);
 
         let message = match dhcproto::v4::Message::decode(&mut decoder) {
@@ -26,8 +296,55 @@ impl DhcpSnooper {
             Err(_) => return,
         };
 
-        match message.opts().msg_type() {
-            Some(MessageType::Ack) => {
+        let msg_type = match message.opts().msg_type() {
+            Some(msg_type @ (MessageType::Ack | MessageType::Nak)) => msg_type,
+            _ => return,
+        };
+
+        if message.opcode() != Opcode::BootReply || message.chaddr() != self.vm_mac_address.0 {
+            warn!(
+                "ignoring DHCP {:?} for another client {:02x?}",
+                msg_type,
+                message.chaddr()
+            );
+
+            return;
+        }
+
+        let Some(transaction) = self.transactions.get(&message.xid()) else {
+            warn!(
+                "ignoring DHCP {:?} with XID {:#010x} that the VM never requested",
+                msg_type,
+                message.xid()
+            );
+
+            return;
+        };
+
+        let server = match message.opts().get(OptionCode::ServerIdentifier) {
+            Some(DhcpOption::ServerIdentifier(server)) => Some(*server),
+            _ => None,
+        };
+
+        // The VM is bound to the server it selected, or, when renewing, to
+        // the one that granted the lease, while any server may answer when rebinding
+        let expected = transaction
+            .server
+            .or(self.server.filter(|_| transaction.renewing));
+
+        if let Some(expected) = expected
+            && server != Some(expected)
+        {
+            warn!(
+                "ignoring DHCP {:?} from server {:?}, expected {}",
+                msg_type, server, expected
+            );
+
+            return;
+        }
+
+        match msg_type {
+            MessageType::Ack => {
                 let lease_time = match message.opts().get(OptionCode::AddressLeaseTime) {
                     Some(DhcpOption::AddressLeaseTime(lease_time)) => lease_time,
                     _ => return,
@@ -45,13 +362,86 @@ impl DhcpSnooper {
                 // Adjust for uncertainty caused by using a coarse clock
                 lease_duration = lease_duration.saturating_sub(self.uncertainty_duration);
 
-                self.vm_lease = Some(Lease::new(message.yiaddr(), lease_duration, dns_ips))
+                let mut lease = Lease::new(message.yiaddr(), lease_duration, dns_ips);
+
+                let renewal_time = match message.opts().get(OptionCode::Renewal) {
+                    Some(DhcpOption::Renewal(renewal_time)) => Some(*renewal_time),
+                    _ => None,
+                };
+                let rebinding_time = match message.opts().get(OptionCode::Rebinding) {
+                    Some(DhcpOption::Rebinding(rebinding_time)) => Some(*rebinding_time),
+                    _ => None,
+                };
+
+                lease.set_timers(
+                    renewal_time.map(|secs| Duration::from_secs(secs as u64)),
+                    rebinding_time.map(|secs| Duration::from_secs(secs as u64)),
+                );
+
+                self.vm_lease = Some(lease);
+                self.server = server.or(self.server);
+                self.save();
             }
-            Some(MessageType::Nak) => {
+            MessageType::Nak => {
                 self.vm_lease = None;
+                self.server = None;
+                self.save();
             }
             _ => {}
         };
+
+        self.transactions.remove(&message.xid());
+    }
+
+    /// Follows the lease through the RENEWING and REBINDING states
+    /// and forgets it once it's expired for longer than the grace period
+    pub fn tick(&mut self, now: coarsetime::Instant) {
+        let Some(lease) = &mut self.vm_lease else {
+            return;
+        };
+
+        let state = lease.state_at(now);
+
+        if state != lease.state {
+            info!("VM's lease on {} is now {:?}", lease.address, state);
+
+            lease.state = state;
+        }
+
+        if state == LeaseState::Expired
+            && now.duration_since(lease.valid_until) >= LEASE_GRACE_PERIOD.into()
+        {
+            self.vm_lease = None;
+            self.server = None;
+            self.save();
+        }
+    }
+
+    /// Persists the lease, or removes the state file when there's none
+    fn save(&self) {
+        let Some(state_path) = &self.state_path else {
+            return;
+        };
+
+        // Static lease comes from the command-line, not from the state file
+        if self.static_lease() {
+            return;
+        }
+
+        let result = match &self.vm_lease {
+            Some(lease) => save(state_path, lease, self.server),
+            None => match std::fs::remove_file(state_path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            },
+        };
+
+        if let Err(err) = result {
+            warn!(
+                "failed to persist the VM's lease to {}: {err:#}",
+                state_path.display()
+            );
+        }
     }
 
     #[cfg(test)]
@@ -59,6 +449,11 @@ impl DhcpSnooper {
         self.vm_lease = vm_lease
     }
 
+    #[cfg(test)]
+    pub(crate) fn set_server(&mut self, server: Option<Ipv4Address>) {
+        self.server = server
+    }
+
     pub fn lease(&self) -> &Option<Lease> {
         &self.vm_lease
     }
@@ -70,21 +465,169 @@ impl DhcpSnooper {
 
         false
     }
+
+    /// Whether a DHCP request is unicast from the leased address to the server
+    /// that granted it, which is how the VM renews the lease, even if it has
+    /// already expired within the grace period
+    pub fn valid_dhcp_target(&self, src_addr: Ipv4Address, dst_addr: Ipv4Address) -> bool {
+        match (&self.vm_lease, self.server) {
+            (Some(lease), Some(server)) => lease.address == src_addr && server == dst_addr,
+            _ => false,
+        }
+    }
+}
+
+/// Writes the lease to a temporary file and renames it over the state file,
+/// so that a crash in the middle never leaves a partially written state
+fn save(state_path: &Path, lease: &Lease, server: Option<Ipv4Address>) -> Result<()> {
+    // Monotonic instants don't survive restarts, so store the wall-clock time
+    let now = coarsetime::Instant::recent();
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let to_unix = |instant: coarsetime::Instant| {
+        let remaining: Duration = instant.duration_since(now).into();
+
+        (unix_now + remaining).as_secs()
+    };
+
+    let state = json!({
+        "address": lease.address.to_string(),
+        "server": server.map(|server| server.to_string()),
+        "dns": lease.dns_ips.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "renew_at": to_unix(lease.renew_at),
+        "rebind_at": to_unix(lease.rebind_at),
+        "valid_until": to_unix(lease.valid_until),
+    });
+
+    let tmp_path = state_path.with_extension("tmp");
+    std::fs::write(&tmp_path, state.to_string())?;
+    std::fs::rename(&tmp_path, state_path)?;
+
+    Ok(())
+}
+
+/// Reads the lease from the state file, unless it's missing or the lease has expired
+fn restore(state_path: &Path) -> Result<Option<(Lease, Option<Ipv4Address>)>> {
+    let contents = match std::fs::read_to_string(state_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let state: serde_json::Value =
+        serde_json::from_str(&contents).context("failed to parse the state file")?;
+
+    let addr = |value: &serde_json::Value| -> Result<Ipv4Address> {
+        value
+            .as_str()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| anyhow!("invalid IP address {value}"))
+    };
+    let remaining = |key: &str| -> Result<Duration> {
+        let unix_time = state[key]
+            .as_u64()
+            .ok_or_else(|| anyhow!("invalid {key} timestamp"))?;
+
+        let time = UNIX_EPOCH + Duration::from_secs(unix_time);
+
+        Ok(time.duration_since(SystemTime::now()).unwrap_or_default())
+    };
+
+    let lease_time = remaining("valid_until")?;
+
+    if lease_time.is_zero() {
+        return Ok(None);
+    }
+
+    let address = addr(&state["address"])?;
+    let server = match &state["server"] {
+        serde_json::Value::Null => None,
+        server => Some(addr(server)?),
+    };
+    let dns_ips = state["dns"]
+        .as_array()
+        .ok_or_else(|| anyhow!("invalid DNS servers"))?
+        .iter()
+        .map(addr)
+        .collect::<Result<HashSet<_>>>()?;
+
+    let mut lease = Lease::new(address, lease_time, dns_ips);
+    lease.set_timers(Some(remaining("renew_at")?), Some(remaining("rebind_at")?));
+
+    Ok(Some((lease, server)))
+}
+
+/// Client states of RFC 2131 that the lease goes through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeaseState {
+    Bound,
+    Renewing,
+    Rebinding,
+    Expired,
 }
 
 #[derive(Debug)]
 pub struct Lease {
     address: Ipv4Address,
+    state: LeaseState,
+    renew_at: coarsetime::Instant,
+    rebind_at: coarsetime::Instant,
     valid_until: coarsetime::Instant,
     dns_ips: HashSet<Ipv4Address>,
+    // Configured statically in the VM rather than obtained via DHCP
+    permanent: bool,
 }
 
 impl Lease {
     pub fn new(address: Ipv4Address, lease_time: Duration, dns_ips: HashSet<Ipv4Address>) -> Lease {
+        let now = coarsetime::Instant::recent();
+
+        // RFC 2131 defaults for T1 and T2
         Lease {
             address,
-            valid_until: coarsetime::Instant::recent() + lease_time.into(),
+            state: LeaseState::Bound,
+            renew_at: now + lease_time.mul_f64(0.5).into(),
+            rebind_at: now + lease_time.mul_f64(0.875).into(),
+            valid_until: now + lease_time.into(),
             dns_ips,
+            permanent: false,
+        }
+    }
+
+    /// Lease for the VM that uses static addressing, which never expires
+    pub fn permanent(address: Ipv4Address, dns_ips: HashSet<Ipv4Address>) -> Lease {
+        Lease {
+            permanent: true,
+            ..Lease::new(address, Duration::ZERO, dns_ips)
+        }
+    }
+
+    /// Overrides the default T1 and T2 with the ones provided by the server,
+    /// keeping them ordered and within the lease
+    fn set_timers(&mut self, renewal_time: Option<Duration>, rebinding_time: Option<Duration>) {
+        let now = coarsetime::Instant::recent();
+
+        if let Some(rebinding_time) = rebinding_time {
+            self.rebind_at = (now + rebinding_time.into()).min(self.valid_until);
+        }
+
+        if let Some(renewal_time) = renewal_time {
+            self.renew_at = (now + renewal_time.into()).min(self.rebind_at);
+        }
+
+        self.renew_at = self.renew_at.min(self.rebind_at);
+    }
+
+    fn state_at(&self, now: coarsetime::Instant) -> LeaseState {
+        if self.permanent {
+            LeaseState::Bound
+        } else if now >= self.valid_until {
+            LeaseState::Expired
+        } else if now >= self.rebind_at {
+            LeaseState::Rebinding
+        } else if now >= self.renew_at {
+            LeaseState::Renewing
+        } else {
+            LeaseState::Bound
         }
     }
 
@@ -93,10 +636,366 @@ impl Lease {
     }
 
     pub fn valid(&self) -> bool {
-        coarsetime::Instant::recent() < self.valid_until
+        self.permanent || coarsetime::Instant::recent() < self.valid_until
     }
 
     pub fn valid_ip_source(&self, address: Ipv4Address) -> bool {
         self.address == address && self.valid()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{DhcpSnooper, LEASE_GRACE_PERIOD, Lease, LeaseState};
+    use dhcproto::Encodable;
+    use dhcproto::v4::{DhcpOption, Message, MessageType, Opcode, OptionCode};
+    use smoltcp::wire::{EthernetAddress, Ipv4Address};
+    use std::collections::HashSet;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const VM_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+    const OTHER_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+    const SERVER: Ipv4Address = Ipv4Address::new(192, 168, 64, 1);
+    const ROGUE: Ipv4Address = Ipv4Address::new(192, 168, 64, 66);
+    const VM_IP: Ipv4Address = Ipv4Address::new(192, 168, 64, 2);
+
+    #[test]
+    fn test_requested_ack_is_accepted() {
+        let mut snooper = snooper();
+
+        snooper.register_dhcp_request(&request(1, MessageType::Discover, VM_MAC, None));
+        snooper.register_dhcp_request(&request(1, MessageType::Request, VM_MAC, Some(SERVER)));
+        snooper.register_dhcp_reply(&reply(1, MessageType::Ack, VM_MAC, SERVER));
+
+        assert_eq!(snooper.lease().as_ref().unwrap().address(), VM_IP);
+        assert_eq!(snooper.server, Some(SERVER));
+    }
+
+    #[test]
+    fn test_unsolicited_ack_is_rejected() {
+        let mut snooper = snooper();
+
+        snooper.register_dhcp_reply(&reply(1, MessageType::Ack, VM_MAC, SERVER));
+        assert!(snooper.lease().is_none());
+
+        // Transaction of another client
+        snooper.register_dhcp_request(&request(2, MessageType::Request, OTHER_MAC, None));
+        snooper.register_dhcp_reply(&reply(2, MessageType::Ack, VM_MAC, SERVER));
+        assert!(snooper.lease().is_none());
+
+        // Reply addressed to another client
+        snooper.register_dhcp_request(&request(3, MessageType::Request, VM_MAC, None));
+        snooper.register_dhcp_reply(&reply(3, MessageType::Ack, OTHER_MAC, SERVER));
+        assert!(snooper.lease().is_none());
+
+        // Transaction is completed by the genuine reply and can't be replayed
+        snooper.register_dhcp_reply(&reply(3, MessageType::Ack, VM_MAC, SERVER));
+        assert!(snooper.lease().is_some());
+        snooper.register_dhcp_reply(&reply(3, MessageType::Nak, VM_MAC, SERVER));
+        assert!(snooper.lease().is_some());
+    }
+
+    #[test]
+    fn test_server_is_pinned() {
+        let mut snooper = snooper();
+
+        // VM selected another server
+        snooper.register_dhcp_request(&request(1, MessageType::Request, VM_MAC, Some(SERVER)));
+        snooper.register_dhcp_reply(&reply(1, MessageType::Ack, VM_MAC, ROGUE));
+        assert!(snooper.lease().is_none());
+
+        // Lease that is due for renewal right away
+        snooper.register_dhcp_reply(&ack(1, SERVER, 0, 300));
+        assert!(snooper.lease().is_some());
+
+        // Renewals don't carry the server identifier
+        snooper.register_dhcp_request(&request(2, MessageType::Request, VM_MAC, None));
+        snooper.register_dhcp_reply(&reply(2, MessageType::Nak, VM_MAC, ROGUE));
+        assert!(snooper.lease().is_some());
+
+        snooper.register_dhcp_reply(&reply(2, MessageType::Nak, VM_MAC, SERVER));
+        assert!(snooper.lease().is_none());
+    }
+
+    #[test]
+    fn test_any_server_may_answer_when_rebinding() {
+        let mut snooper = snooper();
+        let other_server = Ipv4Address::new(192, 168, 64, 10);
+
+        // Lease that is due for rebinding right away
+        snooper.register_dhcp_request(&request(1, MessageType::Request, VM_MAC, Some(SERVER)));
+        snooper.register_dhcp_reply(&ack(1, SERVER, 0, 0));
+        assert_eq!(
+            snooper
+                .lease()
+                .as_ref()
+                .unwrap()
+                .state_at(coarsetime::Instant::recent()),
+            LeaseState::Rebinding
+        );
+
+        // Broadcast REQUEST doesn't carry the server identifier either
+        snooper.register_dhcp_request(&request(2, MessageType::Request, VM_MAC, None));
+        snooper.register_dhcp_reply(&ack(2, other_server, 300, 525));
+        assert!(snooper.lease().is_some());
+        assert_eq!(snooper.server, Some(other_server));
+    }
+
+    #[test]
+    fn test_release_and_decline() {
+        let mut snooper = snooper();
+        lease(&mut snooper);
+
+        // Releasing some other address
+        let mut release = message(2, MessageType::Release, VM_MAC, Some(SERVER));
+        release.set_ciaddr(Ipv4Address::new(192, 168, 64, 3));
+        assert!(!snooper.register_dhcp_request(&release.to_vec().unwrap()));
+        assert!(snooper.lease().is_some());
+
+        release.set_ciaddr(VM_IP);
+        assert!(snooper.register_dhcp_request(&release.to_vec().unwrap()));
+        assert!(snooper.lease().is_none());
+
+        lease(&mut snooper);
+
+        let mut decline = message(3, MessageType::Decline, VM_MAC, Some(SERVER));
+        decline
+            .opts_mut()
+            .insert(DhcpOption::RequestedIpAddress(VM_IP));
+        assert!(snooper.register_dhcp_request(&decline.to_vec().unwrap()));
+        assert!(snooper.lease().is_none());
+    }
+
+    #[test]
+    fn test_renewal_and_rebinding() {
+        let mut snooper = snooper();
+        let start = coarsetime::Instant::recent();
+
+        let mut ack = message(1, MessageType::Ack, VM_MAC, Some(SERVER));
+        ack.set_opcode(Opcode::BootReply).set_yiaddr(VM_IP);
+        ack.opts_mut().insert(DhcpOption::AddressLeaseTime(600));
+        ack.opts_mut().insert(DhcpOption::Renewal(100));
+        ack.opts_mut().insert(DhcpOption::Rebinding(200));
+
+        snooper.register_dhcp_request(&request(1, MessageType::Request, VM_MAC, Some(SERVER)));
+        snooper.register_dhcp_reply(&ack.to_vec().unwrap());
+
+        let lease = snooper.lease().as_ref().unwrap();
+        assert_eq!(lease.state_at(start), LeaseState::Bound);
+        assert_eq!(lease.state_at(at(start, 99)), LeaseState::Bound);
+        assert_eq!(lease.state_at(at(start, 101)), LeaseState::Renewing);
+        assert_eq!(lease.state_at(at(start, 201)), LeaseState::Rebinding);
+        assert_eq!(lease.state_at(at(start, 601)), LeaseState::Expired);
+
+        // Renewals unicast to the server are recognized from the leased address only
+        assert!(snooper.valid_dhcp_target(VM_IP, SERVER));
+        assert!(!snooper.valid_dhcp_target(VM_IP, ROGUE));
+        assert!(!snooper.valid_dhcp_target(Ipv4Address::new(192, 168, 64, 3), SERVER));
+
+        // Expired lease is remembered for the grace period
+        snooper.tick(at(start, 601));
+        assert_eq!(snooper.lease().as_ref().unwrap().state, LeaseState::Expired);
+        assert!(snooper.valid_dhcp_target(VM_IP, SERVER));
+
+        snooper.tick(at(start, 601 + LEASE_GRACE_PERIOD.as_secs()));
+        assert!(snooper.lease().is_none());
+        assert!(!snooper.valid_dhcp_target(VM_IP, SERVER));
+    }
+
+    #[test]
+    fn test_lease_is_persisted() {
+        coarsetime::Instant::update();
+
+        let state_path =
+            std::env::temp_dir().join(format!("softnet-lease-{}.json", std::process::id()));
+
+        let mut snooper = DhcpSnooper::new(VM_MAC, Duration::ZERO, Some(state_path.clone()));
+        lease(&mut snooper);
+
+        let restored = DhcpSnooper::new(VM_MAC, Duration::ZERO, Some(state_path.clone()));
+        let lease = restored.lease().as_ref().unwrap();
+        assert_eq!(lease.address(), VM_IP);
+        assert!(lease.valid());
+        assert_eq!(restored.server, Some(SERVER));
+
+        // Lease is gone, so is the state file
+        snooper.register_dhcp_request(&request(2, MessageType::Request, VM_MAC, None));
+        snooper.register_dhcp_reply(&reply(2, MessageType::Nak, VM_MAC, SERVER));
+        assert!(!state_path.exists());
+
+        // Expired lease is not trusted
+        std::fs::write(
+            &state_path,
+            r#"{"address": "192.168.64.2", "server": "192.168.64.1", "dns": [],
+            "renew_at": 1, "rebind_at": 2, "valid_until": 3}"#,
+        )
+        .unwrap();
+        let restored = DhcpSnooper::new(VM_MAC, Duration::ZERO, Some(state_path.clone()));
+        assert!(restored.lease().is_none());
+
+        std::fs::remove_file(&state_path).unwrap();
+    }
+
+    #[test]
+    fn test_restore_from_bootpd_leases() {
+        coarsetime::Instant::update();
+
+        let path =
+            std::env::temp_dir().join(format!("softnet-bootpd-leases-{}", std::process::id()));
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        std::fs::write(
+            &path,
+            format!(
+                "{{\n\tname=debian\n\tip_address=192.168.64.2\n\thw_address=1,2:0:0:0:0:1\n\
+                \tidentifier=1,2:0:0:0:0:1\n\tlease={expires_at:#x}\n}}\n"
+            ),
+        )
+        .unwrap();
+
+        let mut snooper = snooper();
+        snooper.restore_from_bootpd_leases(&path);
+        let lease = snooper.lease().as_ref().unwrap();
+        assert_eq!(lease.address(), VM_IP);
+        assert!(lease.valid());
+
+        // Database lacks the server identifier, which RELEASE carries
+        let mut release = message(1, MessageType::Release, VM_MAC, Some(SERVER));
+        release.set_ciaddr(VM_IP);
+        assert!(snooper.register_dhcp_request(&release.to_vec().unwrap()));
+        assert!(snooper.lease().is_none());
+
+        // Lease of another VM
+        let mut snooper = DhcpSnooper::new(OTHER_MAC, Duration::ZERO, None);
+        snooper.restore_from_bootpd_leases(&path);
+        assert!(snooper.lease().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_release_on_behalf_of_the_vm() {
+        let mut snooper = snooper();
+        lease(&mut snooper);
+
+        let (message, server) = snooper
+            .release(Ipv4Address::new(192, 168, 64, 254))
+            .unwrap();
+        assert_eq!(server, SERVER);
+        assert_eq!(message.ciaddr(), VM_IP);
+        assert_eq!(message.chaddr(), VM_MAC.0);
+        assert_eq!(message.opts().msg_type(), Some(MessageType::Release));
+        assert_eq!(
+            message.opts().get(OptionCode::ClientIdentifier),
+            Some(&DhcpOption::ClientIdentifier(vec![
+                1, 0x02, 0, 0, 0, 0, 0x01
+            ]))
+        );
+        assert!(snooper.lease().is_none());
+
+        // Nothing left to release
+        assert!(snooper.release(SERVER).is_none());
+    }
+
+    #[test]
+    fn test_static_lease() {
+        let dns = Ipv4Address::new(1, 1, 1, 1);
+
+        let mut snooper = snooper();
+        snooper.set_static_lease(Lease::permanent(VM_IP, HashSet::from([dns])));
+        assert!(snooper.valid_dns_target(&dns));
+
+        // Server's replies never override the static lease
+        snooper.register_dhcp_request(&request(1, MessageType::Request, VM_MAC, Some(SERVER)));
+        snooper.register_dhcp_reply(&reply(1, MessageType::Nak, VM_MAC, SERVER));
+        assert!(snooper.lease().is_some());
+
+        // Nor does it ever expire
+        let start = coarsetime::Instant::recent();
+        snooper.tick(at(start, 365 * 24 * 60 * 60));
+        let lease = snooper.lease().as_ref().unwrap();
+        assert_eq!(
+            lease.state_at(at(start, 365 * 24 * 60 * 60)),
+            LeaseState::Bound
+        );
+        assert!(lease.valid_ip_source(VM_IP));
+    }
+
+    fn at(start: coarsetime::Instant, secs: u64) -> coarsetime::Instant {
+        start + coarsetime::Duration::from_secs(secs)
+    }
+
+    fn lease(snooper: &mut DhcpSnooper) {
+        snooper.register_dhcp_request(&request(1, MessageType::Request, VM_MAC, Some(SERVER)));
+        snooper.register_dhcp_reply(&reply(1, MessageType::Ack, VM_MAC, SERVER));
+        assert!(snooper.lease().is_some());
+    }
+
+    fn snooper() -> DhcpSnooper {
+        coarsetime::Instant::update();
+
+        DhcpSnooper::new(VM_MAC, Duration::from_millis(100), None)
+    }
+
+    fn request(
+        xid: u32,
+        msg_type: MessageType,
+        chaddr: EthernetAddress,
+        server: Option<Ipv4Address>,
+    ) -> Vec<u8> {
+        message(xid, msg_type, chaddr, server).to_vec().unwrap()
+    }
+
+    fn message(
+        xid: u32,
+        msg_type: MessageType,
+        chaddr: EthernetAddress,
+        server: Option<Ipv4Address>,
+    ) -> Message {
+        let mut message = Message::default();
+        message.set_xid(xid).set_chaddr(&chaddr.0);
+        message.opts_mut().insert(DhcpOption::MessageType(msg_type));
+
+        if let Some(server) = server {
+            message
+                .opts_mut()
+                .insert(DhcpOption::ServerIdentifier(server));
+        }
+
+        message
+    }
+
+    fn ack(xid: u32, server: Ipv4Address, renewal_time: u32, rebinding_time: u32) -> Vec<u8> {
+        let mut ack = message(xid, MessageType::Ack, VM_MAC, Some(server));
+        ack.set_opcode(Opcode::BootReply).set_yiaddr(VM_IP);
+        ack.opts_mut().insert(DhcpOption::AddressLeaseTime(600));
+        ack.opts_mut().insert(DhcpOption::Renewal(renewal_time));
+        ack.opts_mut().insert(DhcpOption::Rebinding(rebinding_time));
+
+        ack.to_vec().unwrap()
+    }
+
+    fn reply(
+        xid: u32,
+        msg_type: MessageType,
+        chaddr: EthernetAddress,
+        server: Ipv4Address,
+    ) -> Vec<u8> {
+        let mut message = Message::default();
+        message
+            .set_opcode(Opcode::BootReply)
+            .set_xid(xid)
+            .set_chaddr(&chaddr.0)
+            .set_yiaddr(VM_IP);
+
+        let opts = message.opts_mut();
+        opts.insert(DhcpOption::MessageType(msg_type));
+        opts.insert(DhcpOption::ServerIdentifier(server));
+        opts.insert(DhcpOption::AddressLeaseTime(600));
+
+        message.to_vec().unwrap()
+    }
+}
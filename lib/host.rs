@@ -28,6 +28,7 @@ pub struct Host {
     new_packets_rx: UnixDatagram,
     callback_can_continue_tx: SyncSender<()>,
     pub gateway_ip: smoltcp::wire::Ipv4Address,
+    pub subnet_mask: smoltcp::wire::Ipv4Address,
     pub max_packet_size: u64,
     pub read_max_packets: u64,
     finalized: bool,
@@ -59,6 +60,15 @@ impl Host {
         let gateway_ip = Ipv4Addr::from_str(&gateway_ip)
             .context("failed to parse vmnet's interface start address")?;
 
+        // Retrieve the subnet mask used for this interface
+        let Some(Parameter::SubnetMask(subnet_mask)) =
+            interface.parameters().get(ParameterKind::SubnetMask)
+        else {
+            return Err(anyhow!("failed to retrieve vmnet's interface subnet mask"));
+        };
+        let subnet_mask = Ipv4Addr::from_str(&subnet_mask)
+            .context("failed to parse vmnet's interface subnet mask")?;
+
         // Retrieve max packet size for this interface
         let Some(Parameter::MaxPacketSize(max_packet_size)) =
             interface.parameters().get(ParameterKind::MaxPacketSize)
@@ -105,6 +115,7 @@ impl Host {
             new_packets_rx,
             callback_can_continue_tx,
             gateway_ip,
+            subnet_mask,
             max_packet_size,
             read_max_packets,
             finalized: false,
@@ -1,3 +1,4 @@
+mod bootpd_leases;
 mod dhcp_snooper;
 mod host;
 pub use host::NetType;
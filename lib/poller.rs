@@ -64,8 +64,14 @@ impl Poller<'_> {
         self.events.clear();
     }
 
-    pub fn wait(&mut self) -> Result<(bool, bool, bool)> {
-        self.poller.wait(&mut self.events, Some(self.timeout))?;
+    pub fn wait(&mut self, deadline: Option<Duration>) -> Result<(bool, bool, bool)> {
+        // Wake up earlier if requested by the caller
+        let timeout = match deadline {
+            Some(deadline) => deadline.min(self.timeout),
+            None => self.timeout,
+        };
+
+        self.poller.wait(&mut self.events, Some(timeout))?;
 
         let vm_readable = self
             .events
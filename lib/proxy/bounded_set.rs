@@ -0,0 +1,90 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Set holding up to the given number of entries, evicting
+/// the oldest inserted one to make room for the new one
+pub struct BoundedSet<T> {
+    capacity: usize,
+    // Insertion generation of each entry
+    entries: HashMap<T, u64>,
+    // Entries in the order of insertion, including the ones removed since,
+    // which are recognized by their generation and skipped on eviction
+    order: VecDeque<(T, u64)>,
+    generation: u64,
+}
+
+impl<T: Hash + Eq + Clone> BoundedSet<T> {
+    pub fn new(capacity: usize) -> BoundedSet<T> {
+        BoundedSet {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    pub fn contains(&self, entry: &T) -> bool {
+        self.entries.contains_key(entry)
+    }
+
+    /// Returns false if the entry was already present
+    pub fn insert(&mut self, entry: T) -> bool {
+        if self.entries.contains_key(&entry) {
+            return false;
+        }
+
+        while self.entries.len() >= self.capacity {
+            let Some((oldest, generation)) = self.order.pop_front() else {
+                break;
+            };
+
+            if self.entries.get(&oldest) == Some(&generation) {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.generation += 1;
+        self.entries.insert(entry.clone(), self.generation);
+        self.order.push_back((entry, self.generation));
+
+        // Don't let the removed entries accumulate
+        if self.order.len() > self.capacity * 2 {
+            let entries = &self.entries;
+
+            self.order
+                .retain(|(entry, generation)| entries.get(entry) == Some(generation));
+        }
+
+        true
+    }
+
+    pub fn remove(&mut self, entry: &T) -> bool {
+        self.entries.remove(entry).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoundedSet;
+
+    #[test]
+    fn test_oldest_is_evicted() {
+        let mut set = BoundedSet::new(2);
+
+        assert!(set.insert(1));
+        assert!(set.insert(2));
+        assert!(!set.insert(1));
+        assert!(set.insert(3));
+        assert!(!set.contains(&1));
+        assert!(set.contains(&2));
+        assert!(set.contains(&3));
+
+        // Removed entries don't take the place of the present ones
+        assert!(set.remove(&2));
+        assert!(set.insert(2));
+        assert!(set.insert(4));
+        assert!(!set.contains(&3));
+        assert!(set.contains(&2));
+        assert!(set.contains(&4));
+    }
+}
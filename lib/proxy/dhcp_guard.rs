@@ -0,0 +1,307 @@
+use crate::dhcp_snooper::Lease;
+use crate::proxy::Proxy;
+use crate::proxy::events;
+use crate::proxy::fixed_window::FixedWindow;
+use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use dhcproto::Decodable;
+use dhcproto::v4::{DhcpOption, Message, MessageType, OptionCode};
+use log::info;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet,
+    UdpPacket,
+};
+use std::collections::HashSet;
+use std::time::Duration;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// Each DECLINE makes the server quarantine an address, so only a few are
+// tolerated, which is enough for the VM to recover from a genuine conflict
+const DECLINE_WINDOW: Duration = Duration::from_secs(60);
+const MAX_DECLINES: u32 = 3;
+
+// Upper bound on the number of the spoofed identities that were reported
+const MAX_REPORTED: usize = 4096;
+
+/// Keeps the VM from exhausting the DHCP server's pool by requesting
+/// addresses for the hardware addresses or client identifiers that
+/// aren't its own, or by flooding the server with the messages
+pub struct DhcpGuard {
+    vm_mac_address: EthernetAddress,
+    rate_limit: Option<u32>,
+    window: FixedWindow,
+    declines: FixedWindow,
+    // Client identifier that the VM has settled on
+    client_id: Option<Vec<u8>>,
+    reported: HashSet<Vec<u8>>,
+    dropped: u64,
+}
+
+impl DhcpGuard {
+    pub fn new(vm_mac_address: EthernetAddress, rate_limit: Option<u32>) -> DhcpGuard {
+        DhcpGuard {
+            vm_mac_address,
+            rate_limit,
+            window: FixedWindow::new(RATE_WINDOW),
+            declines: FixedWindow::new(DECLINE_WINDOW),
+            client_id: None,
+            reported: HashSet::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Inspects a DHCP message sent by the VM given the address
+    /// currently leased to it, returns false if it should be dropped
+    pub fn admit(&mut self, dhcp_packet: &[u8], leased: Option<Ipv4Address>) -> bool {
+        let admitted = self.within_rate_limit() && self.legitimate(dhcp_packet, leased);
+
+        if !admitted {
+            self.dropped += 1;
+        }
+
+        admitted
+    }
+
+    fn within_rate_limit(&mut self) -> bool {
+        let Some(rate_limit) = self.rate_limit else {
+            return true;
+        };
+
+        let count = self.window.hit(coarsetime::Instant::recent());
+
+        if count == rate_limit + 1 {
+            events::emit(&format!(
+                "VM exceeded the DHCP rate limit of {rate_limit} messages per second"
+            ));
+        }
+
+        count <= rate_limit
+    }
+
+    fn legitimate(&mut self, dhcp_packet: &[u8], leased: Option<Ipv4Address>) -> bool {
+        let Ok(message) = Message::from_bytes(dhcp_packet) else {
+            return false;
+        };
+
+        if message.chaddr() != self.vm_mac_address.0 {
+            self.report(
+                message.chaddr(),
+                &format!(
+                    "VM sent a DHCP message on behalf of another client {:02x?}, \
+                    possibly to exhaust the DHCP server's pool",
+                    message.chaddr()
+                ),
+            );
+
+            return false;
+        }
+
+        let msg_type = message.opts().msg_type();
+
+        if msg_type == Some(MessageType::Decline) && !self.within_decline_limit() {
+            return false;
+        }
+
+        let Some(DhcpOption::ClientIdentifier(client_id)) =
+            message.opts().get(OptionCode::ClientIdentifier)
+        else {
+            return true;
+        };
+
+        match &self.client_id {
+            Some(expected) if expected != client_id => {
+                self.report(
+                    client_id,
+                    &format!(
+                        "VM sent a DHCP message with client identifier {:02x?}, \
+                        while using {:02x?}, possibly to exhaust the DHCP server's pool",
+                        client_id, expected
+                    ),
+                );
+
+                return false;
+            }
+            Some(_) => {}
+            None => self.client_id = Some(client_id.clone()),
+        }
+
+        // Let the VM pick another identifier once it releases the leased
+        // address, but not on DECLINE, which it could send at will
+        if msg_type == Some(MessageType::Release) && leased == Some(message.ciaddr()) {
+            self.client_id = None;
+        }
+
+        true
+    }
+
+    fn within_decline_limit(&mut self) -> bool {
+        let count = self.declines.hit(coarsetime::Instant::recent());
+
+        if count == MAX_DECLINES + 1 {
+            events::emit(&format!(
+                "VM declined more than {} addresses in {} seconds, \
+                possibly to exhaust the DHCP server's pool",
+                MAX_DECLINES,
+                DECLINE_WINDOW.as_secs()
+            ));
+        }
+
+        count <= MAX_DECLINES
+    }
+
+    fn report(&mut self, identity: &[u8], message: &str) {
+        if self.reported.len() >= MAX_REPORTED {
+            self.reported.clear();
+        }
+
+        if self.reported.insert(identity.to_vec()) {
+            events::emit(message);
+        }
+    }
+
+    pub fn log_summary(&self) {
+        if self.dropped > 0 {
+            info!(
+                "DHCP guard dropped {} DHCP message(s) from the VM",
+                self.dropped
+            );
+        }
+    }
+}
+
+impl Proxy<'_> {
+    /// Drops the VM's DHCP messages that could exhaust the DHCP
+    /// server's pool, returns None if the frame was dropped
+    pub(crate) fn guard_dhcp(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Some(());
+        }
+
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+        if ipv4_pkt.next_header() != IpProtocol::Udp {
+            return Some(());
+        }
+
+        let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+        if !udp_pkt.is_dhcp_request() {
+            return Some(());
+        }
+
+        let leased = self.dhcp_snooper.lease().as_ref().map(Lease::address);
+        self.dhcp_guard
+            .admit(udp_pkt.payload(), leased)
+            .then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DhcpGuard, MAX_DECLINES};
+    use dhcproto::Encodable;
+    use dhcproto::v4::{DhcpOption, Message, MessageType};
+    use smoltcp::wire::{EthernetAddress, Ipv4Address};
+
+    const VM_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+    const OTHER_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+    const VM_IP: Ipv4Address = Ipv4Address::new(192, 168, 64, 2);
+
+    #[test]
+    fn test_spoofed_chaddr() {
+        let mut guard = guard(None);
+
+        assert!(guard.admit(&message(MessageType::Discover, VM_MAC, None), None));
+        assert!(!guard.admit(&message(MessageType::Discover, OTHER_MAC, None), None));
+        assert!(!guard.admit(b"garbage", None));
+        assert_eq!(guard.dropped, 2);
+    }
+
+    #[test]
+    fn test_client_id_is_pinned() {
+        let mut guard = guard(None);
+        let discover_a = message(MessageType::Discover, VM_MAC, Some(&[1, 2, 3]));
+        let discover_b = message(MessageType::Discover, VM_MAC, Some(&[4, 5, 6]));
+
+        assert!(guard.admit(&discover_a, None));
+        assert!(guard.admit(
+            &message(MessageType::Request, VM_MAC, Some(&[1, 2, 3])),
+            None
+        ));
+        assert!(!guard.admit(&discover_b, Some(VM_IP)));
+
+        // Declining doesn't unpin the identifier
+        assert!(guard.admit(
+            &message(MessageType::Decline, VM_MAC, Some(&[1, 2, 3])),
+            Some(VM_IP)
+        ));
+        assert!(!guard.admit(&discover_b, None));
+
+        // Neither does releasing an address that isn't leased
+        let mut release = Message::default();
+        release
+            .set_chaddr(&VM_MAC.0)
+            .set_ciaddr(Ipv4Address::new(192, 168, 64, 3));
+        release
+            .opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Release));
+        release
+            .opts_mut()
+            .insert(DhcpOption::ClientIdentifier(vec![1, 2, 3]));
+        assert!(guard.admit(&release.to_vec().unwrap(), Some(VM_IP)));
+        assert!(!guard.admit(&discover_b, Some(VM_IP)));
+
+        // Another identifier is fine after releasing the leased address
+        release.set_ciaddr(VM_IP);
+        assert!(guard.admit(&release.to_vec().unwrap(), Some(VM_IP)));
+        assert!(guard.admit(&discover_b, None));
+    }
+
+    #[test]
+    fn test_declines_are_limited() {
+        let mut guard = guard(None);
+        let decline = message(MessageType::Decline, VM_MAC, None);
+
+        for _ in 0..MAX_DECLINES {
+            assert!(guard.admit(&decline, Some(VM_IP)));
+        }
+        assert!(!guard.admit(&decline, Some(VM_IP)));
+
+        // Other messages are unaffected
+        assert!(guard.admit(&message(MessageType::Discover, VM_MAC, None), None));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut guard = guard(Some(2));
+        let discover = message(MessageType::Discover, VM_MAC, None);
+
+        assert!(guard.admit(&discover, None));
+        assert!(guard.admit(&discover, None));
+        assert!(!guard.admit(&discover, None));
+    }
+
+    fn guard(rate_limit: Option<u32>) -> DhcpGuard {
+        coarsetime::Instant::update();
+
+        DhcpGuard::new(VM_MAC, rate_limit)
+    }
+
+    fn message(
+        msg_type: MessageType,
+        chaddr: EthernetAddress,
+        client_id: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let mut message = Message::default();
+        message.set_chaddr(&chaddr.0);
+        message.opts_mut().insert(DhcpOption::MessageType(msg_type));
+
+        if let Some(client_id) = client_id {
+            message
+                .opts_mut()
+                .insert(DhcpOption::ClientIdentifier(client_id.to_vec()));
+        }
+
+        message.to_vec().unwrap()
+    }
+}
@@ -0,0 +1,388 @@
+use crate::proxy::Proxy;
+use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use anyhow::{Context, Error, Result, anyhow};
+use dhcproto::v4::{DhcpOption, Message, MessageType, Opcode, OptionCode, UnknownOption};
+use dhcproto::{Decodable, Encodable};
+use ipnet::Ipv4Net;
+use log::{info, warn};
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, UdpPacket};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+// RFC 2132, section 5.1
+const MIN_MTU: u16 = 68;
+
+/// Classless static route to push to the VM, parsed from CIDR=GATEWAY
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StaticRoute {
+    pub destination: Ipv4Net,
+    pub gateway: Ipv4Addr,
+}
+
+impl FromStr for StaticRoute {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (destination, gateway) = s.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "invalid static route {:?}, the format should be CIDR=GATEWAY",
+                s
+            )
+        })?;
+
+        Ok(StaticRoute {
+            destination: destination
+                .parse::<Ipv4Net>()
+                .context(format!(
+                    "invalid static route destination {:?}",
+                    destination
+                ))?
+                .trunc(),
+            gateway: gateway
+                .parse()
+                .context(format!("invalid static route gateway {:?}", gateway))?,
+        })
+    }
+}
+
+/// Overrides the selected options in the DHCP offers and acknowledgements
+/// sent to the VM, e.g. to point it at a filtering resolver
+pub struct DhcpRewriter {
+    dns_servers: Vec<Ipv4Addr>,
+    domain_name: Option<String>,
+    // Pre-encoded as per RFC 3397
+    domain_search: Option<Vec<u8>>,
+    mtu: Option<u16>,
+    routes: Vec<StaticRoute>,
+    rewritten: u64,
+}
+
+impl DhcpRewriter {
+    /// Returns None if there's nothing to rewrite
+    pub fn new(
+        dns_servers: Vec<Ipv4Addr>,
+        domain_name: Option<String>,
+        domain_search: Vec<String>,
+        mtu: Option<u16>,
+        routes: Vec<StaticRoute>,
+    ) -> Result<Option<DhcpRewriter>> {
+        if dns_servers.is_empty()
+            && domain_name.is_none()
+            && domain_search.is_empty()
+            && mtu.is_none()
+            && routes.is_empty()
+        {
+            return Ok(None);
+        }
+
+        if let Some(domain_name) = &domain_name {
+            encode_domain(domain_name)?;
+        }
+
+        let domain_search = if domain_search.is_empty() {
+            None
+        } else {
+            let mut encoded = Vec::new();
+
+            for domain in &domain_search {
+                encoded.extend(encode_domain(domain)?);
+            }
+
+            if encoded.len() > u8::MAX as usize {
+                return Err(anyhow!("DHCP domain search list is too long"));
+            }
+
+            Some(encoded)
+        };
+
+        if let Some(mtu) = mtu
+            && mtu < MIN_MTU
+        {
+            return Err(anyhow!("DHCP interface MTU should be at least {MIN_MTU}"));
+        }
+
+        Ok(Some(DhcpRewriter {
+            dns_servers,
+            domain_name,
+            domain_search,
+            mtu,
+            routes,
+            rewritten: 0,
+        }))
+    }
+
+    /// Rewrites the options in place, returns false if the message was left intact
+    pub fn rewrite(&mut self, message: &mut Message) -> bool {
+        if !matches!(
+            message.opts().msg_type(),
+            Some(MessageType::Offer | MessageType::Ack)
+        ) {
+            return false;
+        }
+
+        let opts = message.opts_mut();
+
+        if !self.dns_servers.is_empty() {
+            opts.insert(DhcpOption::DomainNameServer(self.dns_servers.clone()));
+        }
+
+        if let Some(domain_name) = &self.domain_name {
+            opts.insert(DhcpOption::DomainName(domain_name.clone()));
+        }
+
+        if let Some(domain_search) = &self.domain_search {
+            opts.insert(DhcpOption::Unknown(UnknownOption::new(
+                OptionCode::DomainSearch,
+                domain_search.clone(),
+            )));
+        }
+
+        if let Some(mtu) = self.mtu {
+            opts.insert(DhcpOption::InterfaceMtu(mtu));
+        }
+
+        if !self.routes.is_empty() {
+            let mut routes: Vec<_> = self
+                .routes
+                .iter()
+                .map(|route| (route.destination, route.gateway))
+                .collect();
+
+            // Clients ignore the router option in presence of the classless
+            // static routes (RFC 3442), so carry the default route over
+            let router = match opts.get(OptionCode::Router) {
+                Some(DhcpOption::Router(routers)) => routers.first().copied(),
+                _ => None,
+            };
+
+            if let Some(router) = router
+                && !routes
+                    .iter()
+                    .any(|(destination, _)| destination.prefix_len() == 0)
+            {
+                routes.push((Ipv4Net::default(), router));
+            }
+
+            opts.insert(DhcpOption::ClasslessStaticRoute(routes));
+        }
+
+        self.rewritten += 1;
+
+        true
+    }
+
+    pub fn log_summary(&self) {
+        info!("DHCP rewriter: rewrote {} reply(s)", self.rewritten);
+    }
+}
+
+/// Encodes the domain name in the RFC 1035 wire format, without compression
+fn encode_domain(domain: &str) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 || !label.is_ascii() {
+            return Err(anyhow!("invalid domain name {:?}", domain));
+        }
+
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+
+    encoded.push(0);
+
+    if encoded.len() > 255 {
+        return Err(anyhow!("domain name {:?} is too long", domain));
+    }
+
+    Ok(encoded)
+}
+
+impl Proxy<'_> {
+    /// Rewrites the DHCP reply from the host destined to the VM,
+    /// returns the new frame or None if it should be forwarded as is
+    pub(crate) fn rewrite_dhcp_reply(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<Vec<u8>> {
+        let dhcp_rewriter = self.dhcp_rewriter.as_mut()?;
+
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return None;
+        }
+
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+        if ipv4_pkt.src_addr() != self.host.gateway_ip || ipv4_pkt.next_header() != IpProtocol::Udp
+        {
+            return None;
+        }
+
+        let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+        if !udp_pkt.is_dhcp_response() {
+            return None;
+        }
+
+        let mut message = Message::from_bytes(udp_pkt.payload()).ok()?;
+
+        if message.opcode() != Opcode::BootReply || message.chaddr() != self.vm_mac_address.0 {
+            return None;
+        }
+
+        if !dhcp_rewriter.rewrite(&mut message) {
+            return None;
+        }
+
+        let payload = match message.to_vec() {
+            Ok(payload) => payload,
+            Err(err) => {
+                warn!("failed to encode the rewritten DHCP reply: {err}");
+
+                return None;
+            }
+        };
+
+        // Keep the Ethernet and IPv4 headers (including the IP options, if any)
+        // and rebuild everything after them to accommodate the new length
+        let headers_len = EthernetFrame::<&[u8]>::header_len() + ipv4_pkt.header_len() as usize;
+        let udp_len = 8 + payload.len();
+
+        let mut buf = frame.as_ref()[..headers_len].to_vec();
+        buf.resize(headers_len + udp_len, 0);
+
+        let mut frame_mut = EthernetFrame::new_unchecked(&mut buf[..]);
+        let mut ipv4_pkt_mut = Ipv4Packet::new_unchecked(frame_mut.payload_mut());
+        ipv4_pkt_mut.set_total_len((ipv4_pkt.header_len() as usize + udp_len) as u16);
+        ipv4_pkt_mut.fill_checksum();
+
+        let src_addr = ipv4_pkt_mut.src_addr();
+        let dst_addr = ipv4_pkt_mut.dst_addr();
+
+        let mut udp_pkt_mut = UdpPacket::new_unchecked(ipv4_pkt_mut.payload_mut());
+        udp_pkt_mut.set_src_port(udp_pkt.src_port());
+        udp_pkt_mut.set_dst_port(udp_pkt.dst_port());
+        udp_pkt_mut.set_len(udp_len as u16);
+        udp_pkt_mut.payload_mut().copy_from_slice(&payload);
+        udp_pkt_mut.fill_checksum(&src_addr.into(), &dst_addr.into());
+
+        Some(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DhcpRewriter, StaticRoute};
+    use dhcproto::v4::{DhcpOption, Message, MessageType, OptionCode};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_static_route_parsing() {
+        assert_eq!(
+            "10.0.0.1/8=192.168.64.1".parse::<StaticRoute>().unwrap(),
+            StaticRoute {
+                destination: "10.0.0.0/8".parse().unwrap(),
+                gateway: Ipv4Addr::new(192, 168, 64, 1),
+            }
+        );
+        assert!("10.0.0.0/8".parse::<StaticRoute>().is_err());
+        assert!("10.0.0.0/8=gateway".parse::<StaticRoute>().is_err());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let dns = Ipv4Addr::new(10, 0, 0, 53);
+        let router = Ipv4Addr::new(192, 168, 64, 1);
+
+        assert!(
+            DhcpRewriter::new(vec![], None, vec![], None, vec![])
+                .unwrap()
+                .is_none()
+        );
+        assert!(DhcpRewriter::new(vec![], None, vec![], Some(20), vec![]).is_err());
+        assert!(
+            DhcpRewriter::new(
+                vec![],
+                Some("bad..domain".to_string()),
+                vec![],
+                None,
+                vec![]
+            )
+            .is_err()
+        );
+
+        let mut rewriter = DhcpRewriter::new(
+            vec![dns],
+            Some("corp.example".to_string()),
+            vec!["corp.example".to_string(), "example.".to_string()],
+            Some(1400),
+            vec!["10.0.0.0/8=192.168.64.1".parse().unwrap()],
+        )
+        .unwrap()
+        .unwrap();
+
+        let mut ack = Message::default();
+        ack.opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Ack));
+        ack.opts_mut()
+            .insert(DhcpOption::DomainNameServer(vec![router]));
+        ack.opts_mut().insert(DhcpOption::Router(vec![router]));
+        assert!(rewriter.rewrite(&mut ack));
+
+        let opts = ack.opts();
+        assert_eq!(
+            opts.get(OptionCode::DomainNameServer),
+            Some(&DhcpOption::DomainNameServer(vec![dns]))
+        );
+        assert_eq!(
+            opts.get(OptionCode::DomainName),
+            Some(&DhcpOption::DomainName("corp.example".to_string()))
+        );
+        assert_eq!(
+            opts.get(OptionCode::InterfaceMtu),
+            Some(&DhcpOption::InterfaceMtu(1400))
+        );
+        assert!(opts.get(OptionCode::DomainSearch).is_some());
+
+        // Default route is carried over from the router option
+        assert_eq!(
+            opts.get(OptionCode::ClasslessStaticRoute),
+            Some(&DhcpOption::ClasslessStaticRoute(vec![
+                ("10.0.0.0/8".parse().unwrap(), router),
+                ("0.0.0.0/0".parse().unwrap(), router),
+            ]))
+        );
+
+        // NAKs carry no configuration
+        let mut nak = Message::default();
+        nak.opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Nak));
+        assert!(!rewriter.rewrite(&mut nak));
+        assert!(nak.opts().get(OptionCode::DomainNameServer).is_none());
+    }
+
+    #[test]
+    fn test_configured_default_route() {
+        let mut rewriter = DhcpRewriter::new(
+            vec![],
+            None,
+            vec![],
+            None,
+            vec!["0.0.0.0/0=192.168.64.254".parse().unwrap()],
+        )
+        .unwrap()
+        .unwrap();
+
+        let mut ack = Message::default();
+        ack.opts_mut()
+            .insert(DhcpOption::MessageType(MessageType::Ack));
+        ack.opts_mut()
+            .insert(DhcpOption::Router(vec![Ipv4Addr::new(192, 168, 64, 1)]));
+        assert!(rewriter.rewrite(&mut ack));
+
+        assert_eq!(
+            ack.opts().get(OptionCode::ClasslessStaticRoute),
+            Some(&DhcpOption::ClasslessStaticRoute(vec![(
+                "0.0.0.0/0".parse().unwrap(),
+                Ipv4Addr::new(192, 168, 64, 254)
+            )]))
+        );
+    }
+}
@@ -0,0 +1,505 @@
+use crate::proxy::Proxy;
+use crate::proxy::lease_file::LeaseFile;
+use anyhow::{Context, Error, Result, anyhow};
+use dhcproto::v4::{DhcpOption, Message, MessageType, Opcode, OptionCode};
+use dhcproto::{Decodable, Encodable};
+use log::{info, warn};
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet,
+    UdpPacket,
+};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub(crate) const BOOTPS_PORT: u16 = 67;
+pub(crate) const BOOTPC_PORT: u16 = 68;
+
+// How long an offered address is held for the client to request it
+const OFFER_HOLD: Duration = Duration::from_secs(60);
+
+// How long a declined address is kept out of the pool
+const DECLINE_QUARANTINE: Duration = Duration::from_secs(10 * 60);
+
+// Source MAC address of the replies until the host's one is learned
+const FALLBACK_MAC_ADDRESS: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0]);
+
+/// Range of addresses to hand out, parsed from START-END
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DhcpPool {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+}
+
+impl FromStr for DhcpPool {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("DHCP pool should be in the START-END format"))?;
+
+        let start = start
+            .parse()
+            .context(format!("invalid DHCP pool start address {:?}", start))?;
+        let end = end
+            .parse()
+            .context(format!("invalid DHCP pool end address {:?}", end))?;
+
+        if start > end {
+            return Err(anyhow!("DHCP pool start address is past its end address"));
+        }
+
+        Ok(DhcpPool { start, end })
+    }
+}
+
+pub struct DhcpServer {
+    server_ip: Ipv4Addr,
+    subnet_mask: Ipv4Addr,
+    pool: Vec<Ipv4Addr>,
+    lease_time: Duration,
+    lease_file: LeaseFile,
+    acks: u64,
+    naks: u64,
+}
+
+impl DhcpServer {
+    pub fn new(
+        pool: DhcpPool,
+        lease_time: Duration,
+        lease_file_path: &Path,
+        server_ip: Ipv4Addr,
+        subnet_mask: Ipv4Addr,
+    ) -> Result<DhcpServer> {
+        let mask = subnet_mask.to_bits();
+        let subnet = server_ip.to_bits() & mask;
+
+        if pool.start.to_bits() & mask != subnet || pool.end.to_bits() & mask != subnet {
+            return Err(anyhow!(
+                "DHCP pool {}-{} is outside of the {}/{} subnet",
+                pool.start,
+                pool.end,
+                Ipv4Addr::from_bits(subnet),
+                mask.count_ones()
+            ));
+        }
+
+        let pool = (pool.start.to_bits()..=pool.end.to_bits())
+            .map(Ipv4Addr::from_bits)
+            .filter(|address| *address != server_ip)
+            .collect();
+
+        Ok(DhcpServer {
+            server_ip,
+            subnet_mask,
+            pool,
+            lease_time,
+            lease_file: LeaseFile::open(lease_file_path)?,
+            acks: 0,
+            naks: 0,
+        })
+    }
+
+    /// Answers the client's message, returns None if there's nothing to reply with
+    pub fn handle(
+        &mut self,
+        hw_address: EthernetAddress,
+        request: &Message,
+    ) -> Result<Option<Message>> {
+        if request.opcode() != Opcode::BootRequest || request.chaddr() != hw_address.0 {
+            return Ok(None);
+        }
+
+        let requested_ip = match request.opts().get(OptionCode::RequestedIpAddress) {
+            Some(DhcpOption::RequestedIpAddress(requested_ip)) => Some(*requested_ip),
+            _ => None,
+        };
+        let server = match request.opts().get(OptionCode::ServerIdentifier) {
+            Some(DhcpOption::ServerIdentifier(server)) => Some(*server),
+            _ => None,
+        };
+
+        match request.opts().msg_type() {
+            Some(MessageType::Discover) => {
+                let Some(address) =
+                    self.lease_file
+                        .offer(hw_address, requested_ip, &self.pool, OFFER_HOLD)?
+                else {
+                    warn!("DHCP pool is exhausted, not offering an address to the VM");
+
+                    return Ok(None);
+                };
+
+                Ok(Some(self.reply(request, MessageType::Offer, address)))
+            }
+            Some(MessageType::Request) => {
+                // Client has selected another server
+                if server.is_some_and(|server| server != self.server_ip) {
+                    return Ok(None);
+                }
+
+                let address = requested_ip.unwrap_or(request.ciaddr());
+
+                if address.is_unspecified() {
+                    return Ok(None);
+                }
+
+                if self.pool.contains(&address)
+                    && self
+                        .lease_file
+                        .commit(hw_address, address, self.lease_time)?
+                {
+                    self.acks += 1;
+
+                    Ok(Some(self.reply(request, MessageType::Ack, address)))
+                } else {
+                    self.naks += 1;
+
+                    Ok(Some(self.reply(
+                        request,
+                        MessageType::Nak,
+                        Ipv4Addr::UNSPECIFIED,
+                    )))
+                }
+            }
+            Some(MessageType::Release) => {
+                self.lease_file.release(hw_address, request.ciaddr())?;
+
+                Ok(None)
+            }
+            Some(MessageType::Decline) => {
+                if let Some(requested_ip) = requested_ip {
+                    warn!("VM declined {requested_ip}, taking it out of the DHCP pool");
+
+                    self.lease_file
+                        .decline(hw_address, requested_ip, DECLINE_QUARANTINE)?;
+                }
+
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn reply(&self, request: &Message, msg_type: MessageType, address: Ipv4Addr) -> Message {
+        let mut reply = Message::new_with_id(
+            request.xid(),
+            request.ciaddr(),
+            address,
+            Ipv4Addr::UNSPECIFIED,
+            request.giaddr(),
+            request.chaddr(),
+        );
+        reply.set_opcode(Opcode::BootReply);
+        reply.set_flags(request.flags());
+
+        let opts = reply.opts_mut();
+        opts.insert(DhcpOption::MessageType(msg_type));
+        opts.insert(DhcpOption::ServerIdentifier(self.server_ip));
+
+        if msg_type != MessageType::Nak {
+            let lease_time = self.lease_time.as_secs() as u32;
+
+            // RFC 2131 defaults for T1 and T2
+            opts.insert(DhcpOption::AddressLeaseTime(lease_time));
+            opts.insert(DhcpOption::Renewal(lease_time / 2));
+            opts.insert(DhcpOption::Rebinding(lease_time / 8 * 7));
+            opts.insert(DhcpOption::SubnetMask(self.subnet_mask));
+            opts.insert(DhcpOption::Router(vec![self.server_ip]));
+            opts.insert(DhcpOption::DomainNameServer(vec![self.server_ip]));
+        }
+
+        reply
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            "DHCP server: acknowledged {} request(s), rejected {}",
+            self.acks, self.naks
+        );
+    }
+}
+
+impl Proxy<'_> {
+    /// Answers the VM's DHCP messages when running the built-in server
+    /// instead of bootpd(8), returns None if the frame was consumed
+    pub(crate) fn serve_dhcp(&mut self, frame: &EthernetFrame<&[u8]>) -> Result<Option<()>> {
+        let Some(dhcp_server) = &mut self.dhcp_server else {
+            return Ok(Some(()));
+        };
+
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Ok(Some(()));
+        }
+
+        let Ok(ipv4_pkt) = Ipv4Packet::new_checked(frame.payload()) else {
+            return Ok(Some(()));
+        };
+
+        if ipv4_pkt.next_header() != IpProtocol::Udp {
+            return Ok(Some(()));
+        }
+
+        let Ok(udp_pkt) = UdpPacket::new_checked(ipv4_pkt.payload()) else {
+            return Ok(Some(()));
+        };
+
+        if udp_pkt.dst_port() != BOOTPS_PORT {
+            return Ok(Some(()));
+        }
+
+        // The VM's DHCP never reaches bootpd(8) from now on
+        let Ok(request) = Message::from_bytes(udp_pkt.payload()) else {
+            return Ok(None);
+        };
+
+        let mut reply = match dhcp_server.handle(self.vm_mac_address, &request) {
+            Ok(Some(reply)) => reply,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                warn!("DHCP server failed to handle the VM's request: {err:#}");
+
+                return Ok(None);
+            }
+        };
+
+        if let Some(dhcp_rewriter) = &mut self.dhcp_rewriter {
+            dhcp_rewriter.rewrite(&mut reply);
+        }
+
+        let payload = reply.to_vec().context("failed to encode DHCP reply")?;
+
+        // Being authoritative, the snooper knows the lease by construction
+        self.learn_lease(&payload);
+
+        // RFC 2131, section 4.1
+        let nak = reply.opts().msg_type() == Some(MessageType::Nak);
+        let dst_ip = if nak {
+            Ipv4Address::BROADCAST
+        } else if !request.ciaddr().is_unspecified() {
+            request.ciaddr()
+        } else if request.flags().broadcast() {
+            Ipv4Address::BROADCAST
+        } else {
+            reply.yiaddr()
+        };
+        let dst_mac = if dst_ip.is_broadcast() {
+            EthernetAddress::BROADCAST
+        } else {
+            self.vm_mac_address
+        };
+        let src_mac = self
+            .gateway_mac_address
+            .or(Some(frame.dst_addr()).filter(EthernetAddress::is_unicast))
+            .unwrap_or(FALLBACK_MAC_ADDRESS);
+
+        let reply_frame = craft(
+            src_mac,
+            dst_mac,
+            self.host.gateway_ip,
+            dst_ip,
+            BOOTPS_PORT,
+            BOOTPC_PORT,
+            &payload,
+        );
+
+        self.write_to_vm(&reply_frame)?;
+
+        Ok(None)
+    }
+}
+
+/// Wraps the DHCP message into UDP, IPv4 and Ethernet headers
+pub(crate) fn craft(
+    src_mac: EthernetAddress,
+    dst_mac: EthernetAddress,
+    src_ip: Ipv4Address,
+    dst_ip: Ipv4Address,
+    src_port: u16,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let ipv4_len = 20 + udp_len;
+    let mut buf = vec![0u8; 14 + ipv4_len];
+
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    frame.set_src_addr(src_mac);
+    frame.set_dst_addr(dst_mac);
+    frame.set_ethertype(EthernetProtocol::Ipv4);
+
+    let mut ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload_mut());
+    ipv4_pkt.set_version(4);
+    ipv4_pkt.set_header_len(20);
+    ipv4_pkt.set_total_len(ipv4_len as u16);
+    ipv4_pkt.set_hop_limit(64);
+    ipv4_pkt.set_next_header(IpProtocol::Udp);
+    ipv4_pkt.set_src_addr(src_ip);
+    ipv4_pkt.set_dst_addr(dst_ip);
+    ipv4_pkt.fill_checksum();
+
+    let mut udp_pkt = UdpPacket::new_unchecked(ipv4_pkt.payload_mut());
+    udp_pkt.set_src_port(src_port);
+    udp_pkt.set_dst_port(dst_port);
+    udp_pkt.set_len(udp_len as u16);
+    udp_pkt.payload_mut().copy_from_slice(payload);
+    udp_pkt.fill_checksum(&src_ip.into(), &dst_ip.into());
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DhcpPool, DhcpServer};
+    use dhcproto::v4::{DhcpOption, Message, MessageType, OptionCode};
+    use smoltcp::wire::EthernetAddress;
+    use std::net::Ipv4Addr;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    const VM_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+    const OTHER_MAC: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x02]);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 64, 1);
+
+    #[test]
+    fn test_pool_parsing() {
+        assert_eq!(
+            "192.168.64.10-192.168.64.20".parse::<DhcpPool>().unwrap(),
+            DhcpPool {
+                start: Ipv4Addr::new(192, 168, 64, 10),
+                end: Ipv4Addr::new(192, 168, 64, 20),
+            }
+        );
+        assert!("192.168.64.20-192.168.64.10".parse::<DhcpPool>().is_err());
+        assert!("192.168.64.10".parse::<DhcpPool>().is_err());
+    }
+
+    #[test]
+    fn test_discover_request() {
+        let path = lease_file_path("discover-request");
+        let mut server = server(&path, "192.168.64.2-192.168.64.3");
+
+        let offer = server
+            .handle(VM_MAC, &request(VM_MAC, MessageType::Discover, None))
+            .unwrap()
+            .unwrap();
+        assert_eq!(offer.opts().msg_type(), Some(MessageType::Offer));
+        assert_eq!(offer.yiaddr(), Ipv4Addr::new(192, 168, 64, 2));
+        assert_eq!(
+            offer.opts().get(OptionCode::ServerIdentifier),
+            Some(&DhcpOption::ServerIdentifier(SERVER))
+        );
+
+        let ack = server
+            .handle(
+                VM_MAC,
+                &request(VM_MAC, MessageType::Request, Some(offer.yiaddr())),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(ack.opts().msg_type(), Some(MessageType::Ack));
+        assert_eq!(ack.yiaddr(), offer.yiaddr());
+        assert_eq!(
+            ack.opts().get(OptionCode::AddressLeaseTime),
+            Some(&DhcpOption::AddressLeaseTime(600))
+        );
+
+        // Messages of other clients are not answered
+        assert!(
+            server
+                .handle(VM_MAC, &request(OTHER_MAC, MessageType::Discover, None))
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_instances_share_the_pool() {
+        let path = lease_file_path("shared-pool");
+        let mut first = server(&path, "192.168.64.2-192.168.64.2");
+        let mut second = server(&path, "192.168.64.2-192.168.64.2");
+
+        let ack = first
+            .handle(
+                VM_MAC,
+                &request(
+                    VM_MAC,
+                    MessageType::Request,
+                    Some(Ipv4Addr::new(192, 168, 64, 2)),
+                ),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(ack.opts().msg_type(), Some(MessageType::Ack));
+
+        // The only address is taken by the VM of the first instance
+        assert!(
+            second
+                .handle(OTHER_MAC, &request(OTHER_MAC, MessageType::Discover, None))
+                .unwrap()
+                .is_none()
+        );
+        let nak = second
+            .handle(
+                OTHER_MAC,
+                &request(OTHER_MAC, MessageType::Request, Some(ack.yiaddr())),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(nak.opts().msg_type(), Some(MessageType::Nak));
+
+        // Until it's released
+        let mut release = request(VM_MAC, MessageType::Release, None);
+        release.set_ciaddr(ack.yiaddr());
+        assert!(first.handle(VM_MAC, &release).unwrap().is_none());
+
+        let offer = second
+            .handle(OTHER_MAC, &request(OTHER_MAC, MessageType::Discover, None))
+            .unwrap()
+            .unwrap();
+        assert_eq!(offer.yiaddr(), ack.yiaddr());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn server(path: &Path, pool: &str) -> DhcpServer {
+        DhcpServer::new(
+            pool.parse().unwrap(),
+            Duration::from_secs(600),
+            path,
+            SERVER,
+            Ipv4Addr::new(255, 255, 255, 0),
+        )
+        .unwrap()
+    }
+
+    fn lease_file_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("softnet-leases-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        path
+    }
+
+    fn request(
+        hw_address: EthernetAddress,
+        msg_type: MessageType,
+        requested_ip: Option<Ipv4Addr>,
+    ) -> Message {
+        let mut message = Message::default();
+        message.set_chaddr(&hw_address.0);
+        message.opts_mut().insert(DhcpOption::MessageType(msg_type));
+
+        if let Some(requested_ip) = requested_ip {
+            message
+                .opts_mut()
+                .insert(DhcpOption::RequestedIpAddress(requested_ip));
+        }
+
+        message
+    }
+}
@@ -0,0 +1,430 @@
+use crate::proxy::Proxy;
+use crate::proxy::events;
+use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use log::info;
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, UdpPacket};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// How long a base domain stays blocked once it's used for tunnelling
+const BLOCK_DURATION: Duration = Duration::from_secs(15 * 60);
+
+// Upper bounds on the number of tracked and blocked base domains
+const MAX_TRACKED_DOMAINS: usize = 4096;
+const MAX_BLOCKED_DOMAINS: usize = 4096;
+
+const DNS_HEADER_LEN: usize = 12;
+
+const QTYPE_NULL: u16 = 10;
+const QTYPE_TXT: u16 = 16;
+
+// Points added to the base domain's score by a single query for each of
+// the tunnelling signs, the plain queries add none no matter how many
+const LONG_LABEL_POINTS: u32 = 5;
+const HIGH_ENTROPY_POINTS: u32 = 5;
+const TXT_OR_NULL_POINTS: u32 = 3;
+
+// Labels are up to 63 characters long, the legitimate ones rarely exceed 40
+const LONG_LABEL_LEN: usize = 40;
+const LONG_NAME_LEN: usize = 100;
+
+// Encoded payloads (Base32, Base64, hex) are dense, while the
+// host names are mostly made of words and short identifiers
+const HIGH_ENTROPY_MIN_LEN: usize = 24;
+const HIGH_ENTROPY_BITS: f64 = 3.8;
+
+// Public suffixes spanning two labels, under which the registrable
+// domain has three labels. Any other name is keyed on its last two
+// labels, so that a tunnel can't hide its payload in the base domain
+const TWO_LABEL_SUFFIXES: &[&str] = &[
+    "ac.jp", "ac.uk", "co.id", "co.il", "co.in", "co.jp", "co.kr", "co.nz", "co.uk", "co.za",
+    "com.ar", "com.au", "com.br", "com.cn", "com.hk", "com.mx", "com.my", "com.ph", "com.sg",
+    "com.tr", "com.tw", "com.ua", "com.vn", "edu.au", "gov.au", "gov.uk", "ne.jp", "net.au",
+    "net.br", "net.cn", "net.nz", "or.jp", "org.au", "org.br", "org.cn", "org.nz", "org.uk",
+];
+
+/// Question of a DNS query sent by the VM
+#[derive(Debug, PartialEq)]
+pub struct Question {
+    pub labels: Vec<String>,
+    pub qtype: u16,
+}
+
+impl Question {
+    /// Parses the first question of a DNS query,
+    /// compressed names are not expected in queries
+    pub fn parse(message: &[u8]) -> Option<Question> {
+        if message.len() < DNS_HEADER_LEN {
+            return None;
+        }
+
+        // QR bit is set for responses
+        let is_response = message[2] & 0x80 != 0;
+        let qdcount = u16::from_be_bytes([message[4], message[5]]);
+
+        if is_response || qdcount == 0 {
+            return None;
+        }
+
+        let mut labels = Vec::new();
+        let mut offset = DNS_HEADER_LEN;
+
+        loop {
+            let len = *message.get(offset)? as usize;
+            offset += 1;
+
+            if len == 0 {
+                break;
+            }
+
+            if len > 63 {
+                return None;
+            }
+
+            let label = message.get(offset..offset + len)?;
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            offset += len;
+        }
+
+        let qtype = message.get(offset..offset + 2)?;
+
+        Some(Question {
+            labels,
+            qtype: u16::from_be_bytes([qtype[0], qtype[1]]),
+        })
+    }
+
+    /// Registrable part of the name, approximated as the last two labels
+    /// or the last three for the names under the well-known suffixes like co.uk
+    pub fn base_domain(&self) -> String {
+        self.labels[self.labels.len() - self.base_domain_labels()..].join(".")
+    }
+
+    fn base_domain_labels(&self) -> usize {
+        let n = match self.labels.as_slice() {
+            [.., second_level, top_level]
+                if TWO_LABEL_SUFFIXES.iter().any(|suffix| {
+                    suffix.split_once('.') == Some((second_level.as_str(), top_level.as_str()))
+                }) =>
+            {
+                3
+            }
+            _ => 2,
+        };
+
+        n.min(self.labels.len())
+    }
+
+    /// Points this query adds to its base domain's score
+    fn score(&self) -> u32 {
+        let mut score = 0;
+
+        let subdomain = &self.labels[..self.labels.len() - self.base_domain_labels()];
+        let name_len: usize = self.labels.iter().map(|label| label.len() + 1).sum();
+
+        if subdomain.iter().any(|label| label.len() >= LONG_LABEL_LEN) || name_len >= LONG_NAME_LEN
+        {
+            score += LONG_LABEL_POINTS;
+        }
+
+        let subdomain = subdomain.concat();
+
+        if subdomain.len() >= HIGH_ENTROPY_MIN_LEN
+            && entropy(subdomain.as_bytes()) >= HIGH_ENTROPY_BITS
+        {
+            score += HIGH_ENTROPY_POINTS;
+        }
+
+        if matches!(self.qtype, QTYPE_TXT | QTYPE_NULL) {
+            score += TXT_OR_NULL_POINTS;
+        }
+
+        score
+    }
+}
+
+/// Shannon entropy in bits per character
+fn entropy(s: &[u8]) -> f64 {
+    let mut counts = [0u32; 256];
+
+    for &c in s {
+        counts[c as usize] += 1;
+    }
+
+    let len = s.len() as f64;
+
+    counts
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+
+            -p * p.log2()
+        })
+        .sum()
+}
+
+struct Domain {
+    window_start: coarsetime::Instant,
+    score: u32,
+    queries: u32,
+}
+
+pub struct DnsTunnelDetector {
+    threshold: u32,
+    domains: HashMap<String, Domain>,
+    // Blocked base domains along with when they're unblocked
+    blocked: HashMap<String, coarsetime::Instant>,
+    detections: u64,
+    dropped: u64,
+}
+
+impl DnsTunnelDetector {
+    pub fn new(threshold: u32) -> DnsTunnelDetector {
+        DnsTunnelDetector {
+            threshold,
+            domains: HashMap::new(),
+            blocked: HashMap::new(),
+            detections: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Scores a DNS query sent by the VM, returns false if its base
+    /// domain is used for tunnelling and the query should be dropped
+    pub fn inspect(&mut self, now: coarsetime::Instant, question: &Question) -> bool {
+        let base_domain = question.base_domain();
+
+        if let Some(&blocked_until) = self.blocked.get(&base_domain) {
+            if now < blocked_until {
+                self.dropped += 1;
+
+                return false;
+            }
+
+            self.blocked.remove(&base_domain);
+        }
+
+        let score = question.score();
+
+        if score == 0 {
+            return true;
+        }
+
+        if !self.domains.contains_key(&base_domain) && self.domains.len() >= MAX_TRACKED_DOMAINS {
+            self.domains
+                .retain(|_, domain| now.duration_since(domain.window_start) < WINDOW.into());
+
+            if self.domains.len() >= MAX_TRACKED_DOMAINS {
+                return true;
+            }
+        }
+
+        let domain = self
+            .domains
+            .entry(base_domain.clone())
+            .or_insert_with(|| Domain {
+                window_start: now,
+                score: 0,
+                queries: 0,
+            });
+
+        if now.duration_since(domain.window_start) >= WINDOW.into() {
+            domain.window_start = now;
+            domain.score = 0;
+            domain.queries = 0;
+        }
+
+        domain.score += score;
+        domain.queries += 1;
+
+        if domain.score < self.threshold {
+            return true;
+        }
+
+        events::emit(&format!(
+            "VM is likely tunnelling data through DNS queries to {} \
+            (score {} from {} suspicious queries in the last {} seconds), \
+            blocking the domain for {} minutes",
+            base_domain,
+            domain.score,
+            domain.queries,
+            WINDOW.as_secs(),
+            BLOCK_DURATION.as_secs() / 60
+        ));
+
+        self.domains.remove(&base_domain);
+
+        self.block(now, base_domain);
+        self.detections += 1;
+        self.dropped += 1;
+
+        false
+    }
+
+    fn block(&mut self, now: coarsetime::Instant, base_domain: String) {
+        if self.blocked.len() >= MAX_BLOCKED_DOMAINS {
+            self.blocked.retain(|_, blocked_until| now < *blocked_until);
+        }
+
+        // Unblock the domain blocked the longest ago to make room
+        if self.blocked.len() >= MAX_BLOCKED_DOMAINS
+            && let Some(oldest) = self
+                .blocked
+                .iter()
+                .min_by_key(|(_, blocked_until)| **blocked_until)
+                .map(|(base_domain, _)| base_domain.clone())
+        {
+            self.blocked.remove(&oldest);
+        }
+
+        self.blocked
+            .insert(base_domain, now + BLOCK_DURATION.into());
+    }
+
+    pub fn log_summary(&self) {
+        if self.detections != 0 {
+            info!(
+                "DNS tunnel detector: blocked {} domain(s), dropped {} queries",
+                self.detections, self.dropped
+            );
+        }
+    }
+}
+
+impl Proxy<'_> {
+    /// Scores the VM's DNS queries for the signs of tunnelling and
+    /// exfiltration, returns None if the query should be dropped
+    pub(crate) fn detect_dns_tunnel(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
+        let Some(dns_tunnel_detector) = &mut self.dns_tunnel_detector else {
+            return Some(());
+        };
+
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Some(());
+        }
+
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+        if ipv4_pkt.next_header() != IpProtocol::Udp || ipv4_pkt.frag_offset() != 0 {
+            return Some(());
+        }
+
+        let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+        if !udp_pkt.is_dns_request() {
+            return Some(());
+        }
+
+        let Some(question) = Question::parse(udp_pkt.payload()) else {
+            return Some(());
+        };
+
+        dns_tunnel_detector
+            .inspect(coarsetime::Instant::recent(), &question)
+            .then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BLOCK_DURATION, DnsTunnelDetector, QTYPE_TXT, Question, WINDOW, entropy};
+
+    const QTYPE_A: u16 = 1;
+
+    #[test]
+    fn test_parse() {
+        let question = Question::parse(&query("www.Example.co.uk", QTYPE_A)).unwrap();
+        assert_eq!(question.labels, vec!["www", "example", "co", "uk"]);
+        assert_eq!(question.qtype, QTYPE_A);
+        assert_eq!(question.base_domain(), "example.co.uk");
+
+        let question = Question::parse(&query("mail.example.com", QTYPE_TXT)).unwrap();
+        assert_eq!(question.base_domain(), "example.com");
+
+        // Short registrable domains under the country-code TLDs
+        let question = Question::parse(&query("api.abc.io", QTYPE_A)).unwrap();
+        assert_eq!(question.base_domain(), "abc.io");
+
+        // Truncated
+        assert_eq!(Question::parse(&query("example.com", QTYPE_A)[..20]), None);
+
+        // Response
+        let mut response = query("example.com", QTYPE_A);
+        response[2] |= 0x80;
+        assert_eq!(Question::parse(&response), None);
+    }
+
+    #[test]
+    fn test_scoring() {
+        assert!(entropy(b"aaaaaaaa") < 0.1);
+        assert!(entropy(b"mzxw6ytboi2gk3tuebuw4zlsmvzw4ylmnfzxiy3f") > 3.8);
+
+        let benign = Question::parse(&query("www.example.com", QTYPE_A)).unwrap();
+        assert_eq!(benign.score(), 0);
+
+        let encoded = Question::parse(&query(
+            "mzxw6ytboi2gk3tuebuw4zlsmvzw4ylmnfzxiy3fnfxgo.t.example.com",
+            QTYPE_TXT,
+        ))
+        .unwrap();
+        assert_eq!(encoded.score(), 5 + 5 + 3);
+
+        // Payload isn't mistaken for a part of a short base domain
+        let encoded = Question::parse(&query(
+            "mzxw6ytboi2gk3tuebuw4zlsmvzw4ylmnfzxiy3fnfxgo.abc.io",
+            QTYPE_A,
+        ))
+        .unwrap();
+        assert_eq!(encoded.base_domain(), "abc.io");
+        assert_eq!(encoded.score(), 5 + 5);
+    }
+
+    #[test]
+    fn test_blocking() {
+        let mut detector = DnsTunnelDetector::new(50);
+        let start = coarsetime::Instant::now();
+
+        let encoded = Question::parse(&query(
+            "mzxw6ytboi2gk3tuebuw4zlsmvzw4ylmnfzxiy3fnfxgo.t.example.com",
+            QTYPE_TXT,
+        ))
+        .unwrap();
+        let benign = Question::parse(&query("www.example.org", QTYPE_A)).unwrap();
+
+        for _ in 0..3 {
+            assert!(detector.inspect(start, &encoded));
+        }
+        assert!(!detector.inspect(start, &encoded));
+        assert_eq!(detector.detections, 1);
+
+        // The whole base domain is blocked, but not forever
+        let other = Question::parse(&query("www.example.com", QTYPE_A)).unwrap();
+        assert!(!detector.inspect(start, &other));
+        assert!(detector.inspect(start + BLOCK_DURATION.into(), &other));
+
+        // Regular lookups never add up, no matter how many
+        for _ in 0..1000 {
+            assert!(detector.inspect(start, &benign));
+        }
+        let later = start + WINDOW.into();
+        assert!(detector.inspect(later, &benign));
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+
+        for label in name.split('.') {
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+
+        message.push(0);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&1u16.to_be_bytes());
+
+        message
+    }
+}
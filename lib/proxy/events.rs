@@ -0,0 +1,9 @@
+use log::warn;
+
+/// Reports a noteworthy event caused by the VM both to the log and to Sentry,
+/// the VM identity is attached to the latter by the means of scope tags
+pub(crate) fn emit(message: &str) {
+    warn!("{message}");
+
+    sentry::capture_message(message, sentry::Level::Warning);
+}
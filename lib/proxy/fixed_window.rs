@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+/// Counts the events within the consecutive fixed-length windows of time
+pub struct FixedWindow {
+    length: Duration,
+    start: coarsetime::Instant,
+    count: u32,
+}
+
+impl FixedWindow {
+    pub fn new(length: Duration) -> FixedWindow {
+        FixedWindow {
+            length,
+            start: coarsetime::Instant::recent(),
+            count: 0,
+        }
+    }
+
+    /// Registers an event, returns the number of events in the current window including it
+    pub fn hit(&mut self, now: coarsetime::Instant) -> u32 {
+        if now.duration_since(self.start) >= self.length.into() {
+            self.start = now;
+            self.count = 0;
+        }
+
+        self.count = self.count.saturating_add(1);
+
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FixedWindow;
+    use std::time::Duration;
+
+    #[test]
+    fn test_hit() {
+        coarsetime::Instant::update();
+
+        let start = coarsetime::Instant::recent();
+        let mut window = FixedWindow::new(Duration::from_secs(1));
+
+        assert_eq!(window.hit(start), 1);
+        assert_eq!(window.hit(start), 2);
+        assert_eq!(window.hit(start + coarsetime::Duration::from_secs(1)), 1);
+    }
+}
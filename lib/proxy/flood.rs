@@ -0,0 +1,381 @@
+use crate::proxy::Proxy;
+use crate::proxy::events;
+use crate::proxy::shaper::{ByteSize, Rate, TokenBucket};
+use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use clap::ValueEnum;
+use log::info;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket, UdpPacket,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Upper bound on the number of tracked destinations
+const MAX_TRACKED_DESTINATIONS: usize = 4096;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum FloodAction {
+    /// Rate-limit the traffic to the flooded destination to the thresholds
+    #[default]
+    RateLimit,
+    /// Block all traffic to the flooded destination
+    Block,
+    /// Block all of the VM's egress except DHCP and ARP
+    Kill,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FloodThresholds {
+    /// Packets per second to a single destination
+    pub max_pps: Option<u64>,
+    /// Bytes per second to a single destination
+    pub max_rate: Option<Rate>,
+    /// How long the thresholds need to be exceeded for
+    pub duration: Duration,
+}
+
+impl Default for FloodThresholds {
+    fn default() -> Self {
+        FloodThresholds {
+            max_pps: None,
+            max_rate: None,
+            duration: Duration::from_secs(5),
+        }
+    }
+}
+
+impl FloodThresholds {
+    pub fn is_empty(&self) -> bool {
+        self.max_pps.is_none() && self.max_rate.is_none()
+    }
+}
+
+/// Kind of packet, used to characterize the flood
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Syn,
+    Udp,
+    Other,
+}
+
+enum Penalty {
+    RateLimit {
+        packets: Option<TokenBucket>,
+        bytes: Option<TokenBucket>,
+    },
+    Block,
+}
+
+struct Destination {
+    window_start: coarsetime::Instant,
+    packets: u64,
+    bytes: u64,
+    syns: u64,
+    udps: u64,
+    // Number of consecutive windows exceeding the thresholds
+    sustained: u32,
+    penalty: Option<Penalty>,
+}
+
+impl Destination {
+    fn new(now: coarsetime::Instant) -> Destination {
+        Destination {
+            window_start: now,
+            packets: 0,
+            bytes: 0,
+            syns: 0,
+            udps: 0,
+            sustained: 0,
+            penalty: None,
+        }
+    }
+}
+
+pub struct FloodDetector {
+    thresholds: FloodThresholds,
+    action: FloodAction,
+    destinations: HashMap<Ipv4Address, Destination>,
+    killed: bool,
+    detections: u64,
+    dropped: u64,
+}
+
+impl FloodDetector {
+    pub fn new(thresholds: FloodThresholds, action: FloodAction) -> FloodDetector {
+        FloodDetector {
+            thresholds,
+            action,
+            destinations: HashMap::new(),
+            killed: false,
+            detections: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Accounts for a packet sent by the VM, returns false
+    /// if it should be dropped due to a flood response
+    pub fn observe(
+        &mut self,
+        now: coarsetime::Instant,
+        dst_addr: Ipv4Address,
+        len: usize,
+        kind: Kind,
+    ) -> bool {
+        if self.killed {
+            self.dropped += 1;
+
+            return false;
+        }
+
+        if !self.destinations.contains_key(&dst_addr) {
+            if self.destinations.len() >= MAX_TRACKED_DESTINATIONS {
+                self.evict(now);
+            }
+
+            // Still full of floods, nothing more can be done here
+            if self.destinations.len() >= MAX_TRACKED_DESTINATIONS {
+                return true;
+            }
+        }
+
+        let destination = self
+            .destinations
+            .entry(dst_addr)
+            .or_insert_with(|| Destination::new(now));
+
+        let elapsed = now.duration_since(destination.window_start);
+
+        if elapsed >= WINDOW.into() {
+            let exceeded = self
+                .thresholds
+                .max_pps
+                .is_some_and(|max_pps| destination.packets > max_pps)
+                || self
+                    .thresholds
+                    .max_rate
+                    .is_some_and(|max_rate| destination.bytes > max_rate.0);
+
+            // Idle windows in between break the streak
+            let consecutive = elapsed < (WINDOW * 2).into();
+
+            destination.sustained = match (exceeded, consecutive) {
+                (true, true) => destination.sustained + 1,
+                (true, false) => 1,
+                (false, _) => 0,
+            };
+
+            if destination.sustained as u64 == self.thresholds.duration.as_secs().max(1)
+                && destination.penalty.is_none()
+            {
+                let kind = if destination.syns * 2 > destination.packets {
+                    "SYN flood"
+                } else if destination.udps * 2 > destination.packets {
+                    "UDP flood"
+                } else {
+                    "flood"
+                };
+
+                let message = format!(
+                    "VM is sending a {} to {} ({} packets, {} bytes in the last second)",
+                    kind, dst_addr, destination.packets, destination.bytes
+                );
+
+                self.detections += 1;
+
+                let response = match self.action {
+                    FloodAction::RateLimit => {
+                        destination.penalty =
+                            Some(Penalty::RateLimit {
+                                packets: self.thresholds.max_pps.map(|max_pps| {
+                                    TokenBucket::new(Rate(max_pps), ByteSize(max_pps))
+                                }),
+                                bytes: self.thresholds.max_rate.map(|max_rate| {
+                                    TokenBucket::new(max_rate, ByteSize(max_rate.0))
+                                }),
+                            });
+
+                        "rate-limiting the destination"
+                    }
+                    FloodAction::Block => {
+                        destination.penalty = Some(Penalty::Block);
+
+                        "blocking the destination"
+                    }
+                    FloodAction::Kill => {
+                        self.killed = true;
+
+                        "blocking all of its egress"
+                    }
+                };
+
+                events::emit(&format!("{message}, {response}"));
+            }
+
+            destination.window_start = now;
+            destination.packets = 0;
+            destination.bytes = 0;
+            destination.syns = 0;
+            destination.udps = 0;
+        }
+
+        destination.packets += 1;
+        destination.bytes += len as u64;
+
+        match kind {
+            Kind::Syn => destination.syns += 1,
+            Kind::Udp => destination.udps += 1,
+            Kind::Other => {}
+        }
+
+        let admitted = match &mut destination.penalty {
+            None => true,
+            Some(Penalty::Block) => false,
+            Some(Penalty::RateLimit { packets, bytes }) => {
+                packets.as_mut().is_none_or(|packets| packets.take(1))
+                    && bytes.as_mut().is_none_or(|bytes| bytes.take(len))
+            }
+        };
+
+        if !admitted || self.killed {
+            self.dropped += 1;
+
+            return false;
+        }
+
+        true
+    }
+
+    /// Forgets the destinations that the VM didn't send anything to
+    /// recently, the penalized ones are kept to keep the penalties
+    fn evict(&mut self, now: coarsetime::Instant) {
+        self.destinations.retain(|_, destination| {
+            destination.penalty.is_some()
+                || now.duration_since(destination.window_start) < (WINDOW * 2).into()
+        });
+    }
+
+    pub fn log_summary(&self) {
+        if self.detections != 0 {
+            info!(
+                "flood detector: detected {} flood(s), dropped {} packet(s) due to the responses",
+                self.detections, self.dropped
+            );
+        }
+    }
+}
+
+impl Proxy<'_> {
+    /// Watches the VM's egress for floods towards single destinations,
+    /// returns None if the frame should be dropped due to a response
+    pub(crate) fn detect_flood(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
+        let Some(flood_detector) = &mut self.flood_detector else {
+            return Some(());
+        };
+
+        // Let the VM keep its lease either way
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Some(());
+        }
+
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+        let kind = match ipv4_pkt.next_header() {
+            IpProtocol::Tcp => match TcpPacket::new_checked(ipv4_pkt.payload()) {
+                Ok(tcp_pkt) if tcp_pkt.syn() && !tcp_pkt.ack() => Kind::Syn,
+                _ => Kind::Other,
+            },
+            IpProtocol::Udp => match UdpPacket::new_checked(ipv4_pkt.payload()) {
+                Ok(udp_pkt) if udp_pkt.is_dhcp_request() => return Some(()),
+                _ => Kind::Udp,
+            },
+            _ => Kind::Other,
+        };
+
+        flood_detector
+            .observe(
+                coarsetime::Instant::recent(),
+                ipv4_pkt.dst_addr(),
+                frame.as_ref().len(),
+                kind,
+            )
+            .then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FloodAction, FloodDetector, FloodThresholds, Kind};
+    use smoltcp::wire::Ipv4Address;
+    use std::time::Duration;
+
+    const VICTIM: Ipv4Address = Ipv4Address::new(1, 2, 3, 4);
+    const BYSTANDER: Ipv4Address = Ipv4Address::new(5, 6, 7, 8);
+
+    #[test]
+    fn test_sustained_flood_is_blocked() {
+        let mut detector = detector(FloodAction::Block);
+        let start = coarsetime::Instant::now();
+
+        // Flood for 3 seconds, the response kicks in at the beginning of the 4th
+        for second in 0..3 {
+            flood(&mut detector, start, second);
+        }
+        assert!(!detector.observe(at(start, 3000), VICTIM, 60, Kind::Syn));
+        assert_eq!(detector.detections, 1);
+
+        // Other destinations are not affected
+        assert!(detector.observe(at(start, 3000), BYSTANDER, 60, Kind::Syn));
+        assert!(!detector.killed);
+    }
+
+    #[test]
+    fn test_short_burst_is_tolerated() {
+        let mut detector = detector(FloodAction::Kill);
+        let start = coarsetime::Instant::now();
+
+        // Idle second in between breaks the streak
+        flood(&mut detector, start, 0);
+        flood(&mut detector, start, 1);
+        flood(&mut detector, start, 3);
+        assert!(detector.observe(at(start, 4000), VICTIM, 60, Kind::Syn));
+        assert_eq!(detector.detections, 0);
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut detector = detector(FloodAction::Kill);
+        let start = coarsetime::Instant::now();
+
+        for second in 0..3 {
+            flood(&mut detector, start, second);
+        }
+        assert!(!detector.observe(at(start, 3000), VICTIM, 60, Kind::Udp));
+        assert!(!detector.observe(at(start, 3000), BYSTANDER, 60, Kind::Udp));
+        assert!(detector.killed);
+    }
+
+    fn detector(action: FloodAction) -> FloodDetector {
+        FloodDetector::new(
+            FloodThresholds {
+                max_pps: Some(100),
+                max_rate: None,
+                duration: Duration::from_secs(3),
+            },
+            action,
+        )
+    }
+
+    fn flood(detector: &mut FloodDetector, start: coarsetime::Instant, second: u64) {
+        for i in 0..200 {
+            let now = at(start, second * 1000 + i * 4);
+
+            assert!(detector.observe(now, VICTIM, 60, Kind::Syn));
+        }
+    }
+
+    fn at(start: coarsetime::Instant, millis: u64) -> coarsetime::Instant {
+        start + coarsetime::Duration::from_millis(millis)
+    }
+}
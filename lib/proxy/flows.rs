@@ -0,0 +1,503 @@
+use crate::proxy::Proxy;
+use crate::proxy::events;
+use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use anyhow::Result;
+use clap::ValueEnum;
+use log::info;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket, UdpPacket,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const EVENT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Idle timeouts, similar to the ones used by the NAT implementations
+const TCP_OPENING_TIMEOUT: Duration = Duration::from_secs(30);
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const TCP_CLOSING_TIMEOUT: Duration = Duration::from_secs(30);
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum FlowLimitAction {
+    /// Silently drop the over-limit connection attempts
+    #[default]
+    Drop,
+    /// Reset the over-limit TCP connection attempts, UDP is still dropped
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FlowLimits {
+    /// New flows per second
+    pub new_per_second: Option<u32>,
+    /// Concurrent flows
+    pub concurrent: Option<u32>,
+    /// Concurrent flows to a single destination IP
+    pub per_destination: Option<u32>,
+}
+
+impl FlowLimits {
+    pub fn is_empty(&self) -> bool {
+        self.new_per_second.is_none() && self.concurrent.is_none() && self.per_destination.is_none()
+    }
+}
+
+/// Outbound flow initiated by the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: IpProtocol,
+    pub src_port: u16,
+    pub dst_addr: Ipv4Address,
+    pub dst_port: u16,
+}
+
+/// Flow-relevant bits of a packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    /// TCP SYN without ACK, the only way to open a TCP flow
+    Syn,
+    Fin,
+    Rst,
+    /// Any other TCP segment or UDP datagram
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Opening,
+    Established,
+    Closing,
+}
+
+struct Flow {
+    state: State,
+    last_seen: coarsetime::Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Limit {
+    NewPerSecond,
+    Concurrent,
+    PerDestination,
+}
+
+pub struct FlowTable {
+    limits: FlowLimits,
+    action: FlowLimitAction,
+    flows: HashMap<FlowKey, Flow>,
+    per_destination: HashMap<Ipv4Address, u32>,
+    window_start: coarsetime::Instant,
+    window_count: u32,
+    last_sweep: coarsetime::Instant,
+    last_events: HashMap<Limit, coarsetime::Instant>,
+    refused: u64,
+}
+
+impl FlowTable {
+    pub fn new(limits: FlowLimits, action: FlowLimitAction) -> FlowTable {
+        let now = coarsetime::Instant::recent();
+
+        FlowTable {
+            limits,
+            action,
+            flows: HashMap::new(),
+            per_destination: HashMap::new(),
+            window_start: now,
+            window_count: 0,
+            last_sweep: now,
+            last_events: HashMap::new(),
+            refused: 0,
+        }
+    }
+
+    /// Tracks a packet sent by the VM, returns false
+    /// if it opens a new flow that exceeds the limits
+    pub fn egress(&mut self, key: FlowKey, segment: Segment) -> bool {
+        let now = coarsetime::Instant::recent();
+
+        if let Some(flow) = self.flows.get_mut(&key) {
+            flow.last_seen = now;
+
+            match segment {
+                Segment::Rst => self.remove(&key),
+                Segment::Fin => flow.state = State::Closing,
+                Segment::Other if flow.state == State::Opening => flow.state = State::Established,
+                _ => {}
+            }
+
+            return true;
+        }
+
+        // TCP flows we haven't seen opening (e.g. the ones initiated
+        // through the exposed ports) are not subject to the limits
+        if key.protocol == IpProtocol::Tcp && segment != Segment::Syn {
+            return true;
+        }
+
+        if let Some(limit) = self.exceeded(&key) {
+            self.refused += 1;
+            self.report(limit);
+
+            return false;
+        }
+
+        let state = if key.protocol == IpProtocol::Tcp {
+            State::Opening
+        } else {
+            State::Established
+        };
+
+        self.flows.insert(
+            key,
+            Flow {
+                state,
+                last_seen: now,
+            },
+        );
+        *self.per_destination.entry(key.dst_addr).or_default() += 1;
+        self.window_count += 1;
+
+        true
+    }
+
+    /// Tracks a packet sent to the VM, which may close the flow
+    pub fn ingress(&mut self, key: FlowKey, segment: Segment) {
+        match segment {
+            Segment::Rst => self.remove(&key),
+            Segment::Fin => {
+                if let Some(flow) = self.flows.get_mut(&key) {
+                    flow.state = State::Closing;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn action(&self) -> FlowLimitAction {
+        self.action
+    }
+
+    fn exceeded(&mut self, key: &FlowKey) -> Option<Limit> {
+        let now = coarsetime::Instant::recent();
+
+        if now.duration_since(self.window_start) >= RATE_WINDOW.into() {
+            self.window_start = now;
+            self.window_count = 0;
+        }
+
+        if let Some(limit) = self.limits.new_per_second
+            && self.window_count >= limit
+        {
+            return Some(Limit::NewPerSecond);
+        }
+
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL.into() {
+            self.sweep(now);
+        }
+
+        if let Some(limit) = self.limits.concurrent
+            && self.flows.len() >= limit as usize
+        {
+            return Some(Limit::Concurrent);
+        }
+
+        if let Some(limit) = self.limits.per_destination
+            && self
+                .per_destination
+                .get(&key.dst_addr)
+                .is_some_and(|count| *count >= limit)
+        {
+            return Some(Limit::PerDestination);
+        }
+
+        None
+    }
+
+    fn sweep(&mut self, now: coarsetime::Instant) {
+        let expired: Vec<FlowKey> = self
+            .flows
+            .iter()
+            .filter(|(key, flow)| {
+                let timeout = match (key.protocol, flow.state) {
+                    (IpProtocol::Tcp, State::Opening) => TCP_OPENING_TIMEOUT,
+                    (IpProtocol::Tcp, State::Established) => TCP_ESTABLISHED_TIMEOUT,
+                    (IpProtocol::Tcp, State::Closing) => TCP_CLOSING_TIMEOUT,
+                    _ => UDP_TIMEOUT,
+                };
+
+                now.duration_since(flow.last_seen) >= timeout.into()
+            })
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in expired {
+            self.remove(&key);
+        }
+
+        self.last_sweep = now;
+    }
+
+    fn remove(&mut self, key: &FlowKey) {
+        if self.flows.remove(key).is_none() {
+            return;
+        }
+
+        if let Some(count) = self.per_destination.get_mut(&key.dst_addr) {
+            *count -= 1;
+
+            if *count == 0 {
+                self.per_destination.remove(&key.dst_addr);
+            }
+        }
+    }
+
+    fn report(&mut self, limit: Limit) {
+        let now = coarsetime::Instant::recent();
+
+        if let Some(last_event) = self.last_events.get(&limit)
+            && now.duration_since(*last_event) < EVENT_INTERVAL.into()
+        {
+            return;
+        }
+
+        self.last_events.insert(limit, now);
+
+        let message = match limit {
+            Limit::NewPerSecond => format!(
+                "VM exceeded the limit of {} new flows per second",
+                self.limits.new_per_second.unwrap_or_default()
+            ),
+            Limit::Concurrent => format!(
+                "VM exceeded the limit of {} concurrent flows",
+                self.limits.concurrent.unwrap_or_default()
+            ),
+            Limit::PerDestination => format!(
+                "VM exceeded the limit of {} concurrent flows per destination",
+                self.limits.per_destination.unwrap_or_default()
+            ),
+        };
+
+        events::emit(&message);
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            "flow table: {} active flow(s), refused {} new flow(s) over the limits",
+            self.flows.len(),
+            self.refused
+        );
+    }
+}
+
+impl Proxy<'_> {
+    /// Accounts for the flows opened by the VM, returns None
+    /// if the frame should be dropped due to the flow limits
+    pub(crate) fn track_flow(&mut self, frame: &EthernetFrame<&[u8]>) -> Result<Option<()>> {
+        let Some(flows) = &mut self.flows else {
+            return Ok(Some(()));
+        };
+
+        let Some((key, segment)) = parse(frame, true) else {
+            return Ok(Some(()));
+        };
+
+        if flows.egress(key, segment) {
+            return Ok(Some(()));
+        }
+
+        if flows.action() == FlowLimitAction::Reject && segment == Segment::Syn {
+            let reset = reset(frame);
+
+            self.write_to_vm(&reset)?;
+        }
+
+        Ok(None)
+    }
+
+    pub(crate) fn observe_flow(&mut self, frame: &EthernetFrame<&[u8]>) {
+        let Some(flows) = &mut self.flows else {
+            return;
+        };
+
+        if let Some((key, segment)) = parse(frame, false) {
+            flows.ingress(key, segment);
+        }
+    }
+}
+
+/// Extracts the VM-initiated flow key from a frame sent by the VM (egress)
+/// or to the VM (ingress), DHCP is not subject to the flow limits
+fn parse(frame: &EthernetFrame<&[u8]>, egress: bool) -> Option<(FlowKey, Segment)> {
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+
+    let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+    if ipv4_pkt.frag_offset() != 0 {
+        return None;
+    }
+
+    let (protocol, src_port, dst_port, segment) = match ipv4_pkt.next_header() {
+        IpProtocol::Tcp => {
+            let tcp_pkt = TcpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+            let segment = if tcp_pkt.rst() {
+                Segment::Rst
+            } else if tcp_pkt.fin() {
+                Segment::Fin
+            } else if tcp_pkt.syn() && !tcp_pkt.ack() {
+                Segment::Syn
+            } else {
+                Segment::Other
+            };
+
+            (
+                IpProtocol::Tcp,
+                tcp_pkt.src_port(),
+                tcp_pkt.dst_port(),
+                segment,
+            )
+        }
+        IpProtocol::Udp => {
+            let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+            if udp_pkt.is_dhcp_request() || udp_pkt.is_dhcp_response() {
+                return None;
+            }
+
+            (
+                IpProtocol::Udp,
+                udp_pkt.src_port(),
+                udp_pkt.dst_port(),
+                Segment::Other,
+            )
+        }
+        _ => return None,
+    };
+
+    let key = if egress {
+        FlowKey {
+            protocol,
+            src_port,
+            dst_addr: ipv4_pkt.dst_addr(),
+            dst_port,
+        }
+    } else {
+        FlowKey {
+            protocol,
+            src_port: dst_port,
+            dst_addr: ipv4_pkt.src_addr(),
+            dst_port: src_port,
+        }
+    };
+
+    Some((key, segment))
+}
+
+/// Crafts a TCP RST+ACK refusing the SYN sent by the VM,
+/// pretending that it comes from the destination
+fn reset(syn_frame: &EthernetFrame<&[u8]>) -> Vec<u8> {
+    let syn_ipv4_pkt = Ipv4Packet::new_unchecked(syn_frame.payload());
+    let syn_tcp_pkt = TcpPacket::new_unchecked(syn_ipv4_pkt.payload());
+
+    let mut buf = vec![0u8; 14 + 20 + 20];
+
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    frame.set_src_addr(syn_frame.dst_addr());
+    frame.set_dst_addr(syn_frame.src_addr());
+    frame.set_ethertype(EthernetProtocol::Ipv4);
+
+    let mut ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload_mut());
+    ipv4_pkt.set_version(4);
+    ipv4_pkt.set_header_len(20);
+    ipv4_pkt.set_total_len(40);
+    ipv4_pkt.set_hop_limit(64);
+    ipv4_pkt.set_next_header(IpProtocol::Tcp);
+    ipv4_pkt.set_src_addr(syn_ipv4_pkt.dst_addr());
+    ipv4_pkt.set_dst_addr(syn_ipv4_pkt.src_addr());
+    ipv4_pkt.fill_checksum();
+
+    let mut tcp_pkt = TcpPacket::new_unchecked(ipv4_pkt.payload_mut());
+    tcp_pkt.set_src_port(syn_tcp_pkt.dst_port());
+    tcp_pkt.set_dst_port(syn_tcp_pkt.src_port());
+    tcp_pkt.set_header_len(20);
+    tcp_pkt.set_rst(true);
+    tcp_pkt.set_ack(true);
+    // SYN occupies a single sequence number
+    tcp_pkt.set_ack_number(syn_tcp_pkt.seq_number() + 1);
+    tcp_pkt.fill_checksum(
+        &syn_ipv4_pkt.dst_addr().into(),
+        &syn_ipv4_pkt.src_addr().into(),
+    );
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlowKey, FlowLimitAction, FlowLimits, FlowTable, Segment};
+    use smoltcp::wire::{IpProtocol, Ipv4Address};
+
+    #[test]
+    fn test_new_flows_rate() {
+        coarsetime::Instant::update();
+
+        let mut flows = FlowTable::new(
+            FlowLimits {
+                new_per_second: Some(2),
+                ..Default::default()
+            },
+            FlowLimitAction::Drop,
+        );
+
+        assert!(flows.egress(tcp_key(1000, 1), Segment::Syn));
+        assert!(flows.egress(tcp_key(1001, 1), Segment::Syn));
+        assert!(!flows.egress(tcp_key(1002, 1), Segment::Syn));
+
+        // Existing flows are not affected
+        assert!(flows.egress(tcp_key(1000, 1), Segment::Other));
+        assert!(flows.egress(tcp_key(1001, 1), Segment::Syn));
+        assert_eq!(flows.refused, 1);
+    }
+
+    #[test]
+    fn test_concurrent_flows() {
+        coarsetime::Instant::update();
+
+        let mut flows = FlowTable::new(
+            FlowLimits {
+                concurrent: Some(3),
+                per_destination: Some(2),
+                ..Default::default()
+            },
+            FlowLimitAction::Drop,
+        );
+
+        assert!(flows.egress(tcp_key(1000, 1), Segment::Syn));
+        assert!(flows.egress(tcp_key(1001, 1), Segment::Syn));
+        assert!(!flows.egress(tcp_key(1002, 1), Segment::Syn));
+        assert!(flows.egress(tcp_key(1002, 2), Segment::Syn));
+        assert!(!flows.egress(tcp_key(1003, 3), Segment::Syn));
+
+        // Resetting a flow frees up the slot
+        flows.ingress(tcp_key(1000, 1), Segment::Rst);
+        assert!(flows.egress(tcp_key(1003, 1), Segment::Syn));
+
+        // Mid-stream TCP segments don't open new flows
+        assert!(flows.egress(tcp_key(1004, 4), Segment::Other));
+        assert_eq!(flows.flows.len(), 3);
+    }
+
+    fn tcp_key(src_port: u16, dst: u8) -> FlowKey {
+        FlowKey {
+            protocol: IpProtocol::Tcp,
+            src_port,
+            dst_addr: Ipv4Address::new(1, 1, 1, dst),
+            dst_port: 443,
+        }
+    }
+}
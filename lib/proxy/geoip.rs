@@ -0,0 +1,280 @@
+use crate::proxy::{Action, Target};
+use anyhow::{Context, Result, anyhow};
+use maxminddb::{Reader, geoip2};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+
+// Upper bound on the number of cached destinations
+const CACHE_CAPACITY: usize = 4096;
+
+pub struct GeoIp {
+    country_db: Option<Reader<Vec<u8>>>,
+    asn_db: Option<Reader<Vec<u8>>>,
+    countries: HashMap<String, Action>,
+    asns: HashMap<u32, Action>,
+    cache: RefCell<HashMap<Ipv4Addr, Option<(u8, Action)>>>,
+}
+
+impl GeoIp {
+    pub fn new(
+        allow: &[Target],
+        block: &[Target],
+        country_db_path: Option<PathBuf>,
+        asn_db_path: Option<PathBuf>,
+    ) -> Result<GeoIp> {
+        let mut countries = HashMap::new();
+        let mut asns = HashMap::new();
+
+        // SECURITY: blocking rules must always take precedence
+        // over allowing rules when targets are identical.
+        let targets = allow
+            .iter()
+            .map(|target| (target, Action::Allow))
+            .chain(block.iter().map(|target| (target, Action::Block)));
+
+        for (target, action) in targets {
+            match target {
+                Target::Country(code) => {
+                    countries.insert(code.clone(), action);
+                }
+                Target::Asn(asn) => {
+                    asns.insert(*asn, action);
+                }
+                _ => {}
+            }
+        }
+
+        if !countries.is_empty() && country_db_path.is_none() {
+            return Err(anyhow!(
+                "geo: targets require a country database to be specified with --geoip-database"
+            ));
+        }
+
+        if !asns.is_empty() && asn_db_path.is_none() {
+            return Err(anyhow!(
+                "asn: targets require an ASN database to be specified with --asn-database"
+            ));
+        }
+
+        Ok(GeoIp {
+            country_db: country_db_path.map(open).transpose()?,
+            asn_db: asn_db_path.map(open).transpose()?,
+            countries,
+            asns,
+            cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty() && self.asns.is_empty()
+    }
+
+    /// Returns the action for the destination along with the prefix length
+    /// of the database network it was resolved from, so that it can be
+    /// weighed against the CIDR rules using the longest prefix match.
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<(u8, Action)> {
+        if self.is_empty() {
+            return None;
+        }
+
+        if let Some(result) = self.cache.borrow().get(&addr) {
+            return *result;
+        }
+
+        let result = most_specific(self.lookup_country(addr), self.lookup_asn(addr));
+
+        let mut cache = self.cache.borrow_mut();
+
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+
+        cache.insert(addr, result);
+
+        result
+    }
+
+    fn lookup_country(&self, addr: Ipv4Addr) -> Option<(u8, Action)> {
+        let db = self.country_db.as_ref()?;
+
+        let (country, prefix_len) = db.lookup_prefix::<geoip2::Country>(IpAddr::V4(addr)).ok()?;
+        let code = country.country?.iso_code?;
+
+        self.countries
+            .get(code)
+            .map(|action| (prefix_len as u8, *action))
+    }
+
+    fn lookup_asn(&self, addr: Ipv4Addr) -> Option<(u8, Action)> {
+        let db = self.asn_db.as_ref()?;
+
+        let (asn, prefix_len) = db.lookup_prefix::<geoip2::Asn>(IpAddr::V4(addr)).ok()?;
+        let number = asn.autonomous_system_number?;
+
+        self.asns
+            .get(&number)
+            .map(|action| (prefix_len as u8, *action))
+    }
+}
+
+fn open(path: PathBuf) -> Result<Reader<Vec<u8>>> {
+    Reader::open_readfile(&path)
+        .with_context(|| format!("failed to open MaxMind database {}", path.display()))
+}
+
+/// Picks the action with the longest prefix, blocking
+/// takes precedence when the prefixes are identical.
+pub(crate) fn most_specific(
+    a: Option<(u8, Action)>,
+    b: Option<(u8, Action)>,
+) -> Option<(u8, Action)> {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            if a.0 > b.0 {
+                Some(a)
+            } else if b.0 > a.0 {
+                Some(b)
+            } else if a.1 == Action::Block {
+                Some(a)
+            } else {
+                Some(b)
+            }
+        }
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GeoIp, most_specific};
+    use crate::proxy::{Action, Target};
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+
+    // 1.1.0.0/16 is AU and AS13335, 8.8.8.0/24 is US and AS15169
+    fn fixture_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("softnet-{name}-{}.mmdb", std::process::id()));
+        std::fs::write(&path, include_bytes!("../fixtures/geoip.mmdb")).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        assert_eq!(
+            most_specific(Some((16, Action::Block)), Some((24, Action::Allow))),
+            Some((24, Action::Allow))
+        );
+        assert_eq!(
+            most_specific(Some((24, Action::Allow)), Some((16, Action::Block))),
+            Some((24, Action::Allow))
+        );
+        assert_eq!(
+            most_specific(None, Some((16, Action::Block))),
+            Some((16, Action::Block))
+        );
+        assert_eq!(most_specific(None, None), None);
+    }
+
+    #[test]
+    fn test_blocking_takes_precedence() {
+        assert_eq!(
+            most_specific(Some((24, Action::Allow)), Some((24, Action::Block))),
+            Some((24, Action::Block))
+        );
+        assert_eq!(
+            most_specific(Some((24, Action::Block)), Some((24, Action::Allow))),
+            Some((24, Action::Block))
+        );
+    }
+
+    #[test]
+    fn test_target_parsing() {
+        assert_eq!("asn:15169".parse::<Target>().unwrap(), Target::Asn(15169));
+        assert_eq!(
+            "geo:us".parse::<Target>().unwrap(),
+            Target::Country("US".to_string())
+        );
+        assert!("asn:google".parse::<Target>().is_err());
+        assert!("geo:USA".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let path = fixture_path("geoip-lookup");
+        let geoip = GeoIp::new(
+            &[Target::Asn(15169)],
+            &[Target::Country("AU".to_string())],
+            Some(path.clone()),
+            Some(path.clone()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            geoip.lookup(Ipv4Addr::new(8, 8, 8, 8)),
+            Some((24, Action::Allow))
+        );
+        assert_eq!(
+            geoip.lookup(Ipv4Addr::new(1, 1, 1, 1)),
+            Some((16, Action::Block))
+        );
+        assert_eq!(geoip.lookup(Ipv4Addr::new(9, 9, 9, 9)), None);
+
+        // Cached results stay the same
+        assert_eq!(
+            geoip.lookup(Ipv4Addr::new(1, 1, 1, 1)),
+            Some((16, Action::Block))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lookup_blocking_takes_precedence() {
+        let path = fixture_path("geoip-precedence");
+        let geoip = GeoIp::new(
+            &[Target::Country("US".to_string())],
+            &[Target::Asn(15169)],
+            Some(path.clone()),
+            Some(path.clone()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            geoip.lookup(Ipv4Addr::new(8, 8, 8, 8)),
+            Some((24, Action::Block))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_more_specific_cidr_rule_overrides() {
+        let path = fixture_path("geoip-cidr");
+        let geoip = GeoIp::new(
+            &[],
+            &[Target::Country("AU".to_string())],
+            Some(path.clone()),
+            None,
+        )
+        .unwrap();
+
+        // A CIDR rule for 1.1.1.0/24 is more specific than the /16 country network
+        let resolved = geoip.lookup(Ipv4Addr::new(1, 1, 1, 1));
+        assert_eq!(
+            most_specific(Some((24, Action::Allow)), resolved),
+            Some((24, Action::Allow))
+        );
+
+        // While a broader one doesn't override it
+        assert_eq!(
+            most_specific(Some((8, Action::Allow)), resolved),
+            Some((16, Action::Block))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,5 +1,7 @@
 use crate::proxy::Proxy;
+use crate::proxy::shaper::Verdict;
 use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use crate::vm::VM;
 use anyhow::{Context, Result};
 use smoltcp::wire::{EthernetFrame, EthernetProtocol, Ipv4Packet, UdpPacket};
 
@@ -10,30 +12,71 @@ impl Proxy<'_> {
             return Ok(());
         }
 
+        // Notice the flows being closed by the remote side
+        self.observe_flow(frame);
+
+        // Rewrite the selected options in bootpd(8) replies before snooping
+        // on them, so that the rewritten values are the authoritative ones
+        let rewritten = self.rewrite_dhcp_reply(frame);
+        let frame = &match &rewritten {
+            Some(rewritten) => EthernetFrame::new_unchecked(rewritten.as_slice()),
+            None => EthernetFrame::new_unchecked(frame.as_ref()),
+        };
+
         // Snoop bootpd(8) replies from the host to
         // figure out the IP assigned to the VM
         if frame.dst_addr() == self.vm_mac_address {
             self.snoop(frame);
         }
 
-        match self.vm.write(frame.as_ref()) {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                if let Some(libc::ENOBUFS) = err.raw_os_error() {
-                    if !self.enobufs_encountered {
-                        sentry::capture_message(
-                            "No buffer space available in VM's socket",
-                            sentry::Level::Warning,
-                        );
-                        self.enobufs_encountered = true;
-                    }
-
-                    return Ok(());
-                }
+        if self.account_quotas(frame, false).is_none() {
+            return Ok(());
+        }
+
+        if let Some(impairer) = &mut self.ingress_impairer {
+            impairer.submit(frame.as_ref());
+
+            return Ok(());
+        }
+
+        self.forward_to_vm(frame.as_ref())
+    }
+
+    /// Writes the frame to the VM, unless the ingress shaper holds it back
+    pub(crate) fn forward_to_vm(&mut self, frame: &[u8]) -> Result<()> {
+        if let Some(shaper) = &mut self.ingress_shaper
+            && shaper.submit(frame) != Verdict::Pass
+        {
+            return Ok(());
+        }
+
+        self.write_to_vm(frame)
+    }
+
+    pub(crate) fn write_to_vm(&mut self, frame: &[u8]) -> Result<()> {
+        // Keep the frames in the backlog while the VM's socket is
+        // congested, otherwise they'll overtake the backlogged ones
+        if !self.vm_backlog.is_empty()
+            || !try_write_to_vm(&self.vm, &mut self.enobufs_encountered, frame)?
+        {
+            self.vm_backlog.push(frame);
+        }
+
+        Ok(())
+    }
 
-                Err(err).context("failed to write to the VM")
+    /// Writes the backlogged frames to the VM in the order of
+    /// their priority until the VM's socket gets congested again
+    pub(crate) fn drain_vm_backlog(&mut self) -> Result<()> {
+        while let Some(frame) = self.vm_backlog.front() {
+            if !try_write_to_vm(&self.vm, &mut self.enobufs_encountered, frame)? {
+                break;
             }
+
+            self.vm_backlog.pop();
         }
+
+        Ok(())
     }
 
     fn allowed_from_host(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
@@ -58,6 +101,9 @@ impl Proxy<'_> {
             return;
         }
 
+        // Remember the host's MAC address to send the built-in DHCP server's replies from
+        self.gateway_mac_address = Some(frame.src_addr());
+
         if ipv4_pkt.next_header() != smoltcp::wire::IpProtocol::Udp {
             return;
         }
@@ -71,6 +117,39 @@ impl Proxy<'_> {
             return;
         }
 
-        self.dhcp_snooper.register_dhcp_reply(udp_pkt.payload());
+        self.learn_lease(udp_pkt.payload());
+    }
+
+    pub(crate) fn learn_lease(&mut self, dhcp_packet: &[u8]) {
+        self.dhcp_snooper.register_dhcp_reply(dhcp_packet);
+
+        // Attach the VM's current IP to the events reported to Sentry
+        if let Some(lease) = self.dhcp_snooper.lease() {
+            sentry::configure_scope(|scope| {
+                scope.set_tag("vm_ip", lease.address());
+            });
+        }
+    }
+}
+
+/// Returns false if there's no buffer space available in VM's socket
+fn try_write_to_vm(vm: &VM, enobufs_encountered: &mut bool, frame: &[u8]) -> Result<bool> {
+    match vm.write(frame) {
+        Ok(_) => Ok(true),
+        Err(err) => {
+            if let Some(libc::ENOBUFS) = err.raw_os_error() {
+                if !*enobufs_encountered {
+                    sentry::capture_message(
+                        "No buffer space available in VM's socket",
+                        sentry::Level::Warning,
+                    );
+                    *enobufs_encountered = true;
+                }
+
+                return Ok(false);
+            }
+
+            Err(err).context("failed to write to the VM")
+        }
     }
 }
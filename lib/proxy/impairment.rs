@@ -0,0 +1,341 @@
+use anyhow::{Context, Error, anyhow};
+use log::info;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Maximum number of frames held back by an impairer before dropping
+const QUEUE_CAPACITY: usize = 1024;
+
+/// netem-like impairments for a single direction, parsed from
+/// comma-separated KEY=VALUE pairs (e.g. delay=100ms,jitter=10ms,loss=1%)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Impairment {
+    pub delay: Duration,
+    pub jitter: Duration,
+    pub loss: f64,
+    pub reorder: f64,
+    pub duplicate: f64,
+    pub corrupt: f64,
+}
+
+impl FromStr for Impairment {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut impairment = Impairment::default();
+
+        for pair in s.split(',') {
+            let Some((key, value)) = pair.split_once('=') else {
+                return Err(anyhow!(
+                    "invalid impairment {:?}, the format should be KEY=VALUE",
+                    pair
+                ));
+            };
+
+            match key {
+                "delay" => impairment.delay = parse_duration(value)?,
+                "jitter" => impairment.jitter = parse_duration(value)?,
+                "loss" => impairment.loss = parse_percentage(value)?,
+                "reorder" => impairment.reorder = parse_percentage(value)?,
+                "duplicate" => impairment.duplicate = parse_percentage(value)?,
+                "corrupt" => impairment.corrupt = parse_percentage(value)?,
+                _ => {
+                    return Err(anyhow!(
+                        "unknown impairment {:?}, expected delay, jitter, loss, \
+                        reorder, duplicate or corrupt",
+                        key
+                    ));
+                }
+            }
+        }
+
+        Ok(impairment)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let (number, unit): (&str, fn(u64) -> Duration) = if let Some(number) = s.strip_suffix("us") {
+        (number, Duration::from_micros)
+    } else if let Some(number) = s.strip_suffix("ms") {
+        (number, Duration::from_millis)
+    } else if let Some(number) = s.strip_suffix('s') {
+        (number, Duration::from_secs)
+    } else {
+        return Err(anyhow!(
+            "invalid duration {:?}, expected a number with an us, ms or s suffix",
+            s
+        ));
+    };
+
+    number
+        .parse()
+        .map(unit)
+        .context(format!("invalid duration {:?}", s))
+}
+
+fn parse_percentage(s: &str) -> Result<f64, Error> {
+    let percentage: f64 = s
+        .strip_suffix('%')
+        .and_then(|number| number.parse().ok())
+        .ok_or_else(|| {
+            anyhow!(
+                "invalid percentage {:?}, expected a number with a % suffix",
+                s
+            )
+        })?;
+
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err(anyhow!("percentage {:?} is out of the 0-100% range", s));
+    }
+
+    Ok(percentage / 100.0)
+}
+
+/// xorshift64* generator, not cryptographically secure,
+/// but cheap and reproducible given the same seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Scramble the seed with splitmix64 so that
+        // similar seeds yield unrelated sequences
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+
+        // xorshift gets stuck on zero state
+        Rng(z.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Returns a number uniformly distributed in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    lost: u64,
+    reordered: u64,
+    duplicated: u64,
+    corrupted: u64,
+    overflowed: u64,
+}
+
+pub struct Impairer {
+    direction: &'static str,
+    impairment: Impairment,
+    rng: Rng,
+    // Frames ordered by their release time, the sequence
+    // number keeps the frames released at the same time in order
+    queue: BinaryHeap<Reverse<(coarsetime::Instant, u64, Vec<u8>)>>,
+    seq: u64,
+    stats: Stats,
+}
+
+impl Impairer {
+    pub fn new(direction: &'static str, impairment: Impairment, seed: u64) -> Impairer {
+        Impairer {
+            direction,
+            impairment,
+            rng: Rng::new(seed),
+            queue: BinaryHeap::new(),
+            seq: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Applies the impairments to the frame and queues the
+    /// outcome (if any) to be released by the dequeue()
+    pub fn submit(&mut self, frame: &[u8]) {
+        if self.rng.chance(self.impairment.loss) {
+            self.stats.lost += 1;
+
+            return;
+        }
+
+        let copies = if self.rng.chance(self.impairment.duplicate) {
+            self.stats.duplicated += 1;
+
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut frame = frame.to_vec();
+
+            // Flip a random bit past the Ethernet header, so that the
+            // frame still reaches its destination and the corruption
+            // is left for the checksums to catch
+            if frame.len() > 14 && self.rng.chance(self.impairment.corrupt) {
+                let bit = self.rng.next_u64() as usize % ((frame.len() - 14) * 8);
+                frame[14 + bit / 8] ^= 1 << (bit % 8);
+
+                self.stats.corrupted += 1;
+            }
+
+            let delay = self.delay();
+
+            self.enqueue(frame, delay);
+        }
+    }
+
+    fn delay(&mut self) -> Duration {
+        // Reordered frames are sent right away, overtaking the delayed ones
+        if self.rng.chance(self.impairment.reorder) {
+            self.stats.reordered += 1;
+
+            return Duration::ZERO;
+        }
+
+        let jitter = self.impairment.jitter.as_secs_f64();
+
+        if jitter == 0.0 {
+            return self.impairment.delay;
+        }
+
+        let offset = (self.rng.next_f64() * 2.0 - 1.0) * jitter;
+
+        Duration::from_secs_f64((self.impairment.delay.as_secs_f64() + offset).max(0.0))
+    }
+
+    fn enqueue(&mut self, frame: Vec<u8>, delay: Duration) {
+        if self.queue.len() >= QUEUE_CAPACITY {
+            self.stats.overflowed += 1;
+
+            return;
+        }
+
+        let release_at = coarsetime::Instant::recent() + delay.into();
+
+        self.queue.push(Reverse((release_at, self.seq, frame)));
+        self.seq += 1;
+    }
+
+    /// Returns the next queued frame if it's due
+    pub fn dequeue(&mut self) -> Option<Vec<u8>> {
+        let Reverse((release_at, _, _)) = self.queue.peek()?;
+
+        if *release_at > coarsetime::Instant::recent() {
+            return None;
+        }
+
+        self.queue.pop().map(|Reverse((_, _, frame))| frame)
+    }
+
+    /// Returns when the next queued frame is due, if any
+    pub fn next_wakeup(&self) -> Option<Duration> {
+        self.queue.peek().map(|Reverse((release_at, _, _))| {
+            let now = coarsetime::Instant::recent();
+
+            if *release_at > now {
+                release_at.duration_since(now).into()
+            } else {
+                Duration::ZERO
+            }
+        })
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            "{} impairment: lost {} packet(s), reordered {} packet(s), duplicated {} packet(s), \
+            corrupted {} packet(s), dropped {} packet(s) due to a full queue",
+            self.direction,
+            self.stats.lost,
+            self.stats.reordered,
+            self.stats.duplicated,
+            self.stats.corrupted,
+            self.stats.overflowed
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Impairer, Impairment};
+    use std::time::Duration;
+
+    #[test]
+    fn test_parsing() {
+        assert_eq!(
+            "delay=100ms,jitter=500us,loss=1%,reorder=25%,duplicate=0.5%,corrupt=100%"
+                .parse::<Impairment>()
+                .unwrap(),
+            Impairment {
+                delay: Duration::from_millis(100),
+                jitter: Duration::from_micros(500),
+                loss: 0.01,
+                reorder: 0.25,
+                duplicate: 0.005,
+                corrupt: 1.0,
+            }
+        );
+        assert!("delay=100".parse::<Impairment>().is_err());
+        assert!("loss=1".parse::<Impairment>().is_err());
+        assert!("loss=101%".parse::<Impairment>().is_err());
+        assert!("bandwidth=1M".parse::<Impairment>().is_err());
+    }
+
+    #[test]
+    fn test_reproducible() {
+        coarsetime::Instant::update();
+
+        let impairment: Impairment = "loss=30%,duplicate=30%,corrupt=30%".parse().unwrap();
+
+        let run = |seed| {
+            let mut impairer = Impairer::new("egress", impairment, seed);
+
+            for i in 0..100u8 {
+                impairer.submit(&[i; 64]);
+            }
+
+            std::iter::from_fn(|| impairer.dequeue()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn test_delay() {
+        coarsetime::Instant::update();
+
+        let mut impairer = Impairer::new("ingress", "delay=1s".parse().unwrap(), 42);
+
+        impairer.submit(&[1; 64]);
+        impairer.submit(&[2; 64]);
+
+        assert!(impairer.dequeue().is_none());
+        assert_eq!(impairer.next_wakeup(), Some(Duration::from_secs(1)));
+
+        // Fast-forward the queued frames, they should come out in order
+        impairer.queue = impairer
+            .queue
+            .drain()
+            .map(|mut pending| {
+                pending.0.0 = coarsetime::Instant::recent();
+                pending
+            })
+            .collect();
+
+        assert_eq!(impairer.dequeue(), Some(vec![1; 64]));
+        assert_eq!(impairer.dequeue(), Some(vec![2; 64]));
+        assert!(impairer.dequeue().is_none());
+    }
+}
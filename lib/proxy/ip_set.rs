@@ -0,0 +1,265 @@
+use anyhow::{Context, Error, Result, anyhow};
+use ipnet::{IpNet, Ipv4Net};
+use log::{error, info};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpSetSpec {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for IpSetSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(IpSetSpec {
+                name: name.to_string(),
+                path: PathBuf::from(path),
+            }),
+            _ => Err(anyhow!(
+                "invalid IP set specification {:?}, the format should be NAME=PATH",
+                s
+            )),
+        }
+    }
+}
+
+pub struct IpSets {
+    sets: HashMap<String, IpSet>,
+    next_refresh: coarsetime::Instant,
+}
+
+struct IpSet {
+    path: PathBuf,
+    prefixes: Vec<Ipv4Net>,
+    modified: Option<SystemTime>,
+}
+
+impl IpSets {
+    pub fn load(specs: Vec<IpSetSpec>) -> Result<IpSets> {
+        let mut sets = HashMap::new();
+
+        for spec in specs {
+            let modified = modified(&spec.path);
+            let prefixes = load(&spec.path)
+                .with_context(|| format!("failed to load IP set {:?}", spec.name))?;
+
+            sets.insert(
+                spec.name,
+                IpSet {
+                    path: spec.path,
+                    prefixes,
+                    modified,
+                },
+            );
+        }
+
+        Ok(IpSets {
+            sets,
+            next_refresh: coarsetime::Instant::recent() + REFRESH_INTERVAL.into(),
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.sets.contains_key(name)
+    }
+
+    pub fn prefixes(&self, name: &str) -> &[Ipv4Net] {
+        self.sets
+            .get(name)
+            .map(|set| set.prefixes.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Re-reads the IP sets whose files have changed since the last refresh
+    /// and returns true if any of the sets were updated. A set that fails
+    /// to load keeps its last known good prefixes.
+    pub fn refresh(&mut self) -> bool {
+        let now = coarsetime::Instant::recent();
+
+        if now < self.next_refresh {
+            return false;
+        }
+
+        self.next_refresh = now + REFRESH_INTERVAL.into();
+
+        let mut updated = false;
+
+        for (name, set) in &mut self.sets {
+            let modified = modified(&set.path);
+
+            if modified == set.modified {
+                continue;
+            }
+
+            // Remember the modification time even if the load fails
+            // to avoid re-reading (and complaining about) the same file
+            set.modified = modified;
+
+            match load(&set.path) {
+                Ok(prefixes) => {
+                    info!(
+                        "reloaded IP set {:?} from {}: {} prefix(es)",
+                        name,
+                        set.path.display(),
+                        prefixes.len()
+                    );
+
+                    set.prefixes = prefixes;
+                    updated = true;
+                }
+                Err(err) => {
+                    error!(
+                        "failed to reload IP set {:?}, keeping the last good one: {:#}",
+                        name, err
+                    );
+                }
+            }
+        }
+
+        updated
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn load(path: &Path) -> Result<Vec<Ipv4Net>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    parse(&contents)
+}
+
+/// Parses either a JSON array of CIDR strings or a plain list
+/// with one CIDR per line, where empty lines and lines starting
+/// with "#" are ignored. IPv6 entries are skipped.
+fn parse(contents: &str) -> Result<Vec<Ipv4Net>> {
+    let entries: Vec<String> = if contents.trim_start().starts_with('[') {
+        serde_json::from_str(contents).context("failed to parse JSON array of CIDRs")?
+    } else {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    };
+
+    let mut prefixes = Vec::new();
+
+    for entry in entries {
+        match parse_entry(&entry)? {
+            IpNet::V4(prefix) => prefixes.push(prefix.trunc()),
+            IpNet::V6(_) => continue,
+        }
+    }
+
+    if prefixes.is_empty() {
+        return Err(anyhow!("no IPv4 prefixes found"));
+    }
+
+    Ok(prefixes)
+}
+
+fn parse_entry(entry: &str) -> Result<IpNet> {
+    if let Ok(prefix) = IpNet::from_str(entry) {
+        return Ok(prefix);
+    }
+
+    match IpAddr::from_str(entry) {
+        Ok(IpAddr::V4(addr)) => Ok(IpNet::V4(Ipv4Net::from(addr))),
+        Ok(IpAddr::V6(addr)) => Ok(IpNet::V6(addr.into())),
+        Err(_) => Err(anyhow!("invalid CIDR {:?}", entry)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpSetSpec, IpSets, parse};
+    use ipnet::Ipv4Net;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_plain() {
+        let prefixes =
+            parse("# GitHub Actions\n\n4.148.0.0/16\n 13.64.1.1 \n2001:db8::/32\n").unwrap();
+
+        assert_eq!(
+            prefixes,
+            vec![
+                Ipv4Net::from_str("4.148.0.0/16").unwrap(),
+                Ipv4Net::from_str("13.64.1.1/32").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let prefixes = parse(r#"["4.148.0.0/16", "13.64.1.0/24"]"#).unwrap();
+
+        assert_eq!(
+            prefixes,
+            vec![
+                Ipv4Net::from_str("4.148.0.0/16").unwrap(),
+                Ipv4Net::from_str("13.64.1.0/24").unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse("4.148.0.0/16\nnot-a-cidr\n").is_err());
+        assert!(parse(r#"["4.148.0.0/16""#).is_err());
+        assert!(parse("# nothing here\n").is_err());
+    }
+
+    #[test]
+    fn test_broken_update_keeps_last_good_set() {
+        let path = std::env::temp_dir().join(format!("softnet-ip-set-{}", std::process::id()));
+        std::fs::write(&path, "10.0.0.0/8\n").unwrap();
+
+        let mut ip_sets = IpSets::load(vec![IpSetSpec {
+            name: "mirrors".to_string(),
+            path: path.clone(),
+        }])
+        .unwrap();
+
+        std::fs::write(&path, "10.0.0.0/8\ngarbage\n").unwrap();
+        force_refresh(&mut ip_sets);
+        assert!(!ip_sets.refresh());
+        assert_eq!(
+            ip_sets.prefixes("mirrors"),
+            &[Ipv4Net::from_str("10.0.0.0/8").unwrap()]
+        );
+
+        std::fs::write(&path, "192.168.0.0/16\n").unwrap();
+        force_refresh(&mut ip_sets);
+        assert!(ip_sets.refresh());
+        assert_eq!(
+            ip_sets.prefixes("mirrors"),
+            &[Ipv4Net::from_str("192.168.0.0/16").unwrap()]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn force_refresh(ip_sets: &mut IpSets) {
+        ip_sets.next_refresh = coarsetime::Instant::recent();
+
+        for set in ip_sets.sets.values_mut() {
+            set.modified = None;
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use anyhow::{Context, Result, anyhow};
+use mac_address::MacAddress;
+use serde_json::json;
+use smoltcp::wire::EthernetAddress;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::Ipv4Addr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Lease file shared by all of the softnet instances running the built-in
+/// DHCP server, so that they never hand out the same address twice
+pub struct LeaseFile {
+    path: PathBuf,
+    // Opened before dropping the privileges, since
+    // the lease file usually resides in /var/db
+    file: File,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    // None for the addresses declined by the clients
+    hw_address: Option<EthernetAddress>,
+    ip_address: Ipv4Addr,
+    expires_at: u64,
+}
+
+/// Exclusive flock(2) on the lease file, released when dropped
+struct Lock<'file> {
+    path: &'file Path,
+    file: &'file mut File,
+}
+
+impl<'file> Lock<'file> {
+    fn acquire(path: &'file Path, file: &'file mut File) -> Result<Lock<'file>> {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to lock the lease file");
+        }
+
+        Ok(Lock { path, file })
+    }
+
+    /// Refuses to proceed with a corrupted lease file, since treating
+    /// it as empty would hand out the addresses that are in use
+    fn load(&mut self) -> Result<Vec<Entry>> {
+        let mut contents = Vec::new();
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file
+            .read_to_end(&mut contents)
+            .context("failed to read the lease file")?;
+
+        if contents.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        unwrap_body(&contents)
+            .and_then(parse)
+            .with_context(|| format!("failed to parse the lease file {}", self.path.display()))
+    }
+
+    /// Overwrites the lease file in place, prefixing the entries with their
+    /// length and checksum, so that a torn write is detected when loading
+    fn store(&mut self, entries: &[Entry]) -> Result<()> {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "hw_address": entry
+                        .hw_address
+                        .map(|hw_address| MacAddress::new(hw_address.0).to_string()),
+                    "ip_address": entry.ip_address.to_string(),
+                    "expires_at": entry.expires_at,
+                })
+            })
+            .collect();
+
+        let body = serde_json::Value::from(entries).to_string();
+        let contents = format!("{} {:016x}\n{}", body.len(), fnv1a(body.as_bytes()), body);
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(contents.as_bytes())?;
+        self.file.set_len(contents.len() as u64)?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Lock<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+impl LeaseFile {
+    pub fn open(path: &Path) -> Result<LeaseFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o644)
+            .open(path)
+            .with_context(|| format!("failed to open the lease file {}", path.display()))?;
+
+        let mut lease_file = LeaseFile {
+            path: path.to_path_buf(),
+            file,
+        };
+
+        // Fail early rather than on the VM's first DHCP request
+        Lock::acquire(&lease_file.path, &mut lease_file.file)?.load()?;
+
+        Ok(lease_file)
+    }
+
+    /// Picks an address for the client: the one it already holds, the one
+    /// it asked for if free, or the first free one in the pool, and holds
+    /// it for the client for the specified duration
+    pub fn offer(
+        &mut self,
+        hw_address: EthernetAddress,
+        requested: Option<Ipv4Addr>,
+        pool: &[Ipv4Addr],
+        hold: Duration,
+    ) -> Result<Option<Ipv4Addr>> {
+        let now = unix_now()?;
+        let mut lock = Lock::acquire(&self.path, &mut self.file)?;
+        let mut entries = lock.load()?;
+        entries.retain(|entry| entry.expires_at > now);
+
+        let free = |entries: &[Entry], address: &Ipv4Addr| {
+            !entries.iter().any(|entry| entry.ip_address == *address)
+        };
+
+        let address = match entries
+            .iter()
+            .find(|entry| entry.hw_address == Some(hw_address))
+        {
+            Some(entry) => entry.ip_address,
+            None => match requested
+                .filter(|requested| pool.contains(requested) && free(&entries, requested))
+                .or_else(|| pool.iter().find(|address| free(&entries, address)).copied())
+            {
+                Some(address) => address,
+                None => return Ok(None),
+            },
+        };
+
+        hold_for(
+            &mut entries,
+            hw_address,
+            address,
+            now + hold.as_secs(),
+            false,
+        );
+        lock.store(&entries)?;
+
+        Ok(Some(address))
+    }
+
+    /// Commits the address to the client for the lease time,
+    /// returns false if it belongs to another client
+    pub fn commit(
+        &mut self,
+        hw_address: EthernetAddress,
+        address: Ipv4Addr,
+        lease_time: Duration,
+    ) -> Result<bool> {
+        let now = unix_now()?;
+        let mut lock = Lock::acquire(&self.path, &mut self.file)?;
+        let mut entries = lock.load()?;
+        entries.retain(|entry| entry.expires_at > now);
+
+        if entries
+            .iter()
+            .any(|entry| entry.ip_address == address && entry.hw_address != Some(hw_address))
+        {
+            return Ok(false);
+        }
+
+        hold_for(
+            &mut entries,
+            hw_address,
+            address,
+            now + lease_time.as_secs(),
+            true,
+        );
+        lock.store(&entries)?;
+
+        Ok(true)
+    }
+
+    /// Frees the address released by the client
+    pub fn release(&mut self, hw_address: EthernetAddress, address: Ipv4Addr) -> Result<()> {
+        let mut lock = Lock::acquire(&self.path, &mut self.file)?;
+        let mut entries = lock.load()?;
+
+        entries
+            .retain(|entry| !(entry.ip_address == address && entry.hw_address == Some(hw_address)));
+        lock.store(&entries)?;
+
+        Ok(())
+    }
+
+    /// Takes the address declined by the client out of the pool
+    /// for the specified duration, since someone else is using it
+    pub fn decline(
+        &mut self,
+        hw_address: EthernetAddress,
+        address: Ipv4Addr,
+        quarantine: Duration,
+    ) -> Result<()> {
+        let now = unix_now()?;
+        let mut lock = Lock::acquire(&self.path, &mut self.file)?;
+        let mut entries = lock.load()?;
+
+        entries
+            .retain(|entry| !(entry.ip_address == address && entry.hw_address == Some(hw_address)));
+        entries.push(Entry {
+            hw_address: None,
+            ip_address: address,
+            expires_at: now + quarantine.as_secs(),
+        });
+        lock.store(&entries)?;
+
+        Ok(())
+    }
+}
+
+/// Assigns the address to the client, dropping whatever else it held,
+/// offers never shorten the leases that have already been committed
+fn hold_for(
+    entries: &mut Vec<Entry>,
+    hw_address: EthernetAddress,
+    address: Ipv4Addr,
+    expires_at: u64,
+    commit: bool,
+) {
+    let existing = entries
+        .iter()
+        .find(|entry| entry.hw_address == Some(hw_address) && entry.ip_address == address)
+        .map(|entry| entry.expires_at);
+
+    entries.retain(|entry| entry.hw_address != Some(hw_address));
+
+    let expires_at = match existing {
+        Some(existing) if !commit => existing.max(expires_at),
+        _ => expires_at,
+    };
+
+    entries.push(Entry {
+        hw_address: Some(hw_address),
+        ip_address: address,
+        expires_at,
+    });
+}
+
+/// Strips the length and checksum header off the lease file's
+/// contents, returns an error if the entries don't match it
+fn unwrap_body(contents: &[u8]) -> Result<&[u8]> {
+    let (header, body) = contents
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .map(|idx| (&contents[..idx], &contents[idx + 1..]))
+        .ok_or_else(|| anyhow!("missing header"))?;
+
+    let header = std::str::from_utf8(header).context("invalid header")?;
+    let (length, checksum) = header
+        .split_once(' ')
+        .and_then(|(length, checksum)| {
+            Some((
+                length.parse::<usize>().ok()?,
+                u64::from_str_radix(checksum, 16).ok()?,
+            ))
+        })
+        .ok_or_else(|| anyhow!("invalid header {header:?}"))?;
+
+    if body.len() != length || fnv1a(body) != checksum {
+        return Err(anyhow!(
+            "entries don't match the header, possibly a torn write"
+        ));
+    }
+
+    Ok(body)
+}
+
+/// 64-bit FNV-1a hash, which is plenty to detect a torn write
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn parse(contents: &[u8]) -> Result<Vec<Entry>> {
+    let entries: Vec<serde_json::Value> = serde_json::from_slice(contents)?;
+
+    entries
+        .iter()
+        .map(|entry| {
+            let hw_address = match &entry["hw_address"] {
+                serde_json::Value::Null => None,
+                hw_address => Some(
+                    hw_address
+                        .as_str()
+                        .and_then(|hw_address| hw_address.parse::<MacAddress>().ok())
+                        .map(|hw_address| EthernetAddress(hw_address.bytes()))
+                        .ok_or_else(|| anyhow!("invalid hw_address {hw_address}"))?,
+                ),
+            };
+
+            Ok(Entry {
+                hw_address,
+                ip_address: entry["ip_address"]
+                    .as_str()
+                    .and_then(|ip_address| ip_address.parse().ok())
+                    .ok_or_else(|| anyhow!("invalid ip_address {}", entry["ip_address"]))?,
+                expires_at: entry["expires_at"]
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("invalid expires_at {}", entry["expires_at"]))?,
+            })
+        })
+        .collect()
+}
+
+fn unix_now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LeaseFile;
+    use smoltcp::wire::EthernetAddress;
+    use std::fs::Permissions;
+    use std::net::Ipv4Addr;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const HW_ADDRESS: EthernetAddress = EthernetAddress([0x02, 0, 0, 0, 0, 0x01]);
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 64, 100);
+
+    #[test]
+    fn test_corrupted_lease_file_is_not_discarded() {
+        let path = lease_file_path("corrupted");
+        std::fs::write(&path, "[{\"hw_address\": \"02:00:00:00:00:01\"").unwrap();
+        assert!(LeaseFile::open(&path).is_err());
+
+        // Torn write that left the tail of the previous entries behind
+        std::fs::remove_file(&path).unwrap();
+        let mut lease_file = LeaseFile::open(&path).unwrap();
+        commit(&mut lease_file);
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.extend_from_slice(b"00\"}]");
+        std::fs::write(&path, contents).unwrap();
+        assert!(LeaseFile::open(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_directory() {
+        let dir = std::env::temp_dir().join(format!("softnet-leases-ro-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("leases.json");
+
+        // Lease file is opened while privileged and then only written through
+        let mut lease_file = LeaseFile::open(&path).unwrap();
+        std::fs::set_permissions(&dir, Permissions::from_mode(0o555)).unwrap();
+
+        commit(&mut lease_file);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(
+            lease_file
+                .offer(HW_ADDRESS, None, &[ADDRESS], Duration::from_secs(60))
+                .unwrap(),
+            Some(ADDRESS)
+        );
+
+        std::fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn commit(lease_file: &mut LeaseFile) {
+        assert!(
+            lease_file
+                .commit(HW_ADDRESS, ADDRESS, Duration::from_secs(600))
+                .unwrap()
+        );
+    }
+
+    fn lease_file_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "softnet-{}-leases-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+}
@@ -0,0 +1,308 @@
+use crate::proxy::Proxy;
+use crate::proxy::bounded_set::BoundedSet;
+use crate::proxy::events;
+use anyhow::{Context, Error, Result, anyhow};
+use ipnet::Ipv4Net;
+use log::info;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket,
+};
+use std::path::Path;
+use std::str::FromStr;
+
+// Stratum messages are small, there's no need to look further
+const INSPECTION_LEN: usize = 512;
+
+const STRATUM_METHODS: [&[u8]; 2] = [b"\"mining.subscribe\"", b"\"mining.authorize\""];
+
+// Upper bounds on the number of blocked endpoints and inspected flows
+const MAX_BLOCKED_ENDPOINTS: usize = 4096;
+const MAX_INSPECTED_FLOWS: usize = 4096;
+
+/// Known mining pool endpoint, parsed from IP[/PREFIX][:PORT]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolEndpoint {
+    pub net: Ipv4Net,
+    pub port: Option<u16>,
+}
+
+impl FromStr for PoolEndpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (net, port) = match s.rsplit_once(':') {
+            Some((net, port)) => (
+                net,
+                Some(
+                    port.parse()
+                        .context(format!("invalid mining pool port {:?}", port))?,
+                ),
+            ),
+            None => (s, None),
+        };
+
+        let net = match net.parse::<Ipv4Net>() {
+            Ok(net) => net,
+            Err(_) => net
+                .parse::<Ipv4Address>()
+                .map(Ipv4Net::from)
+                .map_err(|_| anyhow!("invalid mining pool address {:?}", net))?,
+        };
+
+        Ok(PoolEndpoint { net, port })
+    }
+}
+
+impl PoolEndpoint {
+    fn matches(&self, addr: Ipv4Address, port: u16) -> bool {
+        self.net.contains(&addr) && self.port.is_none_or(|pool_port| pool_port == port)
+    }
+}
+
+pub struct MiningDetector {
+    pools: Vec<PoolEndpoint>,
+    blocked: BoundedSet<(Ipv4Address, u16)>,
+    // Flows whose first payload was already inspected,
+    // keyed by the VM's port and the destination
+    inspected: BoundedSet<(u16, Ipv4Address, u16)>,
+    detections: u64,
+    dropped: u64,
+}
+
+impl MiningDetector {
+    pub fn new(pools_path: Option<&Path>) -> Result<MiningDetector> {
+        let pools = match pools_path {
+            Some(path) => load(path)
+                .with_context(|| format!("failed to load mining pools from {}", path.display()))?,
+            None => Vec::new(),
+        };
+
+        Ok(MiningDetector {
+            pools,
+            blocked: BoundedSet::new(MAX_BLOCKED_ENDPOINTS),
+            inspected: BoundedSet::new(MAX_INSPECTED_FLOWS),
+            detections: 0,
+            dropped: 0,
+        })
+    }
+
+    /// Inspects a TCP segment sent by the VM, returns false if it
+    /// belongs to a mining pool connection and should be dropped,
+    /// only the first payload of each flow is checked for Stratum
+    pub fn inspect(
+        &mut self,
+        src_port: u16,
+        dst_addr: Ipv4Address,
+        dst_port: u16,
+        syn: bool,
+        payload: &[u8],
+    ) -> bool {
+        let endpoint = (dst_addr, dst_port);
+
+        if self.blocked.contains(&endpoint) {
+            self.dropped += 1;
+
+            return false;
+        }
+
+        // The port may be reused for a new connection
+        let flow = (src_port, dst_addr, dst_port);
+
+        if syn {
+            self.inspected.remove(&flow);
+        }
+
+        let reason = if syn
+            && self
+                .pools
+                .iter()
+                .any(|pool| pool.matches(dst_addr, dst_port))
+        {
+            "connecting to a known mining pool"
+        } else if !payload.is_empty() && self.inspected.insert(flow) && speaks_stratum(payload) {
+            "speaking the Stratum mining protocol to"
+        } else {
+            return true;
+        };
+
+        events::emit(&format!(
+            "VM is {reason} {dst_addr}:{dst_port}, blocking the endpoint"
+        ));
+
+        self.blocked.insert(endpoint);
+        self.detections += 1;
+        self.dropped += 1;
+
+        false
+    }
+
+    pub fn log_summary(&self) {
+        if self.detections != 0 {
+            info!(
+                "mining detector: blocked {} endpoint(s), dropped {} packet(s)",
+                self.detections, self.dropped
+            );
+        }
+    }
+}
+
+/// Looks for the Stratum's (JSON-RPC) subscription and
+/// authorization requests in the beginning of the payload
+fn speaks_stratum(payload: &[u8]) -> bool {
+    let payload = &payload[..payload.len().min(INSPECTION_LEN)];
+
+    if payload.first() != Some(&b'{') {
+        return false;
+    }
+
+    STRATUM_METHODS.iter().any(|method| {
+        payload
+            .windows(method.len())
+            .any(|window| window == *method)
+    })
+}
+
+fn load(path: &Path) -> Result<Vec<PoolEndpoint>> {
+    let contents = std::fs::read_to_string(path)?;
+
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(PoolEndpoint::from_str)
+        .collect()
+}
+
+impl Proxy<'_> {
+    /// Blocks the VM's connections to the mining pools, returns
+    /// None if the frame belongs to such connection
+    pub(crate) fn detect_mining(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
+        let Some(mining_detector) = &mut self.mining_detector else {
+            return Some(());
+        };
+
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Some(());
+        }
+
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+        if ipv4_pkt.next_header() != IpProtocol::Tcp || ipv4_pkt.frag_offset() != 0 {
+            return Some(());
+        }
+
+        let tcp_pkt = TcpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+        mining_detector
+            .inspect(
+                tcp_pkt.src_port(),
+                ipv4_pkt.dst_addr(),
+                tcp_pkt.dst_port(),
+                tcp_pkt.syn() && !tcp_pkt.ack(),
+                tcp_pkt.payload(),
+            )
+            .then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_BLOCKED_ENDPOINTS, MiningDetector, PoolEndpoint, speaks_stratum};
+    use ipnet::Ipv4Net;
+    use smoltcp::wire::Ipv4Address;
+    use std::str::FromStr;
+
+    const POOL: Ipv4Address = Ipv4Address::new(203, 0, 113, 7);
+
+    #[test]
+    fn test_stratum() {
+        assert!(speaks_stratum(
+            br#"{"id": 1, "method": "mining.subscribe", "params": ["cpuminer/2.5.1"]}"#
+        ));
+        assert!(speaks_stratum(
+            br#"{"params": ["worker", "x"], "id": 2, "method": "mining.authorize"}"#
+        ));
+        assert!(!speaks_stratum(br#"{"method": "eth_blockNumber"}"#));
+        assert!(!speaks_stratum(b"GET /mining.subscribe HTTP/1.1"));
+        assert!(!speaks_stratum(b""));
+    }
+
+    #[test]
+    fn test_pool_endpoint_parsing() {
+        assert_eq!(
+            "203.0.113.7:3333".parse::<PoolEndpoint>().unwrap(),
+            PoolEndpoint {
+                net: Ipv4Net::from_str("203.0.113.7/32").unwrap(),
+                port: Some(3333),
+            }
+        );
+        assert_eq!(
+            "203.0.113.0/24".parse::<PoolEndpoint>().unwrap(),
+            PoolEndpoint {
+                net: Ipv4Net::from_str("203.0.113.0/24").unwrap(),
+                port: None,
+            }
+        );
+        assert!("pool.example.com:3333".parse::<PoolEndpoint>().is_err());
+    }
+
+    #[test]
+    fn test_blocking() {
+        let path =
+            std::env::temp_dir().join(format!("softnet-mining-pools-{}", std::process::id()));
+        std::fs::write(&path, "# Example pool\n203.0.113.0/24:3333\n").unwrap();
+
+        let mut detector = MiningDetector::new(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Known pool on a different port
+        assert!(detector.inspect(50000, POOL, 443, true, b""));
+
+        // Known pool
+        assert!(!detector.inspect(50000, POOL, 3333, true, b""));
+        assert!(!detector.inspect(50000, POOL, 3333, false, b"hello"));
+
+        // Unknown pool detected by the protocol, subsequent segments are blocked too
+        let other = Ipv4Address::new(198, 51, 100, 1);
+        assert!(detector.inspect(50001, other, 4444, true, b""));
+        assert!(!detector.inspect(
+            50001,
+            other,
+            4444,
+            false,
+            br#"{"method":"mining.subscribe"}"#
+        ));
+        assert!(!detector.inspect(50001, other, 4444, false, b""));
+        assert_eq!(detector.detections, 2);
+
+        // Only the first payload of each flow is inspected
+        let third = Ipv4Address::new(198, 51, 100, 2);
+        assert!(detector.inspect(50002, third, 80, true, b""));
+        assert!(detector.inspect(50002, third, 80, false, b"POST /rpc HTTP/1.1"));
+        assert!(detector.inspect(50002, third, 80, false, br#"{"method":"mining.subscribe"}"#));
+
+        // Until the port is reused for a new connection
+        assert!(detector.inspect(50002, third, 80, true, b""));
+        assert!(!detector.inspect(50002, third, 80, false, br#"{"method":"mining.subscribe"}"#));
+    }
+
+    #[test]
+    fn test_blocked_endpoints_are_evicted_one_by_one() {
+        let mut detector = MiningDetector::new(None).unwrap();
+        let stratum = br#"{"method":"mining.subscribe"}"#;
+
+        assert!(!detector.inspect(50000, POOL, 3333, false, stratum));
+
+        // Throwaway endpoints only push out the oldest ones
+        for port in 0..MAX_BLOCKED_ENDPOINTS as u16 - 1 {
+            assert!(!detector.inspect(
+                port,
+                Ipv4Address::new(198, 51, 100, 1),
+                port,
+                false,
+                stratum
+            ));
+        }
+        assert!(!detector.inspect(50000, POOL, 3333, false, b""));
+    }
+}
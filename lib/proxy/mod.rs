@@ -1,57 +1,226 @@
+mod bounded_set;
+mod dhcp_guard;
+mod dhcp_rewriter;
+mod dhcp_server;
+mod dns_tunnel;
+mod events;
 mod exposed_port;
+mod fixed_window;
+mod flood;
+mod flows;
+mod geoip;
 mod host;
+mod impairment;
+mod ip_set;
+mod lease_file;
+mod mining;
+mod mtu;
+mod multicast;
 mod port_forwarder;
+mod quota;
+mod scan;
+mod scheduler;
+mod scrubber;
+mod shaper;
+mod tunnel;
 mod udp_packet_helper;
 mod vm;
 
-use crate::dhcp_snooper::DhcpSnooper;
+use crate::dhcp_snooper::{DhcpSnooper, Lease};
 use crate::host::Host;
 use crate::host::NetType;
 use crate::poller::Poller;
 use crate::vm::VM;
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use dhcp_guard::DhcpGuard;
+use dhcp_rewriter::DhcpRewriter;
+pub use dhcp_rewriter::StaticRoute;
+pub use dhcp_server::DhcpPool;
+use dhcp_server::DhcpServer;
+use dns_tunnel::DnsTunnelDetector;
 pub use exposed_port::ExposedPort;
+use flood::FloodDetector;
+pub use flood::{FloodAction, FloodThresholds};
+use flows::FlowTable;
+pub use flows::{FlowLimitAction, FlowLimits};
+use geoip::GeoIp;
+use impairment::Impairer;
+pub use impairment::Impairment;
+pub use ip_set::IpSetSpec;
+use ip_set::IpSets;
 use ipnet::Ipv4Net;
+use log::{info, warn};
 use mac_address::MacAddress;
+use mining::MiningDetector;
+pub use multicast::MulticastGroup;
+use multicast::MulticastPolicy;
 use port_forwarder::PortForwarder;
 use prefix_trie::{Prefix, PrefixMap};
-use smoltcp::wire::EthernetFrame;
+use quota::Quotas;
+pub use quota::{QuotaAction, QuotaSpec};
+use scan::ScanDetector;
+pub use scan::{ScanAction, ScanThresholds};
+use scheduler::Scheduler;
+pub use scheduler::SchedulingMode;
+pub use scrubber::ScrubCheck;
+use scrubber::Scrubber;
+use shaper::Shaper;
+pub use shaper::{ByteSize, Rate};
+use smoltcp::wire::{EthernetFrame, Ipv4Address};
+use std::collections::HashSet;
 use std::io::ErrorKind;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tunnel::TunnelDetector;
+pub use tunnel::TunnelPolicy;
 use vmnet::Batch;
 
+const VM_BACKLOG_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
 pub struct Proxy<'proxy> {
     vm: VM,
     host: Host,
     poller: Poller<'proxy>,
     vm_mac_address: smoltcp::wire::EthernetAddress,
+    gateway_mac_address: Option<smoltcp::wire::EthernetAddress>,
+    scrubber: Scrubber,
     dhcp_snooper: DhcpSnooper,
+    dhcp_server: Option<DhcpServer>,
+    dhcp_rewriter: Option<DhcpRewriter>,
+    dhcp_release_on_shutdown: bool,
+    dhcp_guard: DhcpGuard,
+    allow: Vec<Target>,
+    block: Vec<Target>,
+    ip_sets: IpSets,
     rules: PrefixMap<Ipv4Net, Action>,
+    geoip: GeoIp,
+    multicast: MulticastPolicy,
+    egress_shaper: Option<Shaper>,
+    ingress_shaper: Option<Shaper>,
+    quotas: Quotas,
+    egress_impairer: Option<Impairer>,
+    ingress_impairer: Option<Impairer>,
+    mtu: u16,
+    mtu_stats: mtu::Stats,
+    vm_backlog: Scheduler,
+    flows: Option<FlowTable>,
+    scan_detector: Option<ScanDetector>,
+    flood_detector: Option<FloodDetector>,
+    mining_detector: Option<MiningDetector>,
+    tunnel_detector: Option<TunnelDetector>,
+    dns_tunnel_detector: Option<DnsTunnelDetector>,
     enobufs_encountered: bool,
     port_forwarder: PortForwarder,
 }
 
+#[derive(Default)]
+pub struct Options {
+    pub allow: Vec<Target>,
+    pub block: Vec<Target>,
+    pub exposed_ports: Vec<ExposedPort>,
+    pub scrub_checks: Vec<ScrubCheck>,
+    pub ip_sets: Vec<IpSetSpec>,
+    pub geoip_database: Option<PathBuf>,
+    pub asn_database: Option<PathBuf>,
+    pub multicast_groups: Vec<MulticastGroup>,
+    pub multicast_rate_limit: Option<u32>,
+    pub egress_rate: Option<Rate>,
+    pub egress_burst: Option<ByteSize>,
+    pub ingress_rate: Option<Rate>,
+    pub ingress_burst: Option<ByteSize>,
+    pub quotas: Vec<QuotaSpec>,
+    pub quota_action: QuotaAction,
+    pub quota_throttle_rate: Option<Rate>,
+    pub egress_impairment: Option<Impairment>,
+    pub ingress_impairment: Option<Impairment>,
+    pub impairment_seed: Option<u64>,
+    pub mtu: Option<u16>,
+    pub scheduling_mode: SchedulingMode,
+    pub flow_limits: FlowLimits,
+    pub flow_limit_action: FlowLimitAction,
+    pub scan_thresholds: ScanThresholds,
+    pub scan_action: ScanAction,
+    pub flood_thresholds: FloodThresholds,
+    pub flood_action: FloodAction,
+    pub block_mining: bool,
+    pub mining_pools: Option<PathBuf>,
+    pub tunnel_policy: TunnelPolicy,
+    pub dns_tunnel_threshold: Option<u32>,
+    pub state_dir: Option<PathBuf>,
+    pub bootpd_leases: Option<PathBuf>,
+    pub dhcp_server: bool,
+    pub dhcp_pool: Option<DhcpPool>,
+    pub dhcp_lease_time: Duration,
+    pub dhcp_lease_file: PathBuf,
+    pub vm_ip: Option<Ipv4Address>,
+    pub vm_dns: Vec<Ipv4Address>,
+    pub dhcp_dns: Vec<Ipv4Address>,
+    pub dhcp_domain: Option<String>,
+    pub dhcp_search: Vec<String>,
+    pub dhcp_mtu: Option<u16>,
+    pub dhcp_routes: Vec<StaticRoute>,
+    pub dhcp_release_on_shutdown: bool,
+    pub dhcp_rate_limit: Option<u32>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Target {
     Prefix(Ipv4Net),
     Host,
+    Set(String),
+    Asn(u32),
+    Country(String),
 }
 
 impl FromStr for Target {
-    type Err = ipnet::AddrParseError;
+    type Err = anyhow::Error;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         if s == "@host" {
             return Ok(Target::Host);
         }
 
-        Ipv4Net::from_str(s).map(Target::Prefix)
+        if let Some(name) = s.strip_prefix("@set:") {
+            return Ok(Target::Set(name.to_string()));
+        }
+
+        if let Some(asn) = s.strip_prefix("asn:") {
+            return asn
+                .parse()
+                .map(Target::Asn)
+                .map_err(|_| anyhow!("invalid autonomous system number {:?}", asn));
+        }
+
+        if let Some(code) = s.strip_prefix("geo:") {
+            if code.len() != 2 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(anyhow!(
+                    "invalid country code {:?}, expected an ISO 3166-1 alpha-2 code",
+                    code
+                ));
+            }
+
+            return Ok(Target::Country(code.to_ascii_uppercase()));
+        }
+
+        Ok(Ipv4Net::from_str(s).map(Target::Prefix)?)
+    }
+}
+
+impl Target {
+    fn prefixes(&self, gateway_ip: Ipv4Address, ip_sets: &IpSets) -> Vec<Ipv4Net> {
+        match self {
+            Target::Prefix(prefix) => vec![*prefix],
+            Target::Host => vec![gateway_ip.into()],
+            Target::Set(name) => ip_sets.prefixes(name).to_vec(),
+            // Resolved on each new destination by the GeoIp instead
+            Target::Asn(_) | Target::Country(_) => Vec::new(),
+        }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum Action {
     Block,
     Allow,
@@ -62,57 +231,222 @@ impl Proxy<'_> {
         vm_fd: RawFd,
         vm_mac_address: MacAddress,
         vm_net_type: NetType,
-        allow: Vec<Target>,
-        block: Vec<Target>,
-        exposed_ports: Vec<ExposedPort>,
+        options: Options,
     ) -> Result<Proxy<'proxy>> {
+        let ip_sets = IpSets::load(options.ip_sets)?;
+
+        for target in options.allow.iter().chain(options.block.iter()) {
+            if let Target::Set(name) = target
+                && !ip_sets.contains(name)
+            {
+                return Err(anyhow!(
+                    "IP set {:?} is referenced in the rules, but was not defined with --ip-set",
+                    name
+                ));
+            }
+        }
+
         let vm = VM::new(vm_fd)?;
         let host = Host::new(
             vm_net_type,
-            !allow.contains(&Target::Prefix(Ipv4Net::zero())),
+            !options.allow.contains(&Target::Prefix(Ipv4Net::zero())),
         )?;
         let poller_timeout = Duration::from_millis(100);
         let poller = Poller::new(vm.as_raw_fd(), host.as_raw_fd(), poller_timeout)?;
 
-        // Craft packet filter rules
-        //
-        // SECURITY: blocking rules must always take precedence
-        // over allowing rules when prefixes are identical.
-        let mut rules = PrefixMap::new();
+        let rules = craft_rules(&options.allow, &options.block, host.gateway_ip, &ip_sets);
+        let geoip = GeoIp::new(
+            &options.allow,
+            &options.block,
+            options.geoip_database,
+            options.asn_database,
+        )?;
 
-        for allow_target in allow {
-            let allow_prefix = match allow_target {
-                Target::Prefix(prefix) => prefix,
-                Target::Host => host.gateway_ip.into(),
-            };
+        let max_frame_len = host.max_packet_size as usize;
+        let scrubber = Scrubber::new(options.scrub_checks, max_frame_len);
+        let mtu = mtu::effective_mtu(options.mtu, max_frame_len)?;
+        let flows = (!options.flow_limits.is_empty())
+            .then(|| FlowTable::new(options.flow_limits, options.flow_limit_action));
+        let scan_detector = (!options.scan_thresholds.is_empty()).then(|| {
+            ScanDetector::new(options.scan_thresholds, options.scan_action, max_frame_len)
+        });
+        let flood_detector = (!options.flood_thresholds.is_empty())
+            .then(|| FloodDetector::new(options.flood_thresholds, options.flood_action));
+        let mining_detector = if options.block_mining || options.mining_pools.is_some() {
+            Some(MiningDetector::new(options.mining_pools.as_deref())?)
+        } else {
+            None
+        };
+        let tunnel_detector = (options.tunnel_policy != TunnelPolicy::Ignore)
+            .then(|| TunnelDetector::new(options.tunnel_policy));
+        let dns_tunnel_detector = options.dns_tunnel_threshold.map(DnsTunnelDetector::new);
+        let egress_shaper = options.egress_rate.map(|rate| {
+            Shaper::new(
+                "egress",
+                true,
+                rate,
+                options.egress_burst,
+                max_frame_len,
+                options.scheduling_mode,
+            )
+        });
+        let ingress_shaper = options.ingress_rate.map(|rate| {
+            Shaper::new(
+                "ingress",
+                false,
+                rate,
+                options.ingress_burst,
+                max_frame_len,
+                options.scheduling_mode,
+            )
+        });
+        let quotas = Quotas::new(
+            options.quotas,
+            options.quota_action,
+            options.quota_throttle_rate,
+            max_frame_len,
+        );
 
-            rules.insert(allow_prefix, Action::Allow);
-        }
+        // Impairments are reproducible given the same seed,
+        // so log it when it was picked at random
+        let impairment_seed = options.impairment_seed.unwrap_or_else(|| {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64)
+                .unwrap_or_default();
 
-        for block_target in block {
-            let block_prefix = match block_target {
-                Target::Prefix(prefix) => prefix,
-                Target::Host => host.gateway_ip.into(),
-            };
+            if options.egress_impairment.is_some() || options.ingress_impairment.is_some() {
+                info!("using impairment seed {seed}");
+            }
 
-            rules.insert(block_prefix, Action::Block);
+            seed
+        });
+        let egress_impairer = options
+            .egress_impairment
+            .map(|impairment| Impairer::new("egress", impairment, impairment_seed));
+        let ingress_impairer = options
+            .ingress_impairment
+            .map(|impairment| Impairer::new("ingress", impairment, !impairment_seed));
+
+        // State files are keyed by the VM's MAC address, since
+        // that's what identifies the VM to the DHCP server
+        let lease_state_path = options.state_dir.map(|state_dir| {
+            state_dir.join(format!(
+                "lease-{}.json",
+                vm_mac_address.to_string().replace(':', "-")
+            ))
+        });
+
+        let mut dhcp_snooper = DhcpSnooper::new(
+            smoltcp::wire::EthernetAddress(vm_mac_address.bytes()),
+            poller_timeout,
+            lease_state_path,
+        );
+
+        if let Some(vm_ip) = options.vm_ip {
+            let mask = host.subnet_mask.to_bits();
+
+            if vm_ip.to_bits() & mask != host.gateway_ip.to_bits() & mask
+                || vm_ip == host.gateway_ip
+            {
+                return Err(anyhow!(
+                    "VM IP {} is not a host address in the {}/{} subnet",
+                    vm_ip,
+                    Ipv4Address::from_bits(host.gateway_ip.to_bits() & mask),
+                    mask.count_ones()
+                ));
+            }
+
+            // VM uses static addressing, so there's no DHCP to snoop on
+            dhcp_snooper.set_static_lease(Lease::permanent(
+                vm_ip,
+                HashSet::from_iter(options.vm_dns.iter().cloned()),
+            ));
+        } else if let Some(bootpd_leases) = &options.bootpd_leases {
+            dhcp_snooper.restore_from_bootpd_leases(bootpd_leases);
         }
 
+        // bootpd(8) hands out the whole vmnet's range to the other VMs,
+        // so the pool has to be carved out of it explicitly to not overlap
+        let dhcp_server = if options.dhcp_server {
+            let dhcp_pool = options
+                .dhcp_pool
+                .ok_or_else(|| anyhow!("built-in DHCP server requires a DHCP pool"))?;
+
+            Some(DhcpServer::new(
+                dhcp_pool,
+                options.dhcp_lease_time,
+                &options.dhcp_lease_file,
+                host.gateway_ip,
+                host.subnet_mask,
+            )?)
+        } else {
+            None
+        };
+
+        let dhcp_rewriter = DhcpRewriter::new(
+            options.dhcp_dns,
+            options.dhcp_domain,
+            options.dhcp_search,
+            options.dhcp_mtu,
+            options.dhcp_routes,
+        )?;
+
+        // Attach the VM identity to the events reported to Sentry, including
+        // the IP that was either configured statically or restored from disk
+        sentry::configure_scope(|scope| {
+            scope.set_tag("vm_mac_address", vm_mac_address);
+
+            if let Some(lease) = dhcp_snooper.lease() {
+                scope.set_tag("vm_ip", lease.address());
+            }
+        });
+
         Ok(Proxy {
             vm,
             host,
             poller,
             vm_mac_address: smoltcp::wire::EthernetAddress(vm_mac_address.bytes()),
-            dhcp_snooper: DhcpSnooper::new(poller_timeout),
+            gateway_mac_address: None,
+            scrubber,
+            dhcp_snooper,
+            dhcp_server,
+            dhcp_rewriter,
+            dhcp_release_on_shutdown: options.dhcp_release_on_shutdown,
+            dhcp_guard: DhcpGuard::new(
+                smoltcp::wire::EthernetAddress(vm_mac_address.bytes()),
+                options.dhcp_rate_limit,
+            ),
+            allow: options.allow,
+            block: options.block,
+            ip_sets,
             rules,
+            geoip,
+            multicast: MulticastPolicy::new(options.multicast_groups, options.multicast_rate_limit),
+            egress_shaper,
+            ingress_shaper,
+            quotas,
+            egress_impairer,
+            ingress_impairer,
+            mtu,
+            mtu_stats: Default::default(),
+            vm_backlog: Scheduler::new("VM backlog", false, options.scheduling_mode),
+            flows,
+            scan_detector,
+            flood_detector,
+            mining_detector,
+            tunnel_detector,
+            dns_tunnel_detector,
             enobufs_encountered: false,
-            port_forwarder: PortForwarder::new(exposed_ports),
+            port_forwarder: PortForwarder::new(options.exposed_ports),
         })
     }
 
     pub fn run(&mut self) -> Result<()> {
-        // Create a single buffer from reading from the VM
-        let mut buf: Vec<u8> = vec![0; self.host.max_packet_size as usize];
+        // Create a single buffer from reading from the VM,
+        // one extra byte allows the scrubber to detect oversized
+        // frames instead of having them silently truncated
+        let mut buf: Vec<u8> = vec![0; self.host.max_packet_size as usize + 1];
 
         // Create multiple buffers and a batch for reading from the host
         let mut bufs = vec![
@@ -124,10 +458,11 @@ impl Proxy<'_> {
         self.poller.arm()?;
 
         loop {
-            let (vm_readable, host_readable, interrupt) = self.poller.wait()?;
+            let (vm_readable, host_readable, interrupt) = self.poller.wait(self.next_wakeup())?;
 
             // Update coarse time for the DHCP snooper
             coarsetime::Instant::update();
+            self.dhcp_snooper.tick(coarsetime::Instant::recent());
 
             if vm_readable {
                 self.read_from_vm(buf.as_mut_slice())?;
@@ -137,8 +472,29 @@ impl Proxy<'_> {
                 self.read_from_host(&mut batch, &mut bufs)?;
             }
 
+            // Release the frames held back by the VM backlog, impairers and traffic shapers
+            self.drain_queues()?;
+
+            // Pick up the changes to the IP sets' files, if any
+            if self.ip_sets.refresh() {
+                self.rules = craft_rules(
+                    &self.allow,
+                    &self.block,
+                    self.host.gateway_ip,
+                    &self.ip_sets,
+                );
+            }
+
             // Graceful termination
             if interrupt {
+                if self.dhcp_release_on_shutdown
+                    && let Err(err) = self.release_dhcp_lease()
+                {
+                    warn!("failed to release the VM's DHCP lease: {err:#}");
+                }
+
+                self.log_summary();
+
                 return Ok(());
             }
 
@@ -152,6 +508,103 @@ impl Proxy<'_> {
         }
     }
 
+    fn log_summary(&self) {
+        self.scrubber.log_summary();
+
+        if let Some(dhcp_server) = &self.dhcp_server {
+            dhcp_server.log_summary();
+        }
+
+        if let Some(dhcp_rewriter) = &self.dhcp_rewriter {
+            dhcp_rewriter.log_summary();
+        }
+
+        self.mtu_stats.log_summary();
+        self.vm_backlog.log_summary();
+
+        if let Some(flows) = &self.flows {
+            flows.log_summary();
+        }
+
+        if let Some(scan_detector) = &self.scan_detector {
+            scan_detector.log_summary();
+        }
+
+        if let Some(flood_detector) = &self.flood_detector {
+            flood_detector.log_summary();
+        }
+
+        if let Some(mining_detector) = &self.mining_detector {
+            mining_detector.log_summary();
+        }
+
+        if let Some(tunnel_detector) = &self.tunnel_detector {
+            tunnel_detector.log_summary();
+        }
+
+        if let Some(dns_tunnel_detector) = &self.dns_tunnel_detector {
+            dns_tunnel_detector.log_summary();
+        }
+
+        self.multicast.log_summary();
+        self.dhcp_guard.log_summary();
+
+        for shaper in [&self.egress_shaper, &self.ingress_shaper]
+            .into_iter()
+            .flatten()
+        {
+            shaper.log_summary();
+        }
+
+        self.quotas.log_summary();
+
+        for impairer in [&self.egress_impairer, &self.ingress_impairer]
+            .into_iter()
+            .flatten()
+        {
+            impairer.log_summary();
+        }
+    }
+
+    fn next_wakeup(&self) -> Option<Duration> {
+        let shapers = [&self.egress_shaper, &self.ingress_shaper]
+            .into_iter()
+            .flatten()
+            .filter_map(|shaper| shaper.next_wakeup());
+        let impairers = [&self.egress_impairer, &self.ingress_impairer]
+            .into_iter()
+            .flatten()
+            .filter_map(|impairer| impairer.next_wakeup());
+
+        // Retry writing the backlogged frames shortly
+        let vm_backlog = (!self.vm_backlog.is_empty()).then_some(VM_BACKLOG_RETRY_INTERVAL);
+
+        shapers.chain(impairers).chain(vm_backlog).min()
+    }
+
+    fn drain_queues(&mut self) -> Result<()> {
+        self.drain_vm_backlog()?;
+
+        // Impaired frames are subject to shaping, so release them first
+        while let Some(frame) = self.egress_impairer.as_mut().and_then(Impairer::dequeue) {
+            self.forward_to_host(&frame)?;
+        }
+
+        while let Some(frame) = self.ingress_impairer.as_mut().and_then(Impairer::dequeue) {
+            self.forward_to_vm(&frame)?;
+        }
+
+        while let Some(frame) = self.egress_shaper.as_mut().and_then(Shaper::dequeue) {
+            self.write_to_host(&frame)?;
+        }
+
+        while let Some(frame) = self.ingress_shaper.as_mut().and_then(Shaper::dequeue) {
+            self.write_to_vm(&frame)?;
+        }
+
+        Ok(())
+    }
+
     fn read_from_vm(&mut self, buf: &mut [u8]) -> Result<()> {
         loop {
             match self.vm.read(buf) {
@@ -199,17 +652,49 @@ impl Proxy<'_> {
     }
 }
 
+fn craft_rules(
+    allow: &[Target],
+    block: &[Target],
+    gateway_ip: Ipv4Address,
+    ip_sets: &IpSets,
+) -> PrefixMap<Ipv4Net, Action> {
+    // Craft packet filter rules
+    //
+    // SECURITY: blocking rules must always take precedence
+    // over allowing rules when prefixes are identical.
+    let mut rules = PrefixMap::new();
+
+    for allow_target in allow {
+        for allow_prefix in allow_target.prefixes(gateway_ip, ip_sets) {
+            rules.insert(allow_prefix, Action::Allow);
+        }
+    }
+
+    for block_target in block {
+        for block_prefix in block_target.prefixes(gateway_ip, ip_sets) {
+            rules.insert(block_prefix, Action::Block);
+        }
+    }
+
+    rules
+}
+
 #[cfg(test)]
 mod tests {
     use crate::NetType;
     use crate::dhcp_snooper::Lease;
-    use crate::proxy::{Action, Proxy};
+    use crate::proxy::dhcp_rewriter::DhcpRewriter;
+    use crate::proxy::{Action, Options, Proxy};
+    use dhcproto::v4::{DhcpOption, Message, MessageType, Opcode, OptionCode};
+    use dhcproto::{Decodable, Encodable};
     use ipnet::Ipv4Net;
     use mac_address::MacAddress;
     use nix::sys::socket::{AddressFamily, SockFlag, SockType, socketpair};
     use prefix_trie::PrefixMap;
     use serial_test::serial;
-    use smoltcp::wire::{Ipv4Address, Ipv4Packet};
+    use smoltcp::wire::{
+        EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet, UdpPacket,
+    };
     use std::collections::HashSet;
     use std::os::fd::AsRawFd;
     use std::str::FromStr;
@@ -272,6 +757,96 @@ mod tests {
         assert!(allowed_from_vm_ipv4(&proxy, vm_ip, &proxy.host.gateway_ip.to_string()).is_some());
     }
 
+    #[test]
+    #[serial]
+    fn test_dhcp_renewals_bypass_rules() {
+        let vm_ip = Ipv4Address::from_str("192.168.0.2").unwrap();
+        let mut proxy = create_proxy(vm_ip, vec![], vec!["0.0.0.0/0"]);
+        let server = proxy.host.gateway_ip;
+        proxy.dhcp_snooper.set_server(Some(server));
+
+        // Rebinding via broadcast
+        assert!(dhcp_request_from_vm(&proxy, vm_ip, Ipv4Address::BROADCAST).is_some());
+
+        // Renewing via unicast to the server that granted the lease
+        assert!(dhcp_request_from_vm(&proxy, vm_ip, server).is_some());
+        assert!(
+            dhcp_request_from_vm(&proxy, vm_ip, Ipv4Address::from_str("8.8.8.8").unwrap())
+                .is_none()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_dhcp_reply_is_rewritten() {
+        let vm_ip = Ipv4Address::from_str("192.168.0.2").unwrap();
+        let dns = Ipv4Address::from_str("10.0.0.53").unwrap();
+        let mut proxy = create_proxy(vm_ip, vec![], vec![]);
+        proxy.dhcp_rewriter =
+            DhcpRewriter::new(vec![dns], None, vec![], Some(1400), vec![]).unwrap();
+
+        let mut ack = Message::default();
+        ack.set_opcode(Opcode::BootReply)
+            .set_chaddr(&proxy.vm_mac_address.0)
+            .set_yiaddr(vm_ip);
+        let opts = ack.opts_mut();
+        opts.insert(DhcpOption::MessageType(MessageType::Ack));
+        opts.insert(DhcpOption::AddressLeaseTime(600));
+        opts.insert(DhcpOption::DomainNameServer(vec![proxy.host.gateway_ip]));
+        let payload = ack.to_vec().unwrap();
+
+        let udp_len = 8 + payload.len();
+        let mut buf = vec![0; 14 + 20 + udp_len];
+        let mut frame_mut = EthernetFrame::new_unchecked(&mut buf[..]);
+        frame_mut.set_dst_addr(proxy.vm_mac_address);
+        frame_mut.set_ethertype(EthernetProtocol::Ipv4);
+        let mut ipv4_pkt_mut = Ipv4Packet::new_unchecked(frame_mut.payload_mut());
+        ipv4_pkt_mut.set_version(4);
+        ipv4_pkt_mut.set_header_len(20);
+        ipv4_pkt_mut.set_total_len((20 + udp_len) as u16);
+        ipv4_pkt_mut.set_next_header(IpProtocol::Udp);
+        ipv4_pkt_mut.set_src_addr(proxy.host.gateway_ip);
+        ipv4_pkt_mut.set_dst_addr(vm_ip);
+        let mut udp_pkt_mut = UdpPacket::new_unchecked(ipv4_pkt_mut.payload_mut());
+        udp_pkt_mut.set_src_port(67);
+        udp_pkt_mut.set_dst_port(68);
+        udp_pkt_mut.set_len(udp_len as u16);
+        udp_pkt_mut.payload_mut().copy_from_slice(&payload);
+
+        let rewritten = proxy
+            .rewrite_dhcp_reply(&EthernetFrame::new_unchecked(buf.as_slice()))
+            .unwrap();
+
+        let frame = EthernetFrame::new_checked(rewritten.as_slice()).unwrap();
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert!(ipv4_pkt.verify_checksum());
+        let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).unwrap();
+        assert!(udp_pkt.verify_checksum(&ipv4_pkt.src_addr().into(), &ipv4_pkt.dst_addr().into()));
+
+        let message = Message::from_bytes(udp_pkt.payload()).unwrap();
+        assert_eq!(
+            message.opts().get(OptionCode::DomainNameServer),
+            Some(&DhcpOption::DomainNameServer(vec![dns]))
+        );
+        assert_eq!(
+            message.opts().get(OptionCode::InterfaceMtu),
+            Some(&DhcpOption::InterfaceMtu(1400))
+        );
+
+        // Frames for the other clients are left intact
+        ack.set_chaddr(&[0x02, 0, 0, 0, 0, 0x02]);
+        let payload = ack.to_vec().unwrap();
+        let mut frame_mut = EthernetFrame::new_unchecked(&mut buf[..]);
+        let mut ipv4_pkt_mut = Ipv4Packet::new_unchecked(frame_mut.payload_mut());
+        let mut udp_pkt_mut = UdpPacket::new_unchecked(ipv4_pkt_mut.payload_mut());
+        udp_pkt_mut.payload_mut().copy_from_slice(&payload);
+        assert!(
+            proxy
+                .rewrite_dhcp_reply(&EthernetFrame::new_unchecked(buf.as_slice()))
+                .is_none()
+        );
+    }
+
     fn create_proxy<'test>(vm_ip: Ipv4Address, allow: Vec<&str>, block: Vec<&str>) -> Proxy<'test> {
         let (vm_fd, _) = socketpair(
             AddressFamily::Unix,
@@ -286,15 +861,17 @@ mod tests {
             vm_fd.as_raw_fd(),
             MacAddress::from_str("02:00:00:00:00:01").unwrap(),
             NetType::Nat,
-            allow
-                .into_iter()
-                .map(|cidr| cidr.parse().unwrap())
-                .collect(),
-            block
-                .into_iter()
-                .map(|cidr| cidr.parse().unwrap())
-                .collect(),
-            Vec::default(),
+            Options {
+                allow: allow
+                    .into_iter()
+                    .map(|cidr| cidr.parse().unwrap())
+                    .collect(),
+                block: block
+                    .into_iter()
+                    .map(|cidr| cidr.parse().unwrap())
+                    .collect(),
+                ..Default::default()
+            },
         )
         .unwrap();
 
@@ -318,4 +895,25 @@ mod tests {
 
         proxy.allowed_from_vm_ipv4(ipv4_pkt)
     }
+
+    fn dhcp_request_from_vm(proxy: &Proxy, src: Ipv4Address, dst: Ipv4Address) -> Option<()> {
+        let mut buf = vec![0; 20 + 8 + 300];
+
+        let mut ipv4_pkt_mut = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ipv4_pkt_mut.set_version(4);
+        ipv4_pkt_mut.set_header_len(20);
+        ipv4_pkt_mut.set_total_len((20 + 8 + 300) as u16);
+        ipv4_pkt_mut.set_next_header(IpProtocol::Udp);
+        ipv4_pkt_mut.set_src_addr(src);
+        ipv4_pkt_mut.set_dst_addr(dst);
+
+        let mut udp_pkt_mut = UdpPacket::new_unchecked(ipv4_pkt_mut.payload_mut());
+        udp_pkt_mut.set_src_port(68);
+        udp_pkt_mut.set_dst_port(67);
+        udp_pkt_mut.set_len(8 + 300);
+
+        let ipv4_pkt = Ipv4Packet::new_unchecked(buf.as_slice());
+
+        proxy.allowed_from_vm_ipv4(ipv4_pkt)
+    }
 }
@@ -0,0 +1,431 @@
+use crate::proxy::Proxy;
+use anyhow::{Result, anyhow};
+use log::info;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, Icmpv4Message, Icmpv4Packet, IpProtocol,
+    Ipv4Address, Ipv4Packet, TcpPacket,
+};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const TCP_HEADER_LEN: usize = 20;
+const ICMPV4_HEADER_LEN: usize = 8;
+
+// Smallest MTU that every IPv4 host must accept (RFC 791)
+const MIN_MTU: u16 = 576;
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+
+/// Determines the MTU to enforce on the VM's traffic, which is the
+/// host's MTU unless a lower one was requested (e.g. for tunnelled uplinks)
+pub fn effective_mtu(requested: Option<u16>, max_frame_len: usize) -> Result<u16> {
+    let host_mtu = (max_frame_len - ETHERNET_HEADER_LEN) as u16;
+
+    let Some(mtu) = requested else {
+        return Ok(host_mtu);
+    };
+
+    if mtu < MIN_MTU {
+        return Err(anyhow!(
+            "MTU {} is lower than the minimum of {}",
+            mtu,
+            MIN_MTU
+        ));
+    }
+
+    if mtu > host_mtu {
+        return Err(anyhow!(
+            "MTU {} exceeds the host's MTU of {}",
+            mtu,
+            host_mtu
+        ));
+    }
+
+    Ok(mtu)
+}
+
+#[derive(Default)]
+pub struct Stats {
+    clamped_mss: u64,
+    fragmentation_needed: u64,
+    fragmented: u64,
+}
+
+impl Stats {
+    pub fn log_summary(&self) {
+        if self.clamped_mss != 0 || self.fragmentation_needed != 0 || self.fragmented != 0 {
+            info!(
+                "MTU enforcement: clamped MSS in {} SYN(s), rejected {} oversized packet(s) \
+                with ICMP fragmentation needed, fragmented {} oversized packet(s) without DF",
+                self.clamped_mss, self.fragmentation_needed, self.fragmented
+            );
+        }
+    }
+}
+
+impl Proxy<'_> {
+    /// Rejects the oversized IPv4 packets with the DF bit set by sending
+    /// an ICMP "fragmentation needed" back to the VM and fragments the ones
+    /// without it, returns None if the frame was taken care of
+    pub(crate) fn enforce_mtu(&mut self, frame: &EthernetFrame<&[u8]>) -> Result<Option<()>> {
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Ok(Some(()));
+        }
+
+        let Ok(ipv4_pkt) = Ipv4Packet::new_checked(frame.payload()) else {
+            return Ok(Some(()));
+        };
+
+        if ipv4_pkt.total_len() <= self.mtu {
+            return Ok(Some(()));
+        }
+
+        // The host would only fragment the packets without the DF bit
+        // to its own MTU, which may exceed the requested one
+        if !ipv4_pkt.dont_frag() {
+            self.mtu_stats.fragmented += 1;
+
+            for fragment in fragment(frame, &ipv4_pkt, self.mtu) {
+                self.egress_to_host(EthernetFrame::new_unchecked(fragment.as_slice()))?;
+            }
+
+            return Ok(None);
+        }
+
+        let reply = fragmentation_needed(
+            frame.src_addr(),
+            frame.dst_addr(),
+            self.host.gateway_ip,
+            &ipv4_pkt,
+            self.mtu,
+        );
+
+        self.mtu_stats.fragmentation_needed += 1;
+
+        self.write_to_vm(&reply)?;
+
+        Ok(None)
+    }
+
+    /// Returns a copy of the frame with its MSS option lowered to fit
+    /// the MTU if the frame is a TCP SYN advertising a larger MSS
+    pub(crate) fn clamp_mss(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<Vec<u8>> {
+        let max_mss = self.mtu - (IPV4_HEADER_LEN + TCP_HEADER_LEN) as u16;
+
+        let offsets = oversized_mss_offsets(frame.as_ref(), max_mss);
+
+        if offsets.is_empty() {
+            return None;
+        }
+
+        let mut buf = frame.as_ref().to_vec();
+        clamp_mss(&mut buf, &offsets, max_mss);
+
+        self.mtu_stats.clamped_mss += 1;
+
+        Some(buf)
+    }
+}
+
+/// Returns the offsets of the MSS option values exceeding
+/// max_mss within the frame if it's a TCP SYN
+fn oversized_mss_offsets(frame: &[u8], max_mss: u16) -> Vec<usize> {
+    let frame = EthernetFrame::new_unchecked(frame);
+
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return Vec::new();
+    }
+
+    let Ok(ipv4_pkt) = Ipv4Packet::new_checked(frame.payload()) else {
+        return Vec::new();
+    };
+
+    // Only the first fragment carries the TCP header
+    if ipv4_pkt.next_header() != IpProtocol::Tcp || ipv4_pkt.frag_offset() != 0 {
+        return Vec::new();
+    }
+
+    let Ok(tcp_pkt) = TcpPacket::new_checked(ipv4_pkt.payload()) else {
+        return Vec::new();
+    };
+
+    if !tcp_pkt.syn() {
+        return Vec::new();
+    }
+
+    let options = tcp_pkt.options();
+    let options_offset = ETHERNET_HEADER_LEN + ipv4_pkt.header_len() as usize + TCP_HEADER_LEN;
+
+    let mut offsets = Vec::new();
+    let mut idx = 0;
+
+    while idx < options.len() {
+        match options[idx] {
+            TCP_OPTION_END => break,
+            TCP_OPTION_NOP => idx += 1,
+            kind => {
+                let Some(&len) = options.get(idx + 1) else {
+                    break;
+                };
+                let len = len as usize;
+
+                if len < 2 || idx + len > options.len() {
+                    break;
+                }
+
+                if kind == TCP_OPTION_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([options[idx + 2], options[idx + 3]]);
+
+                    if mss > max_mss {
+                        offsets.push(options_offset + idx + 2);
+                    }
+                }
+
+                idx += len;
+            }
+        }
+    }
+
+    offsets
+}
+
+/// Lowers the MSS option values at the given offsets
+/// to max_mss and recomputes the TCP checksum
+fn clamp_mss(frame: &mut [u8], offsets: &[usize], max_mss: u16) {
+    for &offset in offsets {
+        frame[offset..offset + 2].copy_from_slice(&max_mss.to_be_bytes());
+    }
+
+    let mut frame = EthernetFrame::new_unchecked(frame);
+    let mut ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload_mut());
+
+    let src_addr = ipv4_pkt.src_addr();
+    let dst_addr = ipv4_pkt.dst_addr();
+
+    let mut tcp_pkt = TcpPacket::new_unchecked(ipv4_pkt.payload_mut());
+    tcp_pkt.fill_checksum(&src_addr.into(), &dst_addr.into());
+}
+
+/// Splits the IPv4 packet into the fragments that fit the MTU (RFC 791),
+/// the packet itself may already be a fragment of a larger one
+fn fragment(frame: &EthernetFrame<&[u8]>, ipv4_pkt: &Ipv4Packet<&[u8]>, mtu: u16) -> Vec<Vec<u8>> {
+    let header_len = ipv4_pkt.header_len() as usize;
+    let header = &ipv4_pkt.as_ref()[..header_len];
+    let payload = ipv4_pkt.payload();
+
+    // Fragment offsets are measured in 8-byte units
+    let max_len = (mtu as usize - header_len) / 8 * 8;
+
+    payload
+        .chunks(max_len)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let offset = idx * max_len;
+            let last = offset + chunk.len() == payload.len();
+
+            let mut buf = vec![0u8; ETHERNET_HEADER_LEN + header_len + chunk.len()];
+            buf[..ETHERNET_HEADER_LEN].copy_from_slice(&frame.as_ref()[..ETHERNET_HEADER_LEN]);
+            buf[ETHERNET_HEADER_LEN..][..header_len].copy_from_slice(header);
+
+            let mut fragment = Ipv4Packet::new_unchecked(&mut buf[ETHERNET_HEADER_LEN..]);
+            fragment.set_total_len((header_len + chunk.len()) as u16);
+            fragment.payload_mut().copy_from_slice(chunk);
+            fragment.set_frag_offset(ipv4_pkt.frag_offset() + offset as u16);
+            fragment.set_more_frags(ipv4_pkt.more_frags() || !last);
+            fragment.fill_checksum();
+
+            buf
+        })
+        .collect()
+}
+
+/// Crafts an ICMP "fragmentation needed" (RFC 1191) in response to
+/// the oversized packet, pretending that it comes from the gateway
+fn fragmentation_needed(
+    vm_mac_address: EthernetAddress,
+    next_hop_mac_address: EthernetAddress,
+    gateway_ip: Ipv4Address,
+    ipv4_pkt: &Ipv4Packet<&[u8]>,
+    mtu: u16,
+) -> Vec<u8> {
+    // Original IP header and the first 8 bytes of its payload
+    let original = ipv4_pkt.as_ref();
+    let original = &original[..original.len().min(ipv4_pkt.header_len() as usize + 8)];
+
+    let icmp_len = ICMPV4_HEADER_LEN + original.len();
+    let ipv4_len = IPV4_HEADER_LEN + icmp_len;
+    let mut buf = vec![0u8; ETHERNET_HEADER_LEN + ipv4_len];
+
+    let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+    frame.set_src_addr(next_hop_mac_address);
+    frame.set_dst_addr(vm_mac_address);
+    frame.set_ethertype(EthernetProtocol::Ipv4);
+
+    let mut reply = Ipv4Packet::new_unchecked(frame.payload_mut());
+    reply.set_version(4);
+    reply.set_header_len(IPV4_HEADER_LEN as u8);
+    reply.set_total_len(ipv4_len as u16);
+    reply.set_hop_limit(64);
+    reply.set_next_header(IpProtocol::Icmp);
+    reply.set_src_addr(gateway_ip);
+    reply.set_dst_addr(ipv4_pkt.src_addr());
+    reply.fill_checksum();
+
+    // Next-hop MTU occupies the second half of the otherwise unused field
+    reply.payload_mut()[6..8].copy_from_slice(&mtu.to_be_bytes());
+
+    let mut icmp_pkt = Icmpv4Packet::new_unchecked(reply.payload_mut());
+    icmp_pkt.set_msg_type(Icmpv4Message::DstUnreachable);
+    icmp_pkt.set_msg_code(4);
+    icmp_pkt.data_mut().copy_from_slice(original);
+    icmp_pkt.fill_checksum();
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{clamp_mss, effective_mtu, fragment, fragmentation_needed, oversized_mss_offsets};
+    use smoltcp::wire::{
+        EthernetAddress, EthernetFrame, EthernetProtocol, Icmpv4Message, Icmpv4Packet, IpProtocol,
+        Ipv4Address, Ipv4Packet, TcpPacket,
+    };
+
+    const VM_IP: Ipv4Address = Ipv4Address::new(192, 168, 64, 2);
+    const DST_IP: Ipv4Address = Ipv4Address::new(1, 1, 1, 1);
+
+    #[test]
+    fn test_effective_mtu() {
+        assert_eq!(effective_mtu(None, 1514).unwrap(), 1500);
+        assert_eq!(effective_mtu(Some(1400), 1514).unwrap(), 1400);
+        assert!(effective_mtu(Some(9000), 1514).is_err());
+        assert!(effective_mtu(Some(500), 1514).is_err());
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        let mut frame = syn_frame(1460);
+        let offsets = oversized_mss_offsets(&frame, 1360);
+        assert_eq!(offsets, [14 + 20 + 20 + 3]);
+        clamp_mss(&mut frame, &offsets, 1360);
+
+        let frame = EthernetFrame::new_unchecked(frame.as_slice());
+        let ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload());
+        let tcp_pkt = TcpPacket::new_unchecked(ipv4_pkt.payload());
+        assert_eq!(
+            tcp_pkt.options(),
+            &[0x01, 0x02, 0x04, 0x05, 0x50, 0x01, 0x01, 0x00]
+        );
+        assert!(tcp_pkt.verify_checksum(&VM_IP.into(), &DST_IP.into()));
+
+        // Smaller MSS is left intact
+        assert!(oversized_mss_offsets(&syn_frame(1200), 1360).is_empty());
+    }
+
+    #[test]
+    fn test_fragmentation_needed() {
+        let mut buf = vec![0u8; 1400];
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_total_len(1400);
+        ipv4_pkt.set_dont_frag(true);
+        ipv4_pkt.set_next_header(IpProtocol::Udp);
+        ipv4_pkt.set_src_addr(VM_IP);
+        ipv4_pkt.set_dst_addr(DST_IP);
+
+        let reply = fragmentation_needed(
+            EthernetAddress([0x02, 0, 0, 0, 0, 0x01]),
+            EthernetAddress([0x02, 0, 0, 0, 0, 0x02]),
+            Ipv4Address::new(192, 168, 64, 1),
+            &Ipv4Packet::new_unchecked(buf.as_slice()),
+            1280,
+        );
+
+        let frame = EthernetFrame::new_checked(reply.as_slice()).unwrap();
+        assert_eq!(frame.dst_addr(), EthernetAddress([0x02, 0, 0, 0, 0, 0x01]));
+
+        let reply_pkt = Ipv4Packet::new_checked(frame.payload()).unwrap();
+        assert!(reply_pkt.verify_checksum());
+        assert_eq!(reply_pkt.src_addr(), Ipv4Address::new(192, 168, 64, 1));
+        assert_eq!(reply_pkt.dst_addr(), VM_IP);
+
+        let icmp_pkt = Icmpv4Packet::new_checked(reply_pkt.payload()).unwrap();
+        assert!(icmp_pkt.verify_checksum());
+        assert_eq!(icmp_pkt.msg_type(), Icmpv4Message::DstUnreachable);
+        assert_eq!(icmp_pkt.msg_code(), 4);
+        assert_eq!(&reply_pkt.payload()[6..8], &1280u16.to_be_bytes());
+        assert_eq!(icmp_pkt.data(), &buf[..28]);
+    }
+
+    #[test]
+    fn test_fragment() {
+        let mut buf = vec![0u8; 14 + 1400];
+
+        let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+        frame.set_ethertype(EthernetProtocol::Ipv4);
+
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload_mut());
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_total_len(1400);
+        ipv4_pkt.set_ident(0x1234);
+        ipv4_pkt.set_next_header(IpProtocol::Udp);
+        ipv4_pkt.set_src_addr(VM_IP);
+        ipv4_pkt.set_dst_addr(DST_IP);
+        for (idx, byte) in ipv4_pkt.payload_mut().iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+
+        let frame = EthernetFrame::new_unchecked(buf.as_slice());
+        let ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload());
+        let fragments = fragment(&frame, &ipv4_pkt, 576);
+
+        // 556 bytes of payload fit into each fragment, rounded down to 552
+        assert_eq!(fragments.len(), 3);
+
+        let mut payload = Vec::new();
+
+        for (idx, fragment) in fragments.iter().enumerate() {
+            let frame = EthernetFrame::new_checked(fragment.as_slice()).unwrap();
+            let fragment = Ipv4Packet::new_checked(frame.payload()).unwrap();
+            assert!(fragment.verify_checksum());
+            assert!(fragment.total_len() <= 576);
+            assert_eq!(fragment.ident(), 0x1234);
+            assert_eq!(fragment.frag_offset() as usize, idx * 552);
+            assert_eq!(fragment.more_frags(), idx != 2);
+
+            payload.extend_from_slice(fragment.payload());
+        }
+
+        assert_eq!(payload, ipv4_pkt.payload());
+    }
+
+    fn syn_frame(mss: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; 14 + 20 + 28];
+
+        let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+        frame.set_ethertype(EthernetProtocol::Ipv4);
+
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload_mut());
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_total_len(48);
+        ipv4_pkt.set_next_header(IpProtocol::Tcp);
+        ipv4_pkt.set_src_addr(VM_IP);
+        ipv4_pkt.set_dst_addr(DST_IP);
+
+        let mut tcp_pkt = TcpPacket::new_unchecked(ipv4_pkt.payload_mut());
+        tcp_pkt.set_header_len(28);
+        tcp_pkt.set_syn(true);
+        // NOP padding around the MSS option to exercise the parser
+        let [mss_hi, mss_lo] = mss.to_be_bytes();
+        tcp_pkt
+            .options_mut()
+            .copy_from_slice(&[0x01, 0x02, 0x04, mss_hi, mss_lo, 0x01, 0x01, 0x00]);
+
+        buf
+    }
+}
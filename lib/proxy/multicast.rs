@@ -0,0 +1,264 @@
+use crate::proxy::fixed_window::FixedWindow;
+use anyhow::{Context, Error, anyhow};
+use log::{info, warn};
+use smoltcp::wire::{EthernetAddress, IpProtocol, Ipv4Address, Ipv4Packet, UdpPacket};
+use std::str::FromStr;
+use std::time::Duration;
+
+const MDNS_GROUP: MulticastGroup = MulticastGroup {
+    addr: Ipv4Address::new(224, 0, 0, 251),
+    port: Some(5353),
+};
+
+const SSDP_GROUP: MulticastGroup = MulticastGroup {
+    addr: Ipv4Address::new(239, 255, 255, 250),
+    port: Some(1900),
+};
+
+// IGMPv2 leave and IGMPv3 membership report destinations
+const IGMP_ALL_ROUTERS: Ipv4Address = Ipv4Address::new(224, 0, 0, 2);
+const IGMPV3_ROUTERS: Ipv4Address = Ipv4Address::new(224, 0, 0, 22);
+
+const STORM_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MulticastGroup {
+    pub addr: Ipv4Address,
+    pub port: Option<u16>,
+}
+
+impl FromStr for MulticastGroup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mdns" => return Ok(MDNS_GROUP),
+            "ssdp" => return Ok(SSDP_GROUP),
+            _ => {}
+        }
+
+        let (addr, port) = match s.split_once(':') {
+            Some((addr, port)) => (
+                addr,
+                Some(
+                    port.parse()
+                        .context(format!("invalid multicast port {:?}", port))?,
+                ),
+            ),
+            None => (s, None),
+        };
+
+        let addr = Ipv4Address::from_str(addr)
+            .context(format!("invalid multicast group address {:?}", addr))?;
+
+        if !addr.is_multicast() {
+            return Err(anyhow!("{} is not a multicast group address", addr));
+        }
+
+        Ok(MulticastGroup { addr, port })
+    }
+}
+
+impl MulticastGroup {
+    fn matches(&self, ipv4_pkt: &Ipv4Packet<&[u8]>) -> bool {
+        if ipv4_pkt.dst_addr() != self.addr {
+            return false;
+        }
+
+        let Some(port) = self.port else {
+            return true;
+        };
+
+        if ipv4_pkt.next_header() != IpProtocol::Udp {
+            return false;
+        }
+
+        UdpPacket::new_checked(ipv4_pkt.payload())
+            .map(|udp_pkt| udp_pkt.dst_port() == port)
+            .unwrap_or(false)
+    }
+}
+
+pub struct MulticastPolicy {
+    groups: Vec<MulticastGroup>,
+    rate_limit: Option<u32>,
+    window: FixedWindow,
+    dropped: u64,
+}
+
+impl MulticastPolicy {
+    pub fn new(groups: Vec<MulticastGroup>, rate_limit: Option<u32>) -> MulticastPolicy {
+        MulticastPolicy {
+            groups,
+            rate_limit,
+            window: FixedWindow::new(STORM_WINDOW),
+            dropped: 0,
+        }
+    }
+
+    pub fn allowed(&self, ipv4_pkt: &Ipv4Packet<&[u8]>) -> bool {
+        if self.groups.is_empty() {
+            return false;
+        }
+
+        // Keep the traffic to the groups beyond the link-local
+        // block from being routed past the host's network
+        if !is_link_local(ipv4_pkt.dst_addr()) && ipv4_pkt.hop_limit() != 1 {
+            return false;
+        }
+
+        // Let the VM manage its group memberships, otherwise
+        // the multicast traffic to the VM might not get through
+        if ipv4_pkt.next_header() == IpProtocol::Igmp {
+            let dst_addr = ipv4_pkt.dst_addr();
+
+            return dst_addr == IGMP_ALL_ROUTERS
+                || dst_addr == IGMPV3_ROUTERS
+                || self.groups.iter().any(|group| group.addr == dst_addr);
+        }
+
+        self.groups.iter().any(|group| group.matches(ipv4_pkt))
+    }
+
+    /// Accounts for a broadcast or multicast frame sent by the VM
+    /// and returns false if it exceeds the configured storm limit.
+    pub fn admit(&mut self) -> bool {
+        let Some(rate_limit) = self.rate_limit else {
+            return true;
+        };
+
+        let count = self.window.hit(coarsetime::Instant::recent());
+
+        if count > rate_limit {
+            if count == rate_limit + 1 {
+                warn!(
+                    "VM exceeded the broadcast/multicast storm limit of {rate_limit} packets per second"
+                );
+            }
+
+            self.dropped += 1;
+
+            return false;
+        }
+
+        true
+    }
+
+    pub fn log_summary(&self) {
+        if self.dropped != 0 {
+            info!(
+                "storm limit dropped {} broadcast/multicast frame(s) from the VM",
+                self.dropped
+            );
+        }
+    }
+}
+
+/// Returns true if the group belongs to the Local Network Control Block
+/// (224.0.0.0/24), which is never forwarded by routers (RFC 5771)
+fn is_link_local(addr: Ipv4Address) -> bool {
+    addr.octets()[..3] == [224, 0, 0]
+}
+
+/// Returns the Ethernet address that the IPv4 multicast group maps to (RFC 1112)
+pub fn multicast_mac(addr: Ipv4Address) -> EthernetAddress {
+    let octets = addr.octets();
+
+    EthernetAddress([0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MulticastGroup, MulticastPolicy, multicast_mac};
+    use smoltcp::wire::{EthernetAddress, IpProtocol, Ipv4Address, Ipv4Packet, UdpPacket};
+
+    #[test]
+    fn test_group_parsing() {
+        assert_eq!(
+            "mdns".parse::<MulticastGroup>().unwrap(),
+            MulticastGroup {
+                addr: Ipv4Address::new(224, 0, 0, 251),
+                port: Some(5353),
+            }
+        );
+        assert_eq!(
+            "239.1.2.3".parse::<MulticastGroup>().unwrap(),
+            MulticastGroup {
+                addr: Ipv4Address::new(239, 1, 2, 3),
+                port: None,
+            }
+        );
+        assert!("192.168.0.1".parse::<MulticastGroup>().is_err());
+        assert!("239.1.2.3:http".parse::<MulticastGroup>().is_err());
+    }
+
+    #[test]
+    fn test_allowed() {
+        let policy = MulticastPolicy::new(
+            vec!["mdns".parse().unwrap(), "239.1.2.3".parse().unwrap()],
+            None,
+        );
+
+        let buf = udp_packet(Ipv4Address::new(224, 0, 0, 251), 5353, 255);
+        assert!(policy.allowed(&Ipv4Packet::new_unchecked(buf.as_slice())));
+
+        let buf = udp_packet(Ipv4Address::new(224, 0, 0, 251), 53, 255);
+        assert!(!policy.allowed(&Ipv4Packet::new_unchecked(buf.as_slice())));
+
+        let buf = udp_packet(Ipv4Address::new(239, 1, 2, 3), 9999, 1);
+        assert!(policy.allowed(&Ipv4Packet::new_unchecked(buf.as_slice())));
+
+        let buf = udp_packet(Ipv4Address::new(239, 255, 255, 250), 1900, 1);
+        assert!(!policy.allowed(&Ipv4Packet::new_unchecked(buf.as_slice())));
+    }
+
+    #[test]
+    fn test_routable_groups_are_link_scoped() {
+        let policy = MulticastPolicy::new(vec!["239.1.2.3".parse().unwrap()], None);
+
+        let buf = udp_packet(Ipv4Address::new(239, 1, 2, 3), 9999, 64);
+        assert!(!policy.allowed(&Ipv4Packet::new_unchecked(buf.as_slice())));
+    }
+
+    #[test]
+    fn test_storm_limit() {
+        coarsetime::Instant::update();
+
+        let mut policy = MulticastPolicy::new(Vec::new(), Some(2));
+
+        assert!(policy.admit());
+        assert!(policy.admit());
+        assert!(!policy.admit());
+        assert_eq!(policy.dropped, 1);
+    }
+
+    #[test]
+    fn test_multicast_mac() {
+        assert_eq!(
+            multicast_mac(Ipv4Address::new(224, 0, 0, 251)),
+            EthernetAddress([0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb])
+        );
+        assert_eq!(
+            multicast_mac(Ipv4Address::new(239, 255, 255, 250)),
+            EthernetAddress([0x01, 0x00, 0x5e, 0x7f, 0xff, 0xfa])
+        );
+    }
+
+    fn udp_packet(dst_addr: Ipv4Address, dst_port: u16, hop_limit: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + 8];
+
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_total_len(28);
+        ipv4_pkt.set_hop_limit(hop_limit);
+        ipv4_pkt.set_next_header(IpProtocol::Udp);
+        ipv4_pkt.set_dst_addr(dst_addr);
+
+        let mut udp_pkt = UdpPacket::new_unchecked(ipv4_pkt.payload_mut());
+        udp_pkt.set_dst_port(dst_port);
+        udp_pkt.set_len(8);
+
+        buf
+    }
+}
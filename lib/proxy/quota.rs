@@ -0,0 +1,307 @@
+use crate::proxy::Proxy;
+use crate::proxy::events;
+use crate::proxy::shaper::{ByteSize, Rate, TokenBucket};
+use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use anyhow::{Context, Error, anyhow};
+use clap::ValueEnum;
+use log::info;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet, UdpPacket,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+const DEFAULT_THROTTLE_RATE: Rate = Rate(1_000_000 / 8);
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum QuotaAction {
+    /// Block the traffic that is over quota
+    #[default]
+    Block,
+    /// Throttle the traffic that is over quota to --quota-throttle-rate
+    Throttle,
+    /// Only emit an event
+    Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    /// Globally routable addresses
+    Global,
+    /// vmnet bridge gateway
+    Gateway,
+    /// DNS servers provided by the DHCP
+    Dns,
+    /// Everything else (e.g. LAN addresses)
+    Local,
+}
+
+impl fmt::Display for TrafficClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = match self {
+            TrafficClass::Global => "global",
+            TrafficClass::Gateway => "gateway",
+            TrafficClass::Dns => "dns",
+            TrafficClass::Local => "local",
+        };
+
+        write!(f, "{class}")
+    }
+}
+
+/// Byte quota either for the total traffic (when class is None) or for a specific class
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuotaSpec {
+    pub class: Option<TrafficClass>,
+    pub limit: ByteSize,
+}
+
+impl FromStr for QuotaSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((class, limit)) = s.split_once('=') else {
+            return Err(anyhow!(
+                "invalid quota specification {:?}, the format should be CLASS=SIZE",
+                s
+            ));
+        };
+
+        let class = match class {
+            "total" => None,
+            "global" => Some(TrafficClass::Global),
+            "gateway" => Some(TrafficClass::Gateway),
+            "dns" => Some(TrafficClass::Dns),
+            _ => {
+                return Err(anyhow!(
+                    "invalid quota class {:?}, expected total, global, gateway or dns",
+                    class
+                ));
+            }
+        };
+
+        let limit = limit
+            .parse()
+            .context(format!("invalid quota size {:?}", limit))?;
+
+        Ok(QuotaSpec { class, limit })
+    }
+}
+
+pub struct Quotas {
+    limits: HashMap<Option<TrafficClass>, u64>,
+    usage: HashMap<Option<TrafficClass>, u64>,
+    exceeded: HashSet<Option<TrafficClass>>,
+    action: QuotaAction,
+    throttle: TokenBucket,
+}
+
+impl Quotas {
+    pub fn new(
+        specs: Vec<QuotaSpec>,
+        action: QuotaAction,
+        throttle_rate: Option<Rate>,
+        max_frame_len: usize,
+    ) -> Quotas {
+        let throttle_rate = throttle_rate.unwrap_or(DEFAULT_THROTTLE_RATE);
+
+        Quotas {
+            limits: specs
+                .into_iter()
+                .map(|spec| (spec.class, spec.limit.0))
+                .collect(),
+            usage: HashMap::new(),
+            exceeded: HashSet::new(),
+            action,
+            throttle: TokenBucket::new(throttle_rate, ByteSize(max_frame_len as u64)),
+        }
+    }
+
+    /// Accounts the frame to the total usage and to its class usage,
+    /// returns false if the frame should be dropped
+    pub fn account(&mut self, class: TrafficClass, bytes: usize) -> bool {
+        let keys = [None, Some(class)];
+        let mut over_quota = false;
+
+        for key in keys {
+            let Some(limit) = self.limits.get(&key) else {
+                continue;
+            };
+
+            let usage = self.usage.get(&key).copied().unwrap_or_default();
+
+            if usage + bytes as u64 <= *limit && !self.exceeded.contains(&key) {
+                continue;
+            }
+
+            over_quota = true;
+
+            if self.exceeded.insert(key) {
+                events::emit(&format!(
+                    "VM exceeded its {} traffic quota of {} bytes, applying the {:?} action",
+                    key.map(|class| class.to_string())
+                        .unwrap_or("total".to_string()),
+                    limit,
+                    self.action
+                ));
+            }
+        }
+
+        let pass = !over_quota
+            || match self.action {
+                QuotaAction::Block => false,
+                QuotaAction::Throttle => self.throttle.take(bytes),
+                QuotaAction::Event => true,
+            };
+
+        // Only account for the traffic that actually went through
+        if pass {
+            for key in keys {
+                *self.usage.entry(key).or_default() += bytes as u64;
+            }
+        }
+
+        pass
+    }
+
+    pub fn log_summary(&self) {
+        let mut usage: Vec<_> = self.usage.iter().collect();
+        usage.sort_by_key(|(key, _)| key.map(|class| class.to_string()));
+
+        for (key, bytes) in usage {
+            let class = key
+                .map(|class| class.to_string())
+                .unwrap_or("total".to_string());
+
+            match self.limits.get(key) {
+                Some(limit) => info!("{class} traffic usage: {bytes} out of {limit} bytes"),
+                None => info!("{class} traffic usage: {bytes} bytes"),
+            }
+        }
+    }
+}
+
+impl Proxy<'_> {
+    /// Accounts the frame sent by the VM (egress) or to the VM (ingress)
+    /// against the quotas, returns None if the frame should be dropped
+    pub(crate) fn account_quotas(
+        &mut self,
+        frame: &EthernetFrame<&[u8]>,
+        egress: bool,
+    ) -> Option<()> {
+        let Some(class) = self.traffic_class(frame, egress) else {
+            return Some(());
+        };
+
+        self.quotas
+            .account(class, frame.as_ref().len())
+            .then_some(())
+    }
+
+    fn traffic_class(&self, frame: &EthernetFrame<&[u8]>, egress: bool) -> Option<TrafficClass> {
+        // ARP traffic is not accounted for
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return None;
+        }
+
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+        let peer = if egress {
+            ipv4_pkt.dst_addr()
+        } else {
+            ipv4_pkt.src_addr()
+        };
+
+        if ipv4_pkt.next_header() == IpProtocol::Udp
+            && let Ok(udp_pkt) = UdpPacket::new_checked(ipv4_pkt.payload())
+        {
+            // DHCP traffic is not accounted for, otherwise
+            // the VM won't be able to renew its lease
+            if udp_pkt.is_dhcp_request() || udp_pkt.is_dhcp_response() {
+                return None;
+            }
+
+            let dns = if egress {
+                udp_pkt.is_dns_request()
+            } else {
+                udp_pkt.src_port() == UdpPacket::<&[u8]>::DNS_PORT
+            };
+
+            if dns && self.dhcp_snooper.valid_dns_target(&peer) {
+                return Some(TrafficClass::Dns);
+            }
+        }
+
+        Some(classify_peer(peer, self.host.gateway_ip))
+    }
+}
+
+fn classify_peer(peer: Ipv4Address, gateway_ip: Ipv4Address) -> TrafficClass {
+    if peer == gateway_ip {
+        TrafficClass::Gateway
+    } else if ip_network::IpNetwork::from(peer).is_global() {
+        TrafficClass::Global
+    } else {
+        TrafficClass::Local
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QuotaAction, QuotaSpec, Quotas, TrafficClass};
+    use crate::proxy::shaper::ByteSize;
+
+    #[test]
+    fn test_quota_spec_parsing() {
+        assert_eq!(
+            "total=10G".parse::<QuotaSpec>().unwrap(),
+            QuotaSpec {
+                class: None,
+                limit: ByteSize(10_000_000_000),
+            }
+        );
+        assert_eq!(
+            "dns=1M".parse::<QuotaSpec>().unwrap(),
+            QuotaSpec {
+                class: Some(TrafficClass::Dns),
+                limit: ByteSize(1_000_000),
+            }
+        );
+        assert!("local=1M".parse::<QuotaSpec>().is_err());
+        assert!("total".parse::<QuotaSpec>().is_err());
+    }
+
+    #[test]
+    fn test_block_over_quota() {
+        let mut quotas = Quotas::new(
+            vec!["total=3000".parse().unwrap(), "dns=100".parse().unwrap()],
+            QuotaAction::Block,
+            None,
+            1514,
+        );
+
+        // DNS quota is exceeded, but only DNS traffic gets blocked
+        assert!(quotas.account(TrafficClass::Dns, 100));
+        assert!(!quotas.account(TrafficClass::Dns, 1));
+        assert!(quotas.account(TrafficClass::Global, 1000));
+
+        // Total quota is exceeded, now everything gets blocked
+        assert!(quotas.account(TrafficClass::Gateway, 1900));
+        assert!(!quotas.account(TrafficClass::Global, 1));
+        assert!(!quotas.account(TrafficClass::Local, 1));
+    }
+
+    #[test]
+    fn test_event_over_quota() {
+        let mut quotas = Quotas::new(
+            vec!["global=100".parse().unwrap()],
+            QuotaAction::Event,
+            None,
+            1514,
+        );
+
+        assert!(quotas.account(TrafficClass::Global, 1000));
+        assert!(quotas.account(TrafficClass::Global, 1000));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::proxy::Proxy;
+use crate::proxy::events;
+use crate::proxy::shaper::{ByteSize, Rate, TokenBucket};
+use crate::proxy::udp_packet_helper::UdpPacketHelper;
+use clap::ValueEnum;
+use log::info;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, Icmpv4Message, Icmpv4Packet, IpProtocol, Ipv4Address,
+    Ipv4Packet, TcpPacket, UdpPacket,
+};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+// Upper bound on the number of destinations tracked for the vertical scans
+const MAX_TRACKED_DESTINATIONS: usize = 1024;
+
+const THROTTLE_RATE: Rate = Rate(1_000_000 / 8);
+const THROTTLE_DURATION: Duration = Duration::from_secs(5 * 60);
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum ScanAction {
+    /// Throttle the VM's egress to 1 Mbit/s for 5 minutes
+    #[default]
+    Throttle,
+    /// Block all of the VM's egress except DHCP until softnet exits
+    Quarantine,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScanThresholds {
+    /// Distinct destination IPs per window
+    pub max_hosts: Option<u32>,
+    /// Distinct destination ports on a single destination IP per window
+    pub max_ports: Option<u32>,
+    pub window: Duration,
+}
+
+impl Default for ScanThresholds {
+    fn default() -> Self {
+        ScanThresholds {
+            max_hosts: None,
+            max_ports: None,
+            window: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ScanThresholds {
+    pub fn is_empty(&self) -> bool {
+        self.max_hosts.is_none() && self.max_ports.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scan {
+    /// Many destination IPs
+    Horizontal,
+    /// Many ports of a single destination IP
+    Vertical(Ipv4Address),
+}
+
+pub struct ScanDetector {
+    thresholds: ScanThresholds,
+    action: ScanAction,
+    window_start: coarsetime::Instant,
+    // Both are capped slightly above the thresholds to keep the memory bounded
+    hosts: HashSet<Ipv4Address>,
+    ports: HashMap<Ipv4Address, HashSet<u16>>,
+    throttle: TokenBucket,
+    throttled_until: Option<coarsetime::Instant>,
+    quarantined: bool,
+    detections: u64,
+    dropped: u64,
+}
+
+impl ScanDetector {
+    pub fn new(
+        thresholds: ScanThresholds,
+        action: ScanAction,
+        max_frame_len: usize,
+    ) -> ScanDetector {
+        ScanDetector {
+            thresholds,
+            action,
+            window_start: coarsetime::Instant::recent(),
+            hosts: HashSet::new(),
+            ports: HashMap::new(),
+            throttle: TokenBucket::new(THROTTLE_RATE, ByteSize(max_frame_len as u64)),
+            throttled_until: None,
+            quarantined: false,
+            detections: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Records a connection attempt, returns the scan it completes, if any
+    pub fn observe(&mut self, dst_addr: Ipv4Address, dst_port: Option<u16>) -> Option<Scan> {
+        let now = coarsetime::Instant::recent();
+
+        if now.duration_since(self.window_start) >= self.thresholds.window.into() {
+            self.window_start = now;
+            self.hosts.clear();
+            self.ports.clear();
+        }
+
+        if let Some(max_hosts) = self.thresholds.max_hosts
+            && self.hosts.len() <= max_hosts as usize
+            && self.hosts.insert(dst_addr)
+            && self.hosts.len() > max_hosts as usize
+        {
+            return Some(Scan::Horizontal);
+        }
+
+        if let Some(max_ports) = self.thresholds.max_ports
+            && let Some(dst_port) = dst_port
+        {
+            if !self.ports.contains_key(&dst_addr) && self.ports.len() >= MAX_TRACKED_DESTINATIONS {
+                return None;
+            }
+
+            let ports = self.ports.entry(dst_addr).or_default();
+
+            if ports.len() <= max_ports as usize
+                && ports.insert(dst_port)
+                && ports.len() > max_ports as usize
+            {
+                return Some(Scan::Vertical(dst_addr));
+            }
+        }
+
+        None
+    }
+
+    /// Reports the scan and penalizes the VM according to the action
+    pub fn penalize(&mut self, scan: Scan) {
+        self.detections += 1;
+
+        let penalty = match self.action {
+            ScanAction::Throttle => {
+                self.throttled_until =
+                    Some(coarsetime::Instant::recent() + THROTTLE_DURATION.into());
+
+                "throttling its egress"
+            }
+            ScanAction::Quarantine => {
+                self.quarantined = true;
+
+                "quarantining it"
+            }
+        };
+
+        let window = self.thresholds.window.as_secs();
+
+        let message = match scan {
+            Scan::Horizontal => format!(
+                "VM contacted more than {} distinct hosts in {} seconds, \
+                which looks like a horizontal port scan, {}",
+                self.thresholds.max_hosts.unwrap_or_default(),
+                window,
+                penalty
+            ),
+            Scan::Vertical(dst_addr) => format!(
+                "VM contacted more than {} distinct ports of {} in {} seconds, \
+                which looks like a vertical port scan, {}",
+                self.thresholds.max_ports.unwrap_or_default(),
+                dst_addr,
+                window,
+                penalty
+            ),
+        };
+
+        events::emit(&message);
+    }
+
+    /// Returns false if the frame should be dropped due to a penalty,
+    /// exempt frames (DHCP and ARP) are always let through
+    pub fn admit(&mut self, frame_len: usize, exempt: bool) -> bool {
+        // Let the VM keep its lease either way
+        if exempt {
+            return true;
+        }
+
+        if self.quarantined {
+            self.dropped += 1;
+
+            return false;
+        }
+
+        if let Some(throttled_until) = self.throttled_until {
+            if coarsetime::Instant::recent() >= throttled_until {
+                self.throttled_until = None;
+            } else if !self.throttle.take(frame_len) {
+                self.dropped += 1;
+
+                return false;
+            }
+        }
+
+        true
+    }
+
+    pub fn log_summary(&self) {
+        if self.detections != 0 {
+            info!(
+                "scan detector: detected {} scan(s), dropped {} packet(s) due to the penalties",
+                self.detections, self.dropped
+            );
+        }
+    }
+}
+
+impl Proxy<'_> {
+    /// Watches the VM's connection attempts for the signs of scanning,
+    /// returns None if the frame should be dropped due to a penalty
+    pub(crate) fn detect_scan(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
+        let Some(scan_detector) = &mut self.scan_detector else {
+            return Some(());
+        };
+
+        let (probe, exempt) = match frame.ethertype() {
+            EthernetProtocol::Ipv4 => match Ipv4Packet::new_checked(frame.payload()) {
+                Ok(ipv4_pkt) => probe(&ipv4_pkt),
+                Err(_) => (None, false),
+            },
+            _ => (None, true),
+        };
+
+        if let Some((dst_addr, dst_port)) = probe
+            && let Some(scan) = scan_detector.observe(dst_addr, dst_port)
+        {
+            scan_detector.penalize(scan);
+        }
+
+        scan_detector
+            .admit(frame.as_ref().len(), exempt)
+            .then_some(())
+    }
+}
+
+/// Returns the destination of a connection attempt (TCP SYN, UDP datagram
+/// or ICMP echo request) and whether the packet is a DHCP request
+fn probe(ipv4_pkt: &Ipv4Packet<&[u8]>) -> (Option<(Ipv4Address, Option<u16>)>, bool) {
+    let dst_addr = ipv4_pkt.dst_addr();
+
+    // Local broadcasts and multicast are not the scans we're looking for
+    if dst_addr.is_broadcast() || dst_addr.is_multicast() || ipv4_pkt.frag_offset() != 0 {
+        return (None, false);
+    }
+
+    match ipv4_pkt.next_header() {
+        IpProtocol::Tcp => match TcpPacket::new_checked(ipv4_pkt.payload()) {
+            Ok(tcp_pkt) if tcp_pkt.syn() && !tcp_pkt.ack() => {
+                (Some((dst_addr, Some(tcp_pkt.dst_port()))), false)
+            }
+            _ => (None, false),
+        },
+        IpProtocol::Udp => match UdpPacket::new_checked(ipv4_pkt.payload()) {
+            Ok(udp_pkt) if udp_pkt.is_dhcp_request() => (None, true),
+            Ok(udp_pkt) => (Some((dst_addr, Some(udp_pkt.dst_port()))), false),
+            Err(_) => (None, false),
+        },
+        IpProtocol::Icmp => match Icmpv4Packet::new_checked(ipv4_pkt.payload()) {
+            Ok(icmp_pkt) if icmp_pkt.msg_type() == Icmpv4Message::EchoRequest => {
+                (Some((dst_addr, None)), false)
+            }
+            _ => (None, false),
+        },
+        _ => (None, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_TRACKED_DESTINATIONS, Scan, ScanAction, ScanDetector, ScanThresholds};
+    use smoltcp::wire::Ipv4Address;
+
+    #[test]
+    fn test_horizontal_scan() {
+        coarsetime::Instant::update();
+
+        let mut detector = detector(Some(3), None, ScanAction::Quarantine);
+
+        for i in 1..=3 {
+            assert_eq!(
+                detector.observe(Ipv4Address::new(10, 0, 0, i), Some(22)),
+                None
+            );
+        }
+        assert_eq!(
+            detector.observe(Ipv4Address::new(10, 0, 0, 1), Some(22)),
+            None
+        );
+        assert_eq!(
+            detector.observe(Ipv4Address::new(10, 0, 0, 4), Some(22)),
+            Some(Scan::Horizontal)
+        );
+
+        // Reported once per window
+        assert_eq!(
+            detector.observe(Ipv4Address::new(10, 0, 0, 5), Some(22)),
+            None
+        );
+
+        // Quarantine only lets DHCP through
+        detector.penalize(Scan::Horizontal);
+        assert!(!detector.admit(100, false));
+        assert!(detector.admit(100, true));
+    }
+
+    #[test]
+    fn test_vertical_scan() {
+        coarsetime::Instant::update();
+
+        let mut detector = detector(None, Some(2), ScanAction::Throttle);
+        let dst_addr = Ipv4Address::new(10, 0, 0, 1);
+
+        assert_eq!(detector.observe(dst_addr, Some(22)), None);
+        assert_eq!(detector.observe(dst_addr, Some(80)), None);
+        assert_eq!(detector.observe(dst_addr, None), None);
+        assert_eq!(
+            detector.observe(Ipv4Address::new(10, 0, 0, 2), Some(443)),
+            None
+        );
+        assert_eq!(
+            detector.observe(dst_addr, Some(443)),
+            Some(Scan::Vertical(dst_addr))
+        );
+
+        // Throttling lets a limited amount of traffic through
+        detector.penalize(Scan::Vertical(dst_addr));
+        assert!(detector.admit(1500, false));
+        assert!(!detector.admit(1500, false));
+    }
+
+    #[test]
+    fn test_bounded_memory() {
+        coarsetime::Instant::update();
+
+        let mut detector = detector(Some(10), Some(10), ScanAction::Throttle);
+
+        for i in 0..(MAX_TRACKED_DESTINATIONS as u32 * 2) {
+            detector.observe(Ipv4Address::from(0x0a000000 + i), Some(22));
+        }
+
+        assert_eq!(detector.hosts.len(), 11);
+        assert_eq!(detector.ports.len(), MAX_TRACKED_DESTINATIONS);
+    }
+
+    fn detector(
+        max_hosts: Option<u32>,
+        max_ports: Option<u32>,
+        action: ScanAction,
+    ) -> ScanDetector {
+        ScanDetector::new(
+            ScanThresholds {
+                max_hosts,
+                max_ports,
+                ..Default::default()
+            },
+            action,
+            1514,
+        )
+    }
+}
@@ -0,0 +1,364 @@
+use clap::ValueEnum;
+use log::info;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket,
+};
+use std::collections::VecDeque;
+
+// Maximum number of frames held back by a scheduler across all of its queues
+const CAPACITY: usize = 256;
+
+const DNS_PORT: u16 = 53;
+const SSH_PORT: u16 = 22;
+
+// Number of frames each class may send in a row when
+// using the weighted dequeue, indexed by the Class
+const WEIGHTS: [u32; 3] = [8, 4, 1];
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SchedulingMode {
+    /// Always send the highest priority frames first,
+    /// bulk traffic may starve under a constant load
+    Strict,
+    /// Serve the queues in a weighted round-robin fashion,
+    /// favoring the higher priority frames
+    #[default]
+    Weighted,
+}
+
+/// Priority class of a frame, from the highest to the lowest
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+    /// ARP, DNS queries and replies, TCP SYNs and pure ACKs, while FINs
+    /// and RSTs stay in their connection's class to not overtake its data
+    Control = 0,
+    /// SSH and ICMP
+    Interactive = 1,
+    /// Everything else
+    Bulk = 2,
+}
+
+/// Classifies the frame sent by the VM (egress) or to the VM (ingress)
+pub fn classify(frame: &[u8], egress: bool) -> Class {
+    let Ok(frame) = EthernetFrame::new_checked(frame) else {
+        return Class::Bulk;
+    };
+
+    match frame.ethertype() {
+        EthernetProtocol::Arp => return Class::Control,
+        EthernetProtocol::Ipv4 => {}
+        _ => return Class::Bulk,
+    }
+
+    let Ok(ipv4_pkt) = Ipv4Packet::new_checked(frame.payload()) else {
+        return Class::Bulk;
+    };
+
+    match ipv4_pkt.next_header() {
+        IpProtocol::Icmp => Class::Interactive,
+        IpProtocol::Udp => match UdpPacket::new_checked(ipv4_pkt.payload()) {
+            // Only the queries sent by the VM and the replies to
+            // it, so that other traffic can't pose as DNS
+            Ok(udp_pkt)
+                if (egress && udp_pkt.dst_port() == DNS_PORT)
+                    || (!egress && udp_pkt.src_port() == DNS_PORT) =>
+            {
+                Class::Control
+            }
+            _ => Class::Bulk,
+        },
+        IpProtocol::Tcp => match TcpPacket::new_checked(ipv4_pkt.payload()) {
+            Ok(tcp_pkt)
+                if !tcp_pkt.fin()
+                    && !tcp_pkt.rst()
+                    && (tcp_pkt.syn() || tcp_pkt.payload().is_empty()) =>
+            {
+                Class::Control
+            }
+            Ok(tcp_pkt) if tcp_pkt.src_port() == SSH_PORT || tcp_pkt.dst_port() == SSH_PORT => {
+                Class::Interactive
+            }
+            _ => Class::Bulk,
+        },
+        _ => Class::Bulk,
+    }
+}
+
+/// Bounded set of per-class frame queues
+pub struct Scheduler {
+    name: &'static str,
+    // Whether the frames are sent by the VM or to the VM
+    egress: bool,
+    mode: SchedulingMode,
+    queues: [VecDeque<Vec<u8>>; 3],
+    len: usize,
+    // Weighted round-robin state: the class being
+    // served and the frames it may still send in a row
+    current: usize,
+    credit: u32,
+    dropped: u64,
+    evicted: u64,
+}
+
+impl Scheduler {
+    pub fn new(name: &'static str, egress: bool, mode: SchedulingMode) -> Scheduler {
+        Scheduler {
+            name,
+            egress,
+            mode,
+            queues: Default::default(),
+            len: 0,
+            // Start the first round with the highest priority class
+            current: WEIGHTS.len() - 1,
+            credit: 0,
+            dropped: 0,
+            evicted: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queues the frame, evicting a lower priority frame when full,
+    /// returns false if the frame was dropped instead
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        let class = classify(frame, self.egress) as usize;
+
+        if self.len >= CAPACITY {
+            let Some(victim) = (class + 1..self.queues.len())
+                .rev()
+                .find(|&victim| !self.queues[victim].is_empty())
+            else {
+                self.dropped += 1;
+
+                return false;
+            };
+
+            self.queues[victim].pop_back();
+            self.len -= 1;
+            self.evicted += 1;
+        }
+
+        self.queues[class].push_back(frame.to_vec());
+        self.len += 1;
+
+        true
+    }
+
+    /// Returns the frame that the next pop() will return
+    pub fn front(&self) -> Option<&[u8]> {
+        let class = self.next_class()?;
+
+        self.queues[class].front().map(Vec::as_slice)
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let class = self.next_class()?;
+
+        if self.mode == SchedulingMode::Weighted {
+            if class != self.current || self.credit == 0 {
+                self.current = class;
+                self.credit = WEIGHTS[class];
+            }
+
+            self.credit -= 1;
+        }
+
+        self.len -= 1;
+
+        self.queues[class].pop_front()
+    }
+
+    fn next_class(&self) -> Option<usize> {
+        let classes = self.queues.len();
+
+        match self.mode {
+            SchedulingMode::Strict => (0..classes).find(|&class| !self.queues[class].is_empty()),
+            SchedulingMode::Weighted => {
+                if self.credit > 0 && !self.queues[self.current].is_empty() {
+                    return Some(self.current);
+                }
+
+                (1..=classes)
+                    .map(|offset| (self.current + offset) % classes)
+                    .find(|&class| !self.queues[class].is_empty())
+            }
+        }
+    }
+
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    pub fn log_summary(&self) {
+        if self.dropped != 0 || self.evicted != 0 {
+            info!(
+                "{}: dropped {} frame(s) due to a full queue, evicted {} lower priority frame(s)",
+                self.name, self.dropped, self.evicted
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CAPACITY, Class, Scheduler, SchedulingMode, classify};
+    use smoltcp::wire::{
+        EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, TcpPacket, UdpPacket,
+    };
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&dns_frame(40000, 53), true), Class::Control);
+        assert_eq!(classify(&dns_frame(53, 40000), false), Class::Control);
+        assert_eq!(
+            classify(&tcp_frame(40000, 22, false, false, 100), true),
+            Class::Interactive
+        );
+        assert_eq!(
+            classify(&tcp_frame(40000, 443, false, false, 100), true),
+            Class::Bulk
+        );
+        assert_eq!(
+            classify(&tcp_frame(40000, 443, true, false, 0), true),
+            Class::Control
+        );
+        assert_eq!(
+            classify(&tcp_frame(40000, 443, false, false, 0), true),
+            Class::Control
+        );
+        assert_eq!(classify(&[0; 4], true), Class::Bulk);
+
+        // FINs stay in their connection's class
+        assert_eq!(
+            classify(&tcp_frame(40000, 443, false, true, 0), true),
+            Class::Bulk
+        );
+        assert_eq!(
+            classify(&tcp_frame(40000, 22, false, true, 0), true),
+            Class::Interactive
+        );
+
+        // Traffic posing as DNS in the wrong direction
+        assert_eq!(classify(&dns_frame(53, 40000), true), Class::Bulk);
+        assert_eq!(classify(&dns_frame(40000, 53), false), Class::Bulk);
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut scheduler = Scheduler::new("test", true, SchedulingMode::Strict);
+
+        let bulk = tcp_frame(40000, 443, false, false, 100);
+        let ssh = tcp_frame(40000, 22, false, false, 100);
+        let dns = dns_frame(40000, 53);
+
+        assert!(scheduler.push(&bulk));
+        assert!(scheduler.push(&ssh));
+        assert!(scheduler.push(&dns));
+
+        assert_eq!(scheduler.front(), Some(dns.as_slice()));
+        assert_eq!(scheduler.pop(), Some(dns));
+        assert_eq!(scheduler.pop(), Some(ssh));
+        assert_eq!(scheduler.pop(), Some(bulk));
+        assert!(scheduler.pop().is_none());
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn test_fin_is_not_reordered() {
+        let mut scheduler = Scheduler::new("test", true, SchedulingMode::Strict);
+
+        let data = tcp_frame(40000, 443, false, false, 100);
+        let fin = tcp_frame(40000, 443, false, true, 0);
+
+        assert!(scheduler.push(&data));
+        assert!(scheduler.push(&fin));
+
+        assert_eq!(scheduler.pop(), Some(data));
+        assert_eq!(scheduler.pop(), Some(fin));
+    }
+
+    #[test]
+    fn test_weighted() {
+        let mut scheduler = Scheduler::new("test", true, SchedulingMode::Weighted);
+
+        for _ in 0..10 {
+            assert!(scheduler.push(&dns_frame(40000, 53)));
+            assert!(scheduler.push(&tcp_frame(40000, 443, false, false, 100)));
+        }
+
+        // Bulk traffic gets its turn after 8 control frames
+        let classes: Vec<_> = std::iter::from_fn(|| scheduler.pop())
+            .map(|frame| classify(&frame, true))
+            .take(10)
+            .collect();
+
+        assert_eq!(classes[..8], [Class::Control; 8]);
+        assert_eq!(classes[8], Class::Bulk);
+        assert_eq!(classes[9], Class::Control);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut scheduler = Scheduler::new("test", true, SchedulingMode::Strict);
+
+        for _ in 0..CAPACITY {
+            assert!(scheduler.push(&tcp_frame(40000, 443, false, false, 100)));
+        }
+
+        // Higher priority frame evicts a bulk one, while another bulk one is dropped
+        assert!(scheduler.push(&tcp_frame(40000, 22, false, false, 100)));
+        assert!(!scheduler.push(&tcp_frame(40000, 443, false, false, 100)));
+        assert_eq!(scheduler.evicted, 1);
+        assert_eq!(scheduler.dropped, 1);
+    }
+
+    fn tcp_frame(
+        src_port: u16,
+        dst_port: u16,
+        syn: bool,
+        fin: bool,
+        payload_len: usize,
+    ) -> Vec<u8> {
+        let mut buf = vec![0u8; 14 + 20 + 20 + payload_len];
+
+        let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+        frame.set_ethertype(EthernetProtocol::Ipv4);
+
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload_mut());
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_total_len((20 + 20 + payload_len) as u16);
+        ipv4_pkt.set_next_header(IpProtocol::Tcp);
+
+        let mut tcp_pkt = TcpPacket::new_unchecked(ipv4_pkt.payload_mut());
+        tcp_pkt.set_src_port(src_port);
+        tcp_pkt.set_dst_port(dst_port);
+        tcp_pkt.set_header_len(20);
+        tcp_pkt.set_syn(syn);
+        tcp_pkt.set_fin(fin);
+
+        buf
+    }
+
+    fn dns_frame(src_port: u16, dst_port: u16) -> Vec<u8> {
+        let mut buf = vec![0u8; 14 + 20 + 8];
+
+        let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+        frame.set_ethertype(EthernetProtocol::Ipv4);
+
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(frame.payload_mut());
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_total_len(20 + 8);
+        ipv4_pkt.set_next_header(IpProtocol::Udp);
+
+        let mut udp_pkt = UdpPacket::new_unchecked(ipv4_pkt.payload_mut());
+        udp_pkt.set_src_port(src_port);
+        udp_pkt.set_dst_port(dst_port);
+        udp_pkt.set_len(8);
+
+        buf
+    }
+}
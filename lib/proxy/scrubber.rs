@@ -0,0 +1,336 @@
+use clap::ValueEnum;
+use log::info;
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, TcpPacket};
+use std::collections::HashMap;
+use std::fmt;
+
+// The smallest frame we ever expect from a VM is an ARP packet
+// (14-byte Ethernet header and 28-byte ARP payload), note that
+// guests are not required to pad frames to 60 bytes on virtual links
+const MIN_FRAME_LEN: usize = 14 + 28;
+
+// Frames padded to the minimum Ethernet frame length (60 bytes excluding FCS)
+// may carry an IPv4 packet that is shorter than the frame payload
+const MIN_ETHERNET_PAYLOAD_LEN: usize = 46;
+
+const IPV4_HEADER_LEN: u8 = 20;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ScrubCheck {
+    /// Drop runt frames and frames exceeding vmnet's max packet size
+    FrameSize,
+    /// Drop IPv4 packets with an invalid header checksum
+    Checksum,
+    /// Drop IPv4 packets carrying IP options (e.g. source routing)
+    IpOptions,
+    /// Drop IPv4 packets whose total length disagrees with the frame length
+    Length,
+    /// Drop TCP segments with illegal flag combinations (e.g. SYN+FIN)
+    TcpFlags,
+    /// Drop IPv4 packets with zero TTL
+    Ttl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    RuntFrame,
+    OversizedFrame,
+    BadChecksum,
+    IpOptions,
+    LengthMismatch,
+    IllegalTcpFlags,
+    ZeroTtl,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            DropReason::RuntFrame => "runt frame",
+            DropReason::OversizedFrame => "oversized frame",
+            DropReason::BadChecksum => "bad IPv4 header checksum",
+            DropReason::IpOptions => "IPv4 options present",
+            DropReason::LengthMismatch => "IPv4 total length disagrees with frame length",
+            DropReason::IllegalTcpFlags => "illegal TCP flag combination",
+            DropReason::ZeroTtl => "zero TTL",
+        };
+
+        write!(f, "{reason}")
+    }
+}
+
+pub struct Scrubber {
+    checks: Vec<ScrubCheck>,
+    max_frame_len: usize,
+    drops: HashMap<DropReason, u64>,
+}
+
+impl Scrubber {
+    pub fn new(checks: Vec<ScrubCheck>, max_frame_len: usize) -> Scrubber {
+        Scrubber {
+            checks,
+            max_frame_len,
+            drops: HashMap::new(),
+        }
+    }
+
+    pub fn scrub(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
+        if self.checks.is_empty() {
+            return Some(());
+        }
+
+        match self.check(frame) {
+            Ok(()) => Some(()),
+            Err(reason) => {
+                *self.drops.entry(reason).or_default() += 1;
+
+                None
+            }
+        }
+    }
+
+    pub fn log_summary(&self) {
+        for (reason, count) in &self.drops {
+            info!("scrubber dropped {count} frame(s) from the VM: {reason}");
+        }
+    }
+
+    fn enabled(&self, check: ScrubCheck) -> bool {
+        self.checks.contains(&check)
+    }
+
+    fn check(&self, frame: &EthernetFrame<&[u8]>) -> Result<(), DropReason> {
+        let frame_len = frame.as_ref().len();
+
+        if self.enabled(ScrubCheck::FrameSize) {
+            if frame_len < MIN_FRAME_LEN {
+                return Err(DropReason::RuntFrame);
+            }
+
+            if frame_len > self.max_frame_len {
+                return Err(DropReason::OversizedFrame);
+            }
+        }
+
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Ok(());
+        }
+
+        let ipv4_pkt = match Ipv4Packet::new_checked(frame.payload()) {
+            Ok(ipv4_pkt) => ipv4_pkt,
+            Err(_) => {
+                // Truncated IPv4 packets are dropped by the packet filter anyway,
+                // however we still want them to be accounted for when requested
+                if self.enabled(ScrubCheck::Length) {
+                    return Err(DropReason::LengthMismatch);
+                }
+
+                return Ok(());
+            }
+        };
+
+        if self.enabled(ScrubCheck::Checksum) && !ipv4_pkt.verify_checksum() {
+            return Err(DropReason::BadChecksum);
+        }
+
+        if self.enabled(ScrubCheck::IpOptions) && ipv4_pkt.header_len() > IPV4_HEADER_LEN {
+            return Err(DropReason::IpOptions);
+        }
+
+        if self.enabled(ScrubCheck::Length) {
+            let total_len = ipv4_pkt.total_len() as usize;
+            let payload_len = frame.payload().len();
+            let padded = payload_len == MIN_ETHERNET_PAYLOAD_LEN && total_len < payload_len;
+
+            if total_len != payload_len && !padded {
+                return Err(DropReason::LengthMismatch);
+            }
+        }
+
+        if self.enabled(ScrubCheck::Ttl) && ipv4_pkt.hop_limit() == 0 {
+            return Err(DropReason::ZeroTtl);
+        }
+
+        // Only the first fragment carries the TCP header
+        if self.enabled(ScrubCheck::TcpFlags)
+            && ipv4_pkt.next_header() == IpProtocol::Tcp
+            && ipv4_pkt.frag_offset() == 0
+            && let Ok(tcp_pkt) = TcpPacket::new_checked(ipv4_pkt.payload())
+            && !legal_tcp_flags(&tcp_pkt)
+        {
+            return Err(DropReason::IllegalTcpFlags);
+        }
+
+        Ok(())
+    }
+}
+
+fn legal_tcp_flags(tcp_pkt: &TcpPacket<&[u8]>) -> bool {
+    let (syn, fin, rst, ack) = (tcp_pkt.syn(), tcp_pkt.fin(), tcp_pkt.rst(), tcp_pkt.ack());
+
+    // NULL scan
+    if !syn && !fin && !rst && !ack {
+        return false;
+    }
+
+    // SYN can't be combined with FIN or RST, nor FIN with RST
+    if (syn && (fin || rst)) || (fin && rst) {
+        return false;
+    }
+
+    // FIN, PSH and URG are only valid on an established connection
+    if !ack && (fin || tcp_pkt.psh() || tcp_pkt.urg()) {
+        return false;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DropReason, ScrubCheck, Scrubber};
+    use smoltcp::wire::{
+        EthernetAddress, EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet,
+        TcpPacket,
+    };
+
+    const ALL_CHECKS: [ScrubCheck; 6] = [
+        ScrubCheck::FrameSize,
+        ScrubCheck::Checksum,
+        ScrubCheck::IpOptions,
+        ScrubCheck::Length,
+        ScrubCheck::TcpFlags,
+        ScrubCheck::Ttl,
+    ];
+
+    #[test]
+    fn test_well_formed_frame_passes() {
+        let buf = tcp_frame(|_, tcp_pkt| {
+            tcp_pkt.set_syn(true);
+        });
+
+        assert_eq!(check(&buf), Ok(()));
+    }
+
+    #[test]
+    fn test_disabled_checks_pass_everything() {
+        let mut scrubber = Scrubber::new(Vec::new(), 1514);
+        let buf = vec![0u8; 20];
+
+        assert!(
+            scrubber
+                .scrub(&EthernetFrame::new_unchecked(buf.as_slice()))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_frame_size() {
+        let mut buf = tcp_frame(|_, _| {});
+        buf.truncate(30);
+        assert_eq!(check(&buf), Err(DropReason::RuntFrame));
+
+        let buf = vec![0u8; 1515];
+        assert_eq!(check(&buf), Err(DropReason::OversizedFrame));
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut buf = tcp_frame(|_, tcp_pkt| tcp_pkt.set_syn(true));
+        buf[14 + 10] ^= 0xff;
+
+        assert_eq!(check(&buf), Err(DropReason::BadChecksum));
+    }
+
+    #[test]
+    fn test_ip_options() {
+        let buf = tcp_frame(|ipv4_pkt, _| {
+            ipv4_pkt.set_header_len(24);
+        });
+
+        assert_eq!(check(&buf), Err(DropReason::IpOptions));
+    }
+
+    #[test]
+    fn test_length_mismatch() {
+        let mut buf = tcp_frame(|_, tcp_pkt| tcp_pkt.set_syn(true));
+        buf.extend_from_slice(&[0; 100]);
+
+        assert_eq!(check(&buf), Err(DropReason::LengthMismatch));
+    }
+
+    #[test]
+    fn test_zero_ttl() {
+        let buf = tcp_frame(|ipv4_pkt, tcp_pkt| {
+            ipv4_pkt.set_hop_limit(0);
+            tcp_pkt.set_syn(true);
+        });
+
+        assert_eq!(check(&buf), Err(DropReason::ZeroTtl));
+    }
+
+    #[test]
+    fn test_illegal_tcp_flags() {
+        let cases: Vec<fn(&mut TcpPacket<&mut [u8]>)> = vec![
+            |_| {},
+            |tcp_pkt| {
+                tcp_pkt.set_syn(true);
+                tcp_pkt.set_fin(true);
+            },
+            |tcp_pkt| {
+                tcp_pkt.set_syn(true);
+                tcp_pkt.set_rst(true);
+            },
+            |tcp_pkt| {
+                tcp_pkt.set_fin(true);
+                tcp_pkt.set_psh(true);
+                tcp_pkt.set_urg(true);
+            },
+        ];
+
+        for case in cases {
+            let buf = tcp_frame(|_, tcp_pkt| case(tcp_pkt));
+
+            assert_eq!(check(&buf), Err(DropReason::IllegalTcpFlags));
+        }
+    }
+
+    fn check(buf: &[u8]) -> Result<(), DropReason> {
+        let scrubber = Scrubber::new(ALL_CHECKS.to_vec(), 1514);
+
+        scrubber.check(&EthernetFrame::new_unchecked(buf))
+    }
+
+    fn tcp_frame(
+        modify: impl FnOnce(&mut Ipv4Packet<&mut [u8]>, &mut TcpPacket<&mut [u8]>),
+    ) -> Vec<u8> {
+        let mut ipv4_buf = [0u8; 60];
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(&mut ipv4_buf[..]);
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_hop_limit(64);
+        ipv4_pkt.set_next_header(IpProtocol::Tcp);
+        ipv4_pkt.set_src_addr(Ipv4Address::new(192, 168, 64, 2));
+        ipv4_pkt.set_dst_addr(Ipv4Address::new(1, 1, 1, 1));
+
+        let mut tcp_buf = vec![0u8; 20];
+        let mut tcp_pkt = TcpPacket::new_unchecked(&mut tcp_buf[..]);
+        tcp_pkt.set_header_len(20);
+
+        modify(&mut ipv4_pkt, &mut tcp_pkt);
+
+        let header_len = ipv4_pkt.header_len() as usize;
+        ipv4_pkt.set_total_len((header_len + tcp_buf.len()) as u16);
+        ipv4_pkt.fill_checksum();
+
+        let mut buf = vec![0u8; 14];
+        let mut frame = EthernetFrame::new_unchecked(&mut buf[..]);
+        frame.set_src_addr(EthernetAddress([0x02, 0, 0, 0, 0, 0x01]));
+        frame.set_dst_addr(EthernetAddress([0x02, 0, 0, 0, 0, 0x02]));
+        frame.set_ethertype(EthernetProtocol::Ipv4);
+
+        buf.extend_from_slice(&ipv4_buf[..header_len]);
+        buf.extend_from_slice(&tcp_buf);
+
+        buf
+    }
+}
@@ -0,0 +1,263 @@
+use crate::proxy::scheduler::{Scheduler, SchedulingMode};
+use anyhow::{Error, anyhow};
+use log::info;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Rate in bytes per second, parsed from bits per
+/// second with an optional k, M or G suffix (e.g. 100M)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bits = parse_quantity(s)?;
+
+        if bits < 8 {
+            return Err(anyhow!("rate {:?} is too low", s));
+        }
+
+        Ok(Rate(bits / 8))
+    }
+}
+
+/// Size in bytes with an optional k, M or G suffix (e.g. 64k)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_quantity(s).map(ByteSize)
+    }
+}
+
+fn parse_quantity(s: &str) -> Result<u64, Error> {
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((idx, 'k' | 'K')) => (&s[..idx], 1_000),
+        Some((idx, 'm' | 'M')) => (&s[..idx], 1_000_000),
+        Some((idx, 'g' | 'G')) => (&s[..idx], 1_000_000_000),
+        _ => (s, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| {
+            anyhow!(
+                "invalid quantity {:?}, expected a number with an optional k, M or G suffix",
+                s
+            )
+        })
+}
+
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: coarsetime::Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, burst: ByteSize) -> TokenBucket {
+        TokenBucket {
+            rate: rate.0 as f64,
+            burst: burst.0 as f64,
+            tokens: burst.0 as f64,
+            last_refill: coarsetime::Instant::recent(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = coarsetime::Instant::recent();
+        let elapsed = now.duration_since(self.last_refill).as_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Takes the tokens for the given number of bytes, if available
+    pub fn take(&mut self, bytes: usize) -> bool {
+        self.refill();
+
+        if self.tokens < bytes as f64 {
+            return false;
+        }
+
+        self.tokens -= bytes as f64;
+
+        true
+    }
+
+    /// Returns how long it will take to accumulate tokens for the given number of bytes
+    pub fn time_until(&self, bytes: usize) -> Duration {
+        let missing = bytes as f64 - self.tokens;
+
+        if missing <= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(missing / self.rate)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Pass,
+    Queued,
+    Dropped,
+}
+
+#[derive(Default)]
+struct Stats {
+    passed_packets: u64,
+    passed_bytes: u64,
+    queued_packets: u64,
+    dropped_packets: u64,
+    dropped_bytes: u64,
+}
+
+pub struct Shaper {
+    direction: &'static str,
+    bucket: TokenBucket,
+    queue: Scheduler,
+    stats: Stats,
+}
+
+impl Shaper {
+    pub fn new(
+        direction: &'static str,
+        egress: bool,
+        rate: Rate,
+        burst: Option<ByteSize>,
+        max_frame_len: usize,
+        scheduling_mode: SchedulingMode,
+    ) -> Shaper {
+        // Default to 100ms worth of traffic and make sure that
+        // the biggest possible frame fits into the bucket
+        let burst = burst.unwrap_or(ByteSize(rate.0 / 10));
+        let burst = ByteSize(burst.0.max(max_frame_len as u64));
+
+        Shaper {
+            direction,
+            bucket: TokenBucket::new(rate, burst),
+            queue: Scheduler::new(direction, egress, scheduling_mode),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Decides whether the frame can be sent right away,
+    /// otherwise queues it for later according to its
+    /// priority or drops it when the queue is full.
+    pub fn submit(&mut self, frame: &[u8]) -> Verdict {
+        if self.queue.is_empty() && self.bucket.take(frame.len()) {
+            self.stats.passed_packets += 1;
+            self.stats.passed_bytes += frame.len() as u64;
+
+            return Verdict::Pass;
+        }
+
+        if !self.queue.push(frame) {
+            self.stats.dropped_packets += 1;
+            self.stats.dropped_bytes += frame.len() as u64;
+
+            return Verdict::Dropped;
+        }
+
+        self.stats.queued_packets += 1;
+
+        Verdict::Queued
+    }
+
+    /// Returns the next queued frame if there are enough tokens to send it
+    pub fn dequeue(&mut self) -> Option<Vec<u8>> {
+        let frame_len = self.queue.front()?.len();
+
+        if !self.bucket.take(frame_len) {
+            return None;
+        }
+
+        self.stats.passed_packets += 1;
+        self.stats.passed_bytes += frame_len as u64;
+
+        self.queue.pop()
+    }
+
+    /// Returns when the next queued frame can be sent, if any
+    pub fn next_wakeup(&self) -> Option<Duration> {
+        self.queue
+            .front()
+            .map(|frame| self.bucket.time_until(frame.len()))
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            "{} shaping: passed {} packet(s) ({} bytes), queued {} packet(s), dropped {} packet(s) ({} bytes), \
+            evicted {} lower priority packet(s)",
+            self.direction,
+            self.stats.passed_packets,
+            self.stats.passed_bytes,
+            self.stats.queued_packets,
+            self.stats.dropped_packets,
+            self.stats.dropped_bytes,
+            self.queue.evicted()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ByteSize, Rate, Shaper, Verdict};
+    use crate::proxy::scheduler::SchedulingMode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parsing() {
+        assert_eq!("8".parse::<Rate>().unwrap(), Rate(1));
+        assert_eq!("100M".parse::<Rate>().unwrap(), Rate(12_500_000));
+        assert_eq!("64k".parse::<ByteSize>().unwrap(), ByteSize(64_000));
+        assert!("1".parse::<Rate>().is_err());
+        assert!("10X".parse::<Rate>().is_err());
+        assert!("".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_shaping() {
+        coarsetime::Instant::update();
+
+        let mut shaper = Shaper::new(
+            "egress",
+            true,
+            Rate(1000),
+            Some(ByteSize(3000)),
+            1500,
+            SchedulingMode::Strict,
+        );
+
+        // Burst passes through
+        assert_eq!(shaper.submit(&[0; 1500]), Verdict::Pass);
+        assert_eq!(shaper.submit(&[0; 1500]), Verdict::Pass);
+
+        // Bucket is empty, so the frames are queued in order
+        assert_eq!(shaper.submit(&[1; 1000]), Verdict::Queued);
+        assert_eq!(shaper.submit(&[2; 10]), Verdict::Queued);
+        assert!(shaper.dequeue().is_none());
+        assert_eq!(shaper.next_wakeup(), Some(Duration::from_secs(1)));
+
+        // Queue is bounded
+        for _ in 2..256 {
+            assert_eq!(shaper.submit(&[0; 10]), Verdict::Queued);
+        }
+        assert_eq!(shaper.submit(&[0; 10]), Verdict::Dropped);
+
+        // Refill the bucket
+        shaper.bucket.tokens = 1010.0;
+        assert_eq!(shaper.dequeue(), Some(vec![1; 1000]));
+        assert_eq!(shaper.dequeue(), Some(vec![2; 10]));
+        assert!(shaper.dequeue().is_none());
+    }
+}
@@ -0,0 +1,381 @@
+use crate::proxy::Proxy;
+use crate::proxy::bounded_set::BoundedSet;
+use crate::proxy::events;
+use clap::ValueEnum;
+use log::info;
+use smoltcp::wire::{
+    EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket, UdpPacket,
+};
+use std::fmt;
+
+const DNS_PORT: u16 = 53;
+const IKE_PORT: u16 = 500;
+const IKE_NAT_T_PORT: u16 = 4500;
+
+// Upper bound on the number of detected endpoints remembered
+const MAX_ENDPOINTS: usize = 4096;
+
+const WIREGUARD_HANDSHAKE_INITIATION: u8 = 1;
+const WIREGUARD_HANDSHAKE_INITIATION_LEN: usize = 148;
+const WIREGUARD_HANDSHAKE_RESPONSE: u8 = 2;
+const WIREGUARD_HANDSHAKE_RESPONSE_LEN: usize = 92;
+
+// P_CONTROL_HARD_RESET_CLIENT_V2 and P_CONTROL_HARD_RESET_CLIENT_V3
+// opcodes in the upper 5 bits, key ID in the lower 3 bits is always 0
+const OPENVPN_HARD_RESET_CLIENT_V2: u8 = 7 << 3;
+const OPENVPN_HARD_RESET_CLIENT_V3: u8 = 10 << 3;
+// Opcode, session ID, packet ID array length and message packet ID at the very least
+const OPENVPN_MIN_LEN: usize = 1 + 8 + 1 + 4;
+const OPENVPN_MAX_RESET_LEN: usize = 512;
+// What may follow the session ID before the packet ID array: nothing, or
+// the tls-auth HMAC (SHA1 or SHA256) along with the replay packet ID and time
+const OPENVPN_TLS_AUTH_LENS: [usize; 3] = [0, 20 + 8, 32 + 8];
+
+const IKEV1_VERSION: u8 = 0x10;
+const IKEV2_VERSION: u8 = 0x20;
+const IKE_HEADER_LEN: usize = 28;
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq)]
+pub enum TunnelPolicy {
+    /// Don't look for tunnels
+    #[default]
+    Ignore,
+    /// Emit an event when the VM establishes a tunnel
+    Report,
+    /// Emit an event and block tunnels, even to the allowed destinations
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tunnel {
+    WireGuard,
+    OpenVpn,
+    Ipsec,
+    Gre,
+}
+
+impl fmt::Display for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tunnel = match self {
+            Tunnel::WireGuard => "WireGuard",
+            Tunnel::OpenVpn => "OpenVPN",
+            Tunnel::Ipsec => "IPsec",
+            Tunnel::Gre => "GRE",
+        };
+
+        write!(f, "{tunnel}")
+    }
+}
+
+/// Endpoint of a detected tunnel, the port is
+/// absent for tunnels that run directly over IP
+type Endpoint = (Ipv4Address, IpProtocol, Option<u16>);
+
+pub struct TunnelDetector {
+    policy: TunnelPolicy,
+    endpoints: BoundedSet<Endpoint>,
+    detections: u64,
+    dropped: u64,
+}
+
+impl TunnelDetector {
+    pub fn new(policy: TunnelPolicy) -> TunnelDetector {
+        TunnelDetector {
+            policy,
+            endpoints: BoundedSet::new(MAX_ENDPOINTS),
+            detections: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Inspects an IPv4 packet sent by the VM, returns
+    /// false if it belongs to a tunnel that is blocked
+    pub fn inspect(&mut self, ipv4_pkt: &Ipv4Packet<&[u8]>) -> bool {
+        let Some((tunnel, endpoint)) = fingerprint(ipv4_pkt) else {
+            return !self.blocked(&endpoint(ipv4_pkt));
+        };
+
+        if self.endpoints.insert(endpoint) {
+            self.detections += 1;
+
+            let (addr, _, port) = endpoint;
+            let destination = match port {
+                Some(port) => format!("{addr}:{port}"),
+                None => addr.to_string(),
+            };
+
+            let response = if self.policy == TunnelPolicy::Block {
+                ", blocking the endpoint"
+            } else {
+                ""
+            };
+
+            events::emit(&format!(
+                "VM is tunnelling through {tunnel} to {destination}{response}"
+            ));
+        }
+
+        !self.blocked(&endpoint)
+    }
+
+    fn blocked(&mut self, endpoint: &Endpoint) -> bool {
+        if self.policy != TunnelPolicy::Block || !self.endpoints.contains(endpoint) {
+            return false;
+        }
+
+        self.dropped += 1;
+
+        true
+    }
+
+    pub fn log_summary(&self) {
+        if self.detections != 0 {
+            info!(
+                "tunnel detector: detected {} tunnel endpoint(s), dropped {} packet(s)",
+                self.detections, self.dropped
+            );
+        }
+    }
+}
+
+fn endpoint(ipv4_pkt: &Ipv4Packet<&[u8]>) -> Endpoint {
+    let protocol = ipv4_pkt.next_header();
+
+    let port = match protocol {
+        IpProtocol::Tcp => TcpPacket::new_checked(ipv4_pkt.payload())
+            .ok()
+            .map(|tcp_pkt| tcp_pkt.dst_port()),
+        IpProtocol::Udp => UdpPacket::new_checked(ipv4_pkt.payload())
+            .ok()
+            .map(|udp_pkt| udp_pkt.dst_port()),
+        _ => None,
+    };
+
+    (ipv4_pkt.dst_addr(), protocol, port)
+}
+
+/// Recognizes the tunnelling protocols by their IP protocol
+/// numbers and the shapes of their handshake messages
+fn fingerprint(ipv4_pkt: &Ipv4Packet<&[u8]>) -> Option<(Tunnel, Endpoint)> {
+    // Only the first fragment carries the transport header
+    if ipv4_pkt.frag_offset() != 0 {
+        return None;
+    }
+
+    let endpoint = endpoint(ipv4_pkt);
+
+    let tunnel = match ipv4_pkt.next_header() {
+        IpProtocol::Unknown(47) => Tunnel::Gre,
+        IpProtocol::IpSecEsp | IpProtocol::IpSecAh => Tunnel::Ipsec,
+        IpProtocol::Udp => {
+            let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+            fingerprint_udp(udp_pkt.dst_port(), udp_pkt.payload())?
+        }
+        IpProtocol::Tcp => {
+            let tcp_pkt = TcpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+            fingerprint_tcp(tcp_pkt.payload())?
+        }
+        _ => return None,
+    };
+
+    Some((tunnel, endpoint))
+}
+
+fn fingerprint_udp(dst_port: u16, payload: &[u8]) -> Option<Tunnel> {
+    // IKE, possibly prefixed with a non-ESP marker when NAT-traversal is used
+    let ike = match dst_port {
+        IKE_PORT => Some(payload),
+        IKE_NAT_T_PORT => payload.strip_prefix(&[0, 0, 0, 0]),
+        _ => None,
+    };
+
+    if let Some(ike) = ike
+        && ike.len() >= IKE_HEADER_LEN
+        && matches!(ike[17], IKEV1_VERSION | IKEV2_VERSION)
+    {
+        return Some(Tunnel::Ipsec);
+    }
+
+    // WireGuard handshake messages have a fixed size and three reserved zero bytes
+    if let [message_type, 0, 0, 0, ..] = payload {
+        match (*message_type, payload.len()) {
+            (WIREGUARD_HANDSHAKE_INITIATION, WIREGUARD_HANDSHAKE_INITIATION_LEN)
+            | (WIREGUARD_HANDSHAKE_RESPONSE, WIREGUARD_HANDSHAKE_RESPONSE_LEN) => {
+                return Some(Tunnel::WireGuard);
+            }
+            _ => {}
+        }
+    }
+
+    // DNS queries are frequent and start with a random ID,
+    // so they'd eventually match by a sheer coincidence
+    if dst_port != DNS_PORT && openvpn_hard_reset(payload) {
+        return Some(Tunnel::OpenVpn);
+    }
+
+    None
+}
+
+fn fingerprint_tcp(payload: &[u8]) -> Option<Tunnel> {
+    // OpenVPN over TCP prefixes each packet with its length
+    if let [len_hi, len_lo, packet @ ..] = payload
+        && u16::from_be_bytes([*len_hi, *len_lo]) as usize == packet.len()
+        && openvpn_hard_reset(packet)
+    {
+        return Some(Tunnel::OpenVpn);
+    }
+
+    None
+}
+
+/// Client's initial packet, which opens an OpenVPN session
+fn openvpn_hard_reset(packet: &[u8]) -> bool {
+    // Session ID is random, so it's unlikely to be zero
+    if !(OPENVPN_MIN_LEN..=OPENVPN_MAX_RESET_LEN).contains(&packet.len()) || packet[1..9] == [0; 8]
+    {
+        return false;
+    }
+
+    match packet[0] {
+        // Nothing to acknowledge yet and the very first message packet ID
+        OPENVPN_HARD_RESET_CLIENT_V2 => OPENVPN_TLS_AUTH_LENS.iter().any(|tls_auth_len| {
+            let offset = 9 + tls_auth_len;
+
+            packet.get(offset..offset + 5) == Some(&[0; 5])
+        }),
+        // tls-crypt-v2 encrypts the rest, but the replay packet ID starts at 1
+        OPENVPN_HARD_RESET_CLIENT_V3 => packet[9..13] == [0, 0, 0, 1],
+        _ => false,
+    }
+}
+
+impl Proxy<'_> {
+    /// Looks for the tunnels established by the VM, returns
+    /// None if the frame belongs to a blocked tunnel
+    pub(crate) fn detect_tunnel(&mut self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
+        let Some(tunnel_detector) = &mut self.tunnel_detector else {
+            return Some(());
+        };
+
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return Some(());
+        }
+
+        let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+        tunnel_detector.inspect(&ipv4_pkt).then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tunnel, TunnelDetector, TunnelPolicy, fingerprint_tcp, fingerprint_udp};
+    use smoltcp::wire::{IpProtocol, Ipv4Address, Ipv4Packet, UdpPacket};
+
+    #[test]
+    fn test_wireguard() {
+        let mut initiation = vec![0u8; 148];
+        initiation[0] = 1;
+        initiation[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(fingerprint_udp(51820, &initiation), Some(Tunnel::WireGuard));
+
+        // Wrong size
+        assert_eq!(fingerprint_udp(51820, &initiation[..100]), None);
+    }
+
+    #[test]
+    fn test_openvpn() {
+        let mut reset = vec![0x38, 1, 2, 3, 4, 5, 6, 7, 8, 0];
+        reset.extend_from_slice(&[0; 4]);
+        assert_eq!(fingerprint_udp(1194, &reset), Some(Tunnel::OpenVpn));
+
+        let mut tcp = (reset.len() as u16).to_be_bytes().to_vec();
+        tcp.extend_from_slice(&reset);
+        assert_eq!(fingerprint_tcp(&tcp), Some(Tunnel::OpenVpn));
+
+        // With tls-auth
+        let mut tls_auth = vec![0x38, 1, 2, 3, 4, 5, 6, 7, 8];
+        tls_auth.extend_from_slice(&[0xaa; 20]);
+        tls_auth.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x4a, 0x3b, 0x2c]);
+        tls_auth.extend_from_slice(&[0; 5]);
+        assert_eq!(fingerprint_udp(1194, &tls_auth), Some(Tunnel::OpenVpn));
+
+        // Not length-prefixed
+        assert_eq!(fingerprint_tcp(&reset), None);
+        assert_eq!(fingerprint_tcp(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_dns_query_is_not_openvpn() {
+        // Query for example.com with an ID that looks like a hard reset opcode
+        let mut query = vec![0x38, 0x12, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x07example\x03com\x00");
+        query.extend_from_slice(&[0, 1, 0, 1]);
+
+        assert_eq!(fingerprint_udp(53, &query), None);
+        assert_eq!(fingerprint_udp(5353, &query), None);
+
+        let mut detector = TunnelDetector::new(TunnelPolicy::Block);
+        let dns = udp_packet(53, &query);
+        assert!(detector.inspect(&Ipv4Packet::new_unchecked(dns.as_slice())));
+        assert!(detector.inspect(&Ipv4Packet::new_unchecked(dns.as_slice())));
+    }
+
+    #[test]
+    fn test_ike() {
+        let mut ike = vec![0u8; 28];
+        ike[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        ike[17] = 0x20;
+        ike[18] = 34;
+        assert_eq!(fingerprint_udp(500, &ike), Some(Tunnel::Ipsec));
+
+        let mut nat_t = vec![0u8; 4];
+        nat_t.extend_from_slice(&ike);
+        assert_eq!(fingerprint_udp(4500, &nat_t), Some(Tunnel::Ipsec));
+
+        // DNS query to port 500 is not IKE
+        assert_eq!(fingerprint_udp(500, &[0x12, 0x34, 0x01, 0x00]), None);
+    }
+
+    #[test]
+    fn test_block_policy() {
+        let mut detector = TunnelDetector::new(TunnelPolicy::Block);
+
+        let mut initiation = vec![0u8; 148];
+        initiation[0] = 1;
+        let handshake = udp_packet(51820, &initiation);
+        let transport = udp_packet(51820, &[4, 0, 0, 0, 1, 2, 3, 4]);
+        let dns = udp_packet(53, &[0x12, 0x34, 0x01, 0x00]);
+
+        // Transport data is blocked after the handshake is detected
+        assert!(detector.inspect(&Ipv4Packet::new_unchecked(transport.as_slice())));
+        assert!(!detector.inspect(&Ipv4Packet::new_unchecked(handshake.as_slice())));
+        assert!(!detector.inspect(&Ipv4Packet::new_unchecked(transport.as_slice())));
+        assert!(detector.inspect(&Ipv4Packet::new_unchecked(dns.as_slice())));
+
+        // Reporting doesn't block
+        let mut detector = TunnelDetector::new(TunnelPolicy::Report);
+        assert!(detector.inspect(&Ipv4Packet::new_unchecked(handshake.as_slice())));
+        assert_eq!(detector.detections, 1);
+    }
+
+    fn udp_packet(dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + 8 + payload.len()];
+
+        let mut ipv4_pkt = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ipv4_pkt.set_version(4);
+        ipv4_pkt.set_header_len(20);
+        ipv4_pkt.set_total_len((20 + 8 + payload.len()) as u16);
+        ipv4_pkt.set_next_header(IpProtocol::Udp);
+        ipv4_pkt.set_dst_addr(Ipv4Address::new(203, 0, 113, 1));
+
+        let mut udp_pkt = UdpPacket::new_unchecked(ipv4_pkt.payload_mut());
+        udp_pkt.set_dst_port(dst_port);
+        udp_pkt.set_len((8 + payload.len()) as u16);
+        udp_pkt.payload_mut().copy_from_slice(payload);
+
+        buf
+    }
+}
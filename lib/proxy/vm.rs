@@ -1,26 +1,179 @@
+use crate::proxy::dhcp_server::{BOOTPC_PORT, BOOTPS_PORT, craft};
+use crate::proxy::geoip::most_specific;
+use crate::proxy::multicast::multicast_mac;
+use crate::proxy::shaper::Verdict;
 use crate::proxy::udp_packet_helper::UdpPacketHelper;
 use crate::proxy::{Action, Proxy};
 use anyhow::Context;
 use anyhow::Result;
+use dhcproto::Encodable;
 use ipnet::Ipv4Net;
 use smoltcp::wire::{
-    ArpPacket, EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, UdpPacket,
+    ArpPacket, EthernetAddress, EthernetFrame, EthernetProtocol, IpProtocol, Ipv4Packet, UdpPacket,
 };
 use std::net::Ipv4Addr;
 
 impl Proxy<'_> {
     pub(crate) fn process_frame_from_vm(&mut self, frame: EthernetFrame<&[u8]>) -> Result<()> {
+        // Normalize the traffic before evaluating the policy
+        if self.scrubber.scrub(&frame).is_none() {
+            return Ok(());
+        }
+
         if self.allowed_from_vm(&frame).is_none() {
             // Block packet by not forwarding it to the host
             return Ok(());
         }
 
+        // Keep the VM from exhausting the DHCP server's pool
+        if self.guard_dhcp(&frame).is_none() {
+            return Ok(());
+        }
+
+        // Keep track of the VM's DHCP transactions to
+        // only accept the replies that it asked for
+        self.snoop_dhcp_request(&frame);
+
+        // Answer the VM's DHCP ourselves when running the built-in server
+        if self.serve_dhcp(&frame)?.is_none() {
+            return Ok(());
+        }
+
+        // Cap the broadcast/multicast packets rate to prevent storms on the bridge
+        if frame.dst_addr().is_multicast() && !self.multicast.admit() {
+            return Ok(());
+        }
+
+        if self.detect_mining(&frame).is_none() {
+            return Ok(());
+        }
+
+        // Tunnels are blocked even to the allowed destinations
+        if self.detect_tunnel(&frame).is_none() {
+            return Ok(());
+        }
+
+        // Queries to the DHCP-provided resolvers are implicitly allowed,
+        // make sure they're not used as an exfiltration channel
+        if self.detect_dns_tunnel(&frame).is_none() {
+            return Ok(());
+        }
+
+        if self.detect_scan(&frame).is_none() {
+            return Ok(());
+        }
+
+        if self.detect_flood(&frame).is_none() {
+            return Ok(());
+        }
+
+        // Protect the host's NAT table from being exhausted
+        if self.track_flow(&frame)?.is_none() {
+            return Ok(());
+        }
+
+        if self.enforce_mtu(&frame)?.is_none() {
+            return Ok(());
+        }
+
+        self.egress_to_host(frame)
+    }
+
+    /// Sends the VM's frame that fits the MTU on its way to the host,
+    /// the oversized ones get here once fragmented
+    pub(crate) fn egress_to_host(&mut self, frame: EthernetFrame<&[u8]>) -> Result<()> {
+        // Make sure that the TCP connections initiated by the VM fit the MTU
+        let clamped = self.clamp_mss(&frame);
+        let frame = match &clamped {
+            Some(buf) => EthernetFrame::new_unchecked(buf.as_slice()),
+            None => frame,
+        };
+
+        if self.account_quotas(&frame, true).is_none() {
+            return Ok(());
+        }
+
+        if let Some(impairer) = &mut self.egress_impairer {
+            impairer.submit(frame.as_ref());
+
+            return Ok(());
+        }
+
+        self.forward_to_host(frame.as_ref())
+    }
+
+    /// Writes the frame to the host, unless the egress shaper holds it back
+    pub(crate) fn forward_to_host(&mut self, frame: &[u8]) -> Result<()> {
+        if let Some(shaper) = &mut self.egress_shaper
+            && shaper.submit(frame) != Verdict::Pass
+        {
+            return Ok(());
+        }
+
+        self.write_to_host(frame)
+    }
+
+    pub(crate) fn write_to_host(&mut self, frame: &[u8]) -> Result<()> {
         self.host
-            .write(frame.as_ref())
+            .write(frame)
             .map(|_| ())
             .context("failed to write to the host")
     }
 
+    /// Releases the VM's lease on its behalf, since ephemeral VMs are
+    /// usually killed without getting a chance to send DHCPRELEASE
+    pub(crate) fn release_dhcp_lease(&mut self) -> Result<()> {
+        let Some((release, server)) = self.dhcp_snooper.release(self.host.gateway_ip) else {
+            return Ok(());
+        };
+
+        // Built-in DHCP server keeps its leases in the lease file
+        if let Some(dhcp_server) = &mut self.dhcp_server {
+            dhcp_server.handle(self.vm_mac_address, &release)?;
+
+            return Ok(());
+        }
+
+        let payload = release.to_vec().context("failed to encode DHCP release")?;
+        let frame = craft(
+            self.vm_mac_address,
+            self.gateway_mac_address
+                .unwrap_or(EthernetAddress::BROADCAST),
+            release.ciaddr(),
+            server,
+            BOOTPC_PORT,
+            BOOTPS_PORT,
+            &payload,
+        );
+
+        self.write_to_host(&frame)
+    }
+
+    fn snoop_dhcp_request(&mut self, frame: &EthernetFrame<&[u8]>) {
+        if frame.ethertype() != EthernetProtocol::Ipv4 {
+            return;
+        }
+
+        let Ok(ipv4_pkt) = Ipv4Packet::new_checked(frame.payload()) else {
+            return;
+        };
+
+        if ipv4_pkt.next_header() != IpProtocol::Udp {
+            return;
+        }
+
+        let Ok(udp_pkt) = UdpPacket::new_checked(ipv4_pkt.payload()) else {
+            return;
+        };
+
+        if udp_pkt.is_dhcp_request() && self.dhcp_snooper.register_dhcp_request(udp_pkt.payload()) {
+            // Don't wait for the next tick to stop forwarding
+            // the ports to an address that the VM gave up
+            self.port_forwarder
+                .tick(&mut self.host, self.dhcp_snooper.lease());
+        }
+    }
+
     fn allowed_from_vm(&self, frame: &EthernetFrame<&[u8]>) -> Option<()> {
         if frame.src_addr() != self.vm_mac_address {
             return None;
@@ -33,6 +186,19 @@ impl Proxy<'_> {
             }
             EthernetProtocol::Ipv4 => {
                 let ipv4_pkt = Ipv4Packet::new_checked(frame.payload()).ok()?;
+
+                // Multicast frames must be addressed to the Ethernet
+                // address that corresponds to their IPv4 group to stay
+                // on the local bridge
+                let dst_addr = frame.dst_addr();
+
+                if dst_addr.is_multicast()
+                    && !dst_addr.is_broadcast()
+                    && dst_addr != multicast_mac(ipv4_pkt.dst_addr())
+                {
+                    return None;
+                }
+
                 self.allowed_from_vm_ipv4(ipv4_pkt)
             }
             _ => None,
@@ -59,17 +225,51 @@ impl Proxy<'_> {
     }
 
     pub(crate) fn allowed_from_vm_ipv4(&self, ipv4_pkt: Ipv4Packet<&[u8]>) -> Option<()> {
+        if ipv4_pkt.next_header() == IpProtocol::Udp {
+            let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).ok()?;
+
+            if udp_pkt.is_dhcp_request() {
+                // Allow DHCP communication with the bootpd(8) on host via broadcast address,
+                // otherwise DHCP snooper will never be populated
+                if ipv4_pkt.dst_addr().is_broadcast() {
+                    return Some(());
+                }
+
+                // Allow the renewals unicast to the server that granted the lease
+                // regardless of the rules and of the lease being about to expire
+                if self
+                    .dhcp_snooper
+                    .valid_dhcp_target(ipv4_pkt.src_addr(), ipv4_pkt.dst_addr())
+                {
+                    return Some(());
+                }
+            }
+        }
+
         // Is this packet coming from VM's IP address that we've learned from DHCP snooping?
         if let Some(lease) = &self.dhcp_snooper.lease()
             && lease.valid_ip_source(ipv4_pkt.src_addr())
         {
             let dst_addr = ipv4_pkt.dst_addr();
 
-            // Filter traffic based on user-specified rules first
-            if !self.rules.is_empty() {
+            // Allow multicast traffic to the explicitly permitted groups
+            if dst_addr.is_multicast() && self.multicast.allowed(&ipv4_pkt) {
+                return Some(());
+            }
+
+            // Filter traffic based on user-specified rules first,
+            // ASN and country rules are weighed against the CIDR
+            // rules using the prefix length of the database network
+            if !self.rules.is_empty() || !self.geoip.is_empty() {
                 let dst_net = Ipv4Net::from(dst_addr);
 
-                if let Some((_, action)) = self.rules.get_lpm(&dst_net) {
+                let prefix_match = self
+                    .rules
+                    .get_lpm(&dst_net)
+                    .map(|(prefix, action)| (prefix.prefix_len(), *action));
+
+                if let Some((_, action)) = most_specific(prefix_match, self.geoip.lookup(dst_addr))
+                {
                     return match action {
                         Action::Allow => Some(()),
                         Action::Block => None,
@@ -101,17 +301,6 @@ impl Proxy<'_> {
             }
         }
 
-        // Allow outgoing DHCP requests to broadcast addresses,
-        // otherwise DHCP snooper will never be populated
-        if ipv4_pkt.next_header() == IpProtocol::Udp {
-            let udp_pkt = UdpPacket::new_checked(ipv4_pkt.payload()).ok()?;
-
-            // Allow DHCP communication with the bootpd(8) on host via broadcast address
-            if udp_pkt.is_dhcp_request() && ipv4_pkt.dst_addr().is_broadcast() {
-                return Some(());
-            }
-        }
-
         None
     }
 }
@@ -5,15 +5,37 @@ use nix::sys::signal::{SigHandler, Signal, signal};
 use oslog::OsLogger;
 use privdrop::PrivDrop;
 use softnet::NetType;
+use softnet::proxy::ByteSize;
+use softnet::proxy::DhcpPool;
 use softnet::proxy::ExposedPort;
+use softnet::proxy::FloodAction;
+use softnet::proxy::FloodThresholds;
+use softnet::proxy::FlowLimitAction;
+use softnet::proxy::FlowLimits;
+use softnet::proxy::Impairment;
+use softnet::proxy::IpSetSpec;
+use softnet::proxy::MulticastGroup;
+use softnet::proxy::Options;
 use softnet::proxy::Proxy;
+use softnet::proxy::QuotaAction;
+use softnet::proxy::QuotaSpec;
+use softnet::proxy::Rate;
+use softnet::proxy::ScanAction;
+use softnet::proxy::ScanThresholds;
+use softnet::proxy::SchedulingMode;
+use softnet::proxy::ScrubCheck;
+use softnet::proxy::StaticRoute;
 use softnet::proxy::Target;
+use softnet::proxy::TunnelPolicy;
 use std::borrow::Cow;
 use std::env;
+use std::net::Ipv4Addr;
 use std::os::raw::c_int;
 use std::os::unix::io::RawFd;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::{Command, ExitCode};
+use std::time::Duration;
 use system_configuration::core_foundation::base::TCFType;
 use system_configuration::core_foundation::dictionary::CFDictionary;
 use system_configuration::core_foundation::number::CFNumber;
@@ -53,8 +75,12 @@ struct Args {
         long,
         help = "Comma-separated list of CIDRs to allow the traffic to \
         (e.g. --allow=192.168.0.0/24 may be used to allow a LAN access for a VM), \
-        plus supported @-aliases. Currently the only supported @-alias is @host, \
-        which matches the vmnet bridge gateway IP. \
+        plus supported @-aliases. Currently the supported @-aliases are @host, \
+        which matches the vmnet bridge gateway IP, and @set:NAME, which matches \
+        the prefixes of an IP set defined with --ip-set. \
+        Additionally, asn:NUMBER and geo:COUNTRY targets (e.g. asn:15169 or geo:US) \
+        match destinations by their autonomous system number or country \
+        using the databases specified in --asn-database and --geoip-database. \
         When used with --block, the longest prefix match always wins. \
         In case an identical prefix is both --allow'ed and --block'ed, \
         blocking will take precedence. --allow=0.0.0.0/0 is a special case, \
@@ -70,7 +96,11 @@ struct Args {
         help = "Comma-separated list of CIDRs to block the traffic to \
         (e.g. --block=0.0.0.0/0 may be used to establish a default deny policy \
         that is further relaxed with --allow), plus supported @-aliases. \
-        Currently the only supported @-alias is @host, which matches the vmnet bridge gateway IP. \
+        Currently the supported @-aliases are @host, which matches the vmnet bridge gateway IP, \
+        and @set:NAME, which matches the prefixes of an IP set defined with --ip-set. \
+        Additionally, asn:NUMBER and geo:COUNTRY targets (e.g. asn:15169 or geo:US) \
+        match destinations by their autonomous system number or country \
+        using the databases specified in --asn-database and --geoip-database. \
         When used with --allow, the longest prefix match always wins. \
         In case an identical prefix is both --allow'ed and --block'ed, \
         blocking will take precedence.",
@@ -80,6 +110,33 @@ struct Args {
     )]
     block: Vec<Target>,
 
+    #[clap(
+        long,
+        help = "comma-separated list of named IP sets to load from files \
+        (e.g. --ip-set=github-actions=/etc/softnet/github-actions.txt), \
+        which can then be referenced in --allow and --block as @set:NAME. \
+        Each file should either contain one CIDR per line or a JSON array of CIDRs. \
+        The files are watched for changes and a broken update keeps the last good set.",
+        value_name = "comma-separated NAME=PATH specifications",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set
+    )]
+    ip_set: Vec<IpSetSpec>,
+
+    #[clap(
+        long,
+        help = "path to a local MaxMind-format country database (e.g. GeoLite2-Country.mmdb) \
+        used to resolve geo:COUNTRY targets"
+    )]
+    geoip_database: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "path to a local MaxMind-format ASN database (e.g. GeoLite2-ASN.mmdb) \
+        used to resolve asn:NUMBER targets"
+    )]
+    asn_database: Option<PathBuf>,
+
     #[clap(
         long,
         help = "comma-separated list of TCP ports to expose (e.g. --expose 2222:22,8080:80)",
@@ -89,6 +146,410 @@ struct Args {
     )]
     expose: Vec<ExposedPort>,
 
+    #[clap(
+        long,
+        help = "comma-separated list of multicast groups the VM is allowed to send to \
+        on the local bridge, either mdns (224.0.0.251:5353), ssdp (239.255.255.250:1900) \
+        or an arbitrary GROUP[:PORT] (e.g. --allow-multicast=mdns,239.1.2.3:5000), \
+        packets to the groups outside of 224.0.0.0/24 must have a TTL of 1",
+        value_name = "comma-separated multicast groups",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set
+    )]
+    allow_multicast: Vec<MulticastGroup>,
+
+    #[clap(
+        long,
+        help = "maximum number of broadcast and multicast packets per second \
+        the VM is allowed to send, the excess packets are dropped",
+        value_name = "packets per second"
+    )]
+    multicast_rate_limit: Option<u32>,
+
+    #[clap(
+        long,
+        help = "limit the VM's egress bandwidth to this rate in bits per second \
+        with an optional k, M or G suffix (e.g. --egress-rate=100M), \
+        the excess frames are queued up to a bound and then dropped",
+        value_name = "rate"
+    )]
+    egress_rate: Option<Rate>,
+
+    #[clap(
+        long,
+        help = "burst size in bytes with an optional k, M or G suffix (e.g. --egress-burst=256k) \
+        for --egress-rate, defaults to 100 milliseconds worth of traffic",
+        value_name = "size"
+    )]
+    egress_burst: Option<ByteSize>,
+
+    #[clap(
+        long,
+        help = "limit the VM's ingress bandwidth to this rate in bits per second \
+        with an optional k, M or G suffix (e.g. --ingress-rate=100M), \
+        the excess frames are queued up to a bound and then dropped",
+        value_name = "rate"
+    )]
+    ingress_rate: Option<Rate>,
+
+    #[clap(
+        long,
+        help = "burst size in bytes with an optional k, M or G suffix (e.g. --ingress-burst=256k) \
+        for --ingress-rate, defaults to 100 milliseconds worth of traffic",
+        value_name = "size"
+    )]
+    ingress_burst: Option<ByteSize>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "how to dequeue the frames held back by --egress-rate, --ingress-rate \
+        or a congested VM socket: control traffic (ARP, DNS, TCP SYNs and pure ACKs) \
+        goes first, followed by the interactive traffic (SSH and ICMP) and then the bulk traffic",
+        default_value_t = SchedulingMode::Weighted
+    )]
+    scheduling: SchedulingMode,
+
+    #[clap(
+        long,
+        help = "comma-separated list of byte quotas for the VM's traffic in both directions, \
+        either for the total traffic or for a specific destination class: global, gateway or dns \
+        (e.g. --quota=total=10G,dns=100M), the sizes accept an optional k, M or G suffix",
+        value_name = "comma-separated CLASS=SIZE specifications",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set
+    )]
+    quota: Vec<QuotaSpec>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "action to take when the VM exceeds any of its --quota's",
+        default_value_t = QuotaAction::Block
+    )]
+    quota_action: QuotaAction,
+
+    #[clap(
+        long,
+        help = "rate in bits per second with an optional k, M or G suffix \
+        to throttle the over-quota traffic to when --quota-action=throttle, defaults to 1M",
+        value_name = "rate"
+    )]
+    quota_throttle_rate: Option<Rate>,
+
+    #[clap(
+        long,
+        help = "impair the VM's egress traffic similarly to netem(8) with comma-separated \
+        delay=DURATION, jitter=DURATION, loss=PERCENT, reorder=PERCENT, duplicate=PERCENT \
+        and corrupt=PERCENT impairments (e.g. --impair-egress=delay=100ms,jitter=10ms,loss=1%), \
+        reordered frames are sent without the delay",
+        value_name = "impairments"
+    )]
+    impair_egress: Option<Impairment>,
+
+    #[clap(
+        long,
+        help = "impair the VM's ingress traffic, see --impair-egress for the format",
+        value_name = "impairments"
+    )]
+    impair_ingress: Option<Impairment>,
+
+    #[clap(
+        long,
+        help = "seed for the random number generator that drives --impair-egress \
+        and --impair-ingress, the runs with the same seed are reproducible",
+        value_name = "seed"
+    )]
+    impair_seed: Option<u64>,
+
+    #[clap(
+        long,
+        help = "maximum number of new outbound TCP connections and UDP flows \
+        per second the VM is allowed to open",
+        value_name = "flows per second"
+    )]
+    max_new_flows: Option<u32>,
+
+    #[clap(
+        long,
+        help = "maximum number of concurrent outbound TCP connections and UDP flows \
+        the VM is allowed to have",
+        value_name = "flows"
+    )]
+    max_flows: Option<u32>,
+
+    #[clap(
+        long,
+        help = "maximum number of concurrent outbound TCP connections and UDP flows \
+        the VM is allowed to have to a single destination IP",
+        value_name = "flows"
+    )]
+    max_flows_per_destination: Option<u32>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "action to take on the new flows exceeding --max-new-flows, \
+        --max-flows or --max-flows-per-destination",
+        default_value_t = FlowLimitAction::Drop
+    )]
+    flow_limit_action: FlowLimitAction,
+
+    #[clap(
+        long,
+        help = "number of distinct destination IPs the VM may contact within --scan-window \
+        before it's considered to be doing a horizontal port scan",
+        value_name = "hosts"
+    )]
+    scan_max_hosts: Option<u32>,
+
+    #[clap(
+        long,
+        help = "number of distinct ports of a single destination IP the VM may contact \
+        within --scan-window before it's considered to be doing a vertical port scan",
+        value_name = "ports"
+    )]
+    scan_max_ports: Option<u32>,
+
+    #[clap(
+        long,
+        help = "window for --scan-max-hosts and --scan-max-ports",
+        value_name = "seconds",
+        default_value_t = 10
+    )]
+    scan_window: u64,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "action to take when the VM is detected to be port scanning",
+        default_value_t = ScanAction::Throttle
+    )]
+    scan_action: ScanAction,
+
+    #[clap(
+        long,
+        help = "number of packets per second the VM may send to a single destination IP \
+        for longer than --flood-duration before it's considered to be flooding it",
+        value_name = "packets per second"
+    )]
+    flood_max_pps: Option<u64>,
+
+    #[clap(
+        long,
+        help = "rate in bits per second with an optional k, M or G suffix the VM may send \
+        to a single destination IP for longer than --flood-duration before it's considered \
+        to be flooding it",
+        value_name = "rate"
+    )]
+    flood_max_rate: Option<Rate>,
+
+    #[clap(
+        long,
+        help = "how long --flood-max-pps or --flood-max-rate need to be exceeded for",
+        value_name = "seconds",
+        default_value_t = 5
+    )]
+    flood_duration: u64,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "response to the VM flooding a destination",
+        default_value_t = FloodAction::RateLimit
+    )]
+    flood_action: FloodAction,
+
+    #[clap(
+        long,
+        help = "block the VM's connections speaking the Stratum mining protocol"
+    )]
+    block_mining: bool,
+
+    #[clap(
+        long,
+        help = "path to a file with the known mining pool endpoints to block, \
+        one IP[/PREFIX][:PORT] per line, implies --block-mining"
+    )]
+    mining_pools: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "what to do with the WireGuard, OpenVPN, IPsec and GRE tunnels established by the VM, \
+        blocking applies even to the allowed destinations",
+        default_value_t = TunnelPolicy::Ignore
+    )]
+    tunnels: TunnelPolicy,
+
+    #[clap(
+        long,
+        help = "score at which a base domain is considered to be used for DNS tunnelling \
+        and the VM's further queries to it are dropped for 15 minutes. Each query scores \
+        5 points for the long labels, 5 for the high-entropy subdomains and 3 for the \
+        TXT and NULL records, the plain queries score nothing, the score is reset every \
+        60 seconds. The base domain is the last two labels of the name, or the last three \
+        under a short built-in list of public suffixes like co.uk, so the names under \
+        the other multi-label suffixes (e.g. s3.amazonaws.com) share a single score",
+        value_name = "points"
+    )]
+    dns_tunnel_threshold: Option<u32>,
+
+    #[clap(
+        long,
+        help = "directory to persist the VM's DHCP lease in, so that the VM's \
+        traffic keeps flowing when softnet is restarted while the VM is running",
+        value_name = "path"
+    )]
+    state_dir: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "path to the host's bootpd(8) leases database (usually /var/db/dhcpd_leases) \
+        to restore the VM's DHCP lease from on start-up when --state-dir has none",
+        value_name = "path"
+    )]
+    bootpd_leases: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "answer the VM's DHCP requests by softnet itself instead of bootpd(8), \
+        in which case --bootpd-lease-time is not applied, requires --dhcp-pool",
+        requires = "dhcp_pool"
+    )]
+    dhcp_server: bool,
+
+    #[clap(
+        long,
+        help = "range of addresses for the built-in DHCP server to hand out in the START-END format, \
+        which must not overlap with the range that bootpd(8) hands out to the VMs \
+        not run with --dhcp-server",
+        value_name = "range"
+    )]
+    dhcp_pool: Option<DhcpPool>,
+
+    #[clap(
+        long,
+        help = "lease time (in seconds) of the built-in DHCP server",
+        value_name = "seconds",
+        default_value_t = 600
+    )]
+    dhcp_lease_time: u64,
+
+    #[clap(
+        long,
+        help = "lease file shared by all softnet instances running the built-in DHCP server",
+        value_name = "path",
+        default_value = "/var/db/softnet_leases.json"
+    )]
+    dhcp_lease_file: PathBuf,
+
+    #[clap(
+        long,
+        help = "IP address statically configured in the VM, which then doesn't \
+        use DHCP at all. The VM is allowed to use this address indefinitely",
+        value_name = "address",
+        conflicts_with = "dhcp_server"
+    )]
+    vm_ip: Option<Ipv4Addr>,
+
+    #[clap(
+        long,
+        help = "comma-separated list of DNS servers statically configured in the VM \
+        to allow the queries to, requires --vm-ip",
+        value_name = "comma-separated addresses",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set,
+        requires = "vm_ip"
+    )]
+    vm_dns: Vec<Ipv4Addr>,
+
+    #[clap(
+        long,
+        help = "comma-separated list of DNS servers to announce to the VM in the DHCP replies \
+        instead of the ones provided by the DHCP server (e.g. to point the VM at a filtering resolver)",
+        value_name = "comma-separated addresses",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set
+    )]
+    dhcp_dns: Vec<Ipv4Addr>,
+
+    #[clap(
+        long,
+        help = "domain name to announce to the VM in the DHCP replies",
+        value_name = "domain"
+    )]
+    dhcp_domain: Option<String>,
+
+    #[clap(
+        long,
+        help = "comma-separated list of search domains to announce to the VM in the DHCP replies",
+        value_name = "comma-separated domains",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set
+    )]
+    dhcp_search: Vec<String>,
+
+    #[clap(
+        long,
+        help = "interface MTU to announce to the VM in the DHCP replies",
+        value_name = "bytes"
+    )]
+    dhcp_mtu: Option<u16>,
+
+    #[clap(
+        long,
+        help = "comma-separated list of classless static routes to announce to the VM \
+        in the DHCP replies (e.g. --dhcp-route=10.0.0.0/8=192.168.64.1). Since the VMs \
+        honoring these routes ignore the router option, a default route via the router \
+        is added unless a 0.0.0.0/0 route is specified",
+        value_name = "comma-separated CIDR=GATEWAY routes",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set
+    )]
+    dhcp_route: Vec<StaticRoute>,
+
+    #[clap(
+        long,
+        help = "release the VM's DHCP lease on its behalf when softnet is terminated, \
+        so that the VM's address is immediately available to the other VMs instead of \
+        being reserved for the rest of the lease time. Only use this for ephemeral VMs \
+        that are never restarted with the same MAC address"
+    )]
+    dhcp_release_on_shutdown: bool,
+
+    #[clap(
+        long,
+        help = "maximum number of DHCP messages per second the VM is allowed to send, \
+        the excess messages are dropped",
+        value_name = "messages per second",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    dhcp_rate_limit: Option<u32>,
+
+    #[clap(
+        long,
+        help = "MTU to enforce on the VM's traffic, must not exceed the host's MTU \
+        (e.g. --mtu=1400 for tunnelled uplinks), defaults to the host's MTU. \
+        The MSS of the TCP connections initiated by the VM is clamped to fit it \
+        and the oversized packets are rejected with ICMP fragmentation needed if they have \
+        the DF bit set, or fragmented otherwise",
+        value_name = "bytes"
+    )]
+    mtu: Option<u16>,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "comma-separated list of sanity checks to apply to the VM's traffic \
+        before it's evaluated against the packet filter rules (e.g. --scrub=checksum,ip-options), \
+        frames failing any of these checks are dropped",
+        value_name = "comma-separated checks",
+        use_value_delimiter = true,
+        action = clap::ArgAction::Set
+    )]
+    scrub: Vec<ScrubCheck>,
+
     #[clap(long, hide = true)]
     sudo_escalation_probing: bool,
 
@@ -191,17 +652,77 @@ fn try_main() -> anyhow::Result<()> {
         ));
     }
 
-    // Set bootpd(8) min/max lease time while still having the root privileges
-    set_bootpd_lease_time(args.bootpd_lease_time);
+    // Set bootpd(8) min/max lease time while still having the root privileges,
+    // unless the VM is served by the built-in DHCP server
+    if !args.dhcp_server {
+        set_bootpd_lease_time(args.bootpd_lease_time);
+    }
 
     // Initialize the proxy while still having the root privileges
     let mut proxy = Proxy::new(
         args.vm_fd as RawFd,
         args.vm_mac_address,
         args.vm_net_type,
-        args.allow,
-        args.block,
-        args.expose,
+        Options {
+            allow: args.allow,
+            block: args.block,
+            exposed_ports: args.expose,
+            scrub_checks: args.scrub,
+            ip_sets: args.ip_set,
+            geoip_database: args.geoip_database,
+            asn_database: args.asn_database,
+            multicast_groups: args.allow_multicast,
+            multicast_rate_limit: args.multicast_rate_limit,
+            egress_rate: args.egress_rate,
+            egress_burst: args.egress_burst,
+            ingress_rate: args.ingress_rate,
+            ingress_burst: args.ingress_burst,
+            quotas: args.quota,
+            quota_action: args.quota_action,
+            quota_throttle_rate: args.quota_throttle_rate,
+            egress_impairment: args.impair_egress,
+            ingress_impairment: args.impair_ingress,
+            impairment_seed: args.impair_seed,
+            mtu: args.mtu,
+            scheduling_mode: args.scheduling,
+            flow_limits: FlowLimits {
+                new_per_second: args.max_new_flows,
+                concurrent: args.max_flows,
+                per_destination: args.max_flows_per_destination,
+            },
+            flow_limit_action: args.flow_limit_action,
+            scan_thresholds: ScanThresholds {
+                max_hosts: args.scan_max_hosts,
+                max_ports: args.scan_max_ports,
+                window: Duration::from_secs(args.scan_window),
+            },
+            scan_action: args.scan_action,
+            flood_thresholds: FloodThresholds {
+                max_pps: args.flood_max_pps,
+                max_rate: args.flood_max_rate,
+                duration: Duration::from_secs(args.flood_duration),
+            },
+            flood_action: args.flood_action,
+            block_mining: args.block_mining,
+            mining_pools: args.mining_pools,
+            tunnel_policy: args.tunnels,
+            dns_tunnel_threshold: args.dns_tunnel_threshold,
+            state_dir: args.state_dir,
+            bootpd_leases: args.bootpd_leases,
+            dhcp_server: args.dhcp_server,
+            dhcp_pool: args.dhcp_pool,
+            dhcp_lease_time: Duration::from_secs(args.dhcp_lease_time),
+            dhcp_lease_file: args.dhcp_lease_file,
+            vm_ip: args.vm_ip,
+            vm_dns: args.vm_dns,
+            dhcp_dns: args.dhcp_dns,
+            dhcp_domain: args.dhcp_domain,
+            dhcp_search: args.dhcp_search,
+            dhcp_mtu: args.dhcp_mtu,
+            dhcp_routes: args.dhcp_route,
+            dhcp_release_on_shutdown: args.dhcp_release_on_shutdown,
+            dhcp_rate_limit: args.dhcp_rate_limit,
+        },
     )
     .context("failed to initialize proxy")?;
 